@@ -0,0 +1,91 @@
+//! Runtime settings, read once from environment variables. Unset or unparsable
+//! variables fall back to their defaults.
+
+use reqwest::Url;
+use std::{path::PathBuf, str::FromStr, sync::OnceLock, time::Duration};
+
+use crate::downloader::oversize::OversizeStrategy;
+
+pub struct Config {
+  /// Segment requests in flight for a single media playlist download.
+  pub segment_concurrency: usize,
+  /// Segment requests in flight across all downloads in the process.
+  pub global_segment_concurrency: usize,
+  /// Root under which every job gets its own scratch directory.
+  pub temp_dir: PathBuf,
+  /// Explicit ffmpeg binary; when unset it is looked up on `PATH`.
+  pub ffmpeg_path: Option<PathBuf>,
+  /// Longest stretch of a live stream that is recorded before stopping.
+  pub live_max_duration: Duration,
+  /// Self-hosted `telegram-bot-api` server to use instead of the public one.
+  pub bot_api_url: Option<Url>,
+  /// Whether that server runs with `--local`, so files are passed by path
+  /// instead of being uploaded. It must see `temp_dir` at the same path.
+  pub bot_api_local: bool,
+  /// Largest file the bot can upload, in bytes: 2000 MB through a local Bot API
+  /// server, 50 MB otherwise.
+  pub upload_limit: u64,
+  /// Whether users who have not chosen a quality mode get the largest quality
+  /// that fits `upload_limit` without being asked.
+  pub auto_quality: bool,
+  /// What to do with videos over `upload_limit` in chats that have not chosen.
+  pub oversize_strategy: OversizeStrategy,
+  /// SQLite database holding the bot's persistent state.
+  pub database_path: PathBuf,
+  /// How long an uploaded video's `file_id` is reused.
+  pub file_cache_ttl: Duration,
+  /// Most `file_id` cache entries kept; the oldest are evicted first.
+  pub file_cache_max_entries: usize,
+  /// How long a selection keyboard stays usable.
+  pub pending_ttl: Duration,
+  /// Download jobs running at once.
+  pub workers: usize,
+  /// Download jobs running at once for a single user.
+  pub max_jobs_per_user: usize,
+  /// Download jobs running at once for a single chat.
+  pub max_jobs_per_chat: usize,
+  /// Users whose jobs go ahead of everyone else's, from the comma-separated
+  /// `ADMIN_IDS`.
+  pub admin_ids: Vec<u64>,
+  /// Links downloaded from a single message; further ones are ignored.
+  pub max_links_per_message: usize,
+}
+
+impl Config {
+  fn from_env() -> Self {
+    let bot_api_url = std::env::var("BOT_API_URL").ok().and_then(|url| url.parse().ok());
+    let bot_api_local = env_or("BOT_API_LOCAL", bot_api_url.is_some());
+    let default_upload_limit_mb = if bot_api_local { 2000 } else { 50 };
+
+    Config {
+      segment_concurrency: env_or("SEGMENT_CONCURRENCY", 8).max(1),
+      global_segment_concurrency: env_or("GLOBAL_SEGMENT_CONCURRENCY", 32).max(1),
+      temp_dir: env_or("TEMP_DIR", std::env::temp_dir().join("vid-downloader-tg")),
+      ffmpeg_path: std::env::var_os("FFMPEG_PATH").map(PathBuf::from),
+      live_max_duration: Duration::from_secs(env_or("LIVE_MAX_DURATION", 3600)),
+      bot_api_url,
+      bot_api_local,
+      upload_limit: env_or("UPLOAD_LIMIT_MB", default_upload_limit_mb) * 1024 * 1024,
+      auto_quality: env_or("AUTO_QUALITY", false),
+      oversize_strategy: env_or("OVERSIZE_STRATEGY", OversizeStrategy::Split),
+      database_path: env_or("DATABASE_PATH", PathBuf::from("vid-downloader-tg.sqlite3")),
+      file_cache_ttl: Duration::from_secs(env_or("FILE_CACHE_TTL", 30 * 24 * 3600)),
+      file_cache_max_entries: env_or("FILE_CACHE_MAX_ENTRIES", 10_000),
+      pending_ttl: Duration::from_secs(env_or("PENDING_TTL", 3600)),
+      workers: env_or("WORKERS", 2).max(1),
+      max_jobs_per_user: env_or("MAX_JOBS_PER_USER", 1).max(1),
+      max_jobs_per_chat: env_or("MAX_JOBS_PER_CHAT", 2).max(1),
+      admin_ids: std::env::var("ADMIN_IDS").unwrap_or_default().split(',').filter_map(|id| id.trim().parse().ok()).collect(),
+      max_links_per_message: env_or("MAX_LINKS_PER_MESSAGE", 5).max(1),
+    }
+  }
+}
+
+pub fn get() -> &'static Config {
+  static CONFIG: OnceLock<Config> = OnceLock::new();
+  CONFIG.get_or_init(Config::from_env)
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+  std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
@@ -0,0 +1,39 @@
+//! The SQLite database at `DATABASE_PATH`, shared by `FileCache`,
+//! `PendingStore` and `SettingsStore` through a single connection.
+
+use rusqlite::Connection;
+use std::{
+  path::Path,
+  sync::{Arc, Mutex, MutexGuard},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone)]
+pub struct Database {
+  connection: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+  pub fn open(path: &Path) -> rusqlite::Result<Self> {
+    Ok(Self::with_connection(Connection::open(path)?))
+  }
+
+  /// A database that does not survive restarts, for when the file cannot be
+  /// opened, and for tests.
+  pub fn open_in_memory() -> rusqlite::Result<Self> {
+    Ok(Self::with_connection(Connection::open_in_memory()?))
+  }
+
+  fn with_connection(connection: Connection) -> Self {
+    Database { connection: Arc::new(Mutex::new(connection)) }
+  }
+
+  pub fn lock(&self) -> MutexGuard<'_, Connection> {
+    self.connection.lock().unwrap()
+  }
+}
+
+/// Seconds since the Unix epoch, as stored in `created_at` columns.
+pub fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
@@ -0,0 +1,161 @@
+//! MPEG-DASH manifests. A manifest is exposed as a `VariantPlaylist`, so DASH
+//! and HLS sources share the selection keyboard and the download path.
+
+pub mod mpd;
+pub mod sidx;
+
+use reqwest::header::HeaderMap;
+
+use crate::downloader::{
+  downloader_error::DownloaderError,
+  http,
+  playlist::{
+    hls::{Media, MediaType, Resolution},
+    master_playlist::{AudioRendition, MasterPlaylist, MediaSource, VariantInfo},
+    media_playlist::MediaPlaylist,
+    variant_playlist::VariantPlaylist,
+  },
+};
+use mpd::{ContentType, Manifest, Representation, Segments};
+
+/// Fetches the manifest at `url` and lists its video representations, best
+/// first. Every adaptation set of audio becomes one audio track, using its
+/// highest-bandwidth representation.
+pub async fn variant_playlist(url: &str, headers: HeaderMap) -> Result<VariantPlaylist, DownloaderError> {
+  let response = http::client()
+    .get(url)
+    .headers(headers.clone())
+    .send()
+    .await
+    .map_err(|_| DownloaderError::FetchError)?
+    .text()
+    .await
+    .map_err(|_| DownloaderError::FetchError)?;
+  let manifest = Manifest::parse(&response, url)?;
+
+  let mut audio = Vec::<&Representation>::new();
+  for representation in manifest.representations.iter().filter(|representation| representation.content_type == ContentType::Audio) {
+    match audio.iter_mut().find(|best| best.adaptation_set == representation.adaptation_set) {
+      Some(best) if representation.bandwidth > best.bandwidth => *best = representation,
+      Some(_) => {}
+      None => audio.push(representation),
+    }
+  }
+  let default_set = audio.iter().find(|representation| representation.main).or(audio.first()).map(|representation| representation.adaptation_set);
+  let audio_tracks = audio
+    .iter()
+    .map(|representation| {
+      let media = Media {
+        media_type: MediaType::Audio,
+        group_id: representation.adaptation_set.to_string(),
+        name: representation.label.clone().or(representation.language.clone()).unwrap_or_else(|| "Audio".to_string()),
+        language: representation.language.clone(),
+        default: Some(representation.adaptation_set) == default_set,
+        autoselect: true,
+        uri: None,
+      };
+      AudioRendition::new(media, source(representation, manifest.duration))
+    })
+    .collect::<Vec<_>>();
+
+  let mut videos = manifest
+    .representations
+    .iter()
+    .filter(|representation| representation.content_type == ContentType::Video)
+    .filter_map(|representation| Some((Resolution { width: representation.width?, height: representation.height? }, representation)))
+    .collect::<Vec<_>>();
+  videos.sort_by_key(|(resolution, representation)| std::cmp::Reverse((resolution.pixels(), representation.bandwidth)));
+
+  let audio_bandwidth = audio.iter().find(|representation| Some(representation.adaptation_set) == default_set).map_or(0, |representation| representation.bandwidth);
+  let mut master_playlists = vec![];
+  for (resolution, representation) in videos {
+    let info = VariantInfo {
+      resolution,
+      bandwidth: representation.bandwidth + audio_bandwidth,
+      average_bandwidth: None,
+      codecs: representation.codecs.clone(),
+      frame_rate: representation.frame_rate,
+    };
+    let source = source(representation, manifest.duration);
+    master_playlists.push(MasterPlaylist::from_sources(info, source, audio_tracks.clone(), headers.clone()).await?);
+  }
+
+  Ok(VariantPlaylist { master_playlists, audio_tracks, subtitles: vec![] })
+}
+
+/// Resolves the segments of a representation, fetching its segment index if
+/// needed.
+pub async fn media_playlist(segments: &Segments, fragmented_mp4: bool, headers: &HeaderMap) -> Result<MediaPlaylist, DownloaderError> {
+  let segments = match segments {
+    Segments::List(segments) => segments.clone(),
+    Segments::Indexed { url, initialization, index } => sidx::segments(url, *initialization, *index, headers).await?,
+  };
+  Ok(MediaPlaylist::from_segments(segments, fragmented_mp4, headers))
+}
+
+fn source(representation: &Representation, duration: Option<f64>) -> MediaSource {
+  let mp4 = representation.mime_type.as_deref().is_some_and(|mime_type| mime_type.ends_with("/mp4"));
+  // A lone unranged segment is a whole file, which need not be fragmented.
+  let whole_file = matches!(&representation.segments, Segments::List(segments) if segments.len() == 1 && segments[0].1.is_none());
+  MediaSource::Dash { segments: representation.segments.clone(), fragmented_mp4: mp4 && !whole_file, duration }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::downloader::{
+    progress::ProgressReporter,
+    test_server::{Reply, TestServer},
+  };
+
+  /// `init` followed by a version 0 `sidx` box and the referenced `segments`.
+  fn indexed_file(init: &[u8], segments: &[&[u8]]) -> (Vec<u8>, usize) {
+    let mut sidx = vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0x03, 0xe8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    sidx.extend((segments.len() as u16).to_be_bytes());
+    for segment in segments {
+      sidx.extend((segment.len() as u32).to_be_bytes());
+      sidx.extend([0, 0, 0x03, 0xe8, 0x90, 0, 0, 0]);
+    }
+    let sidx = [((sidx.len() + 8) as u32).to_be_bytes().to_vec(), b"sidx".to_vec(), sidx].concat();
+    ([init, &sidx, &segments.concat()].concat(), sidx.len())
+  }
+
+  #[tokio::test]
+  async fn lists_qualities_and_downloads_indexed_representations() {
+    let (file, sidx_length) = indexed_file(b"init-", &[b"one-", b"two-", b"three"]);
+    let server = TestServer::start().await;
+    server.route("/video.mp4", vec![Reply::Ok(file)]);
+    let mpd = format!(
+      r#"<MPD type="static" mediaPresentationDuration="PT60S"><Period>
+      <AdaptationSet mimeType="video/mp4" codecs="avc1.64001f">
+        <Representation id="360" bandwidth="500000" width="640" height="360"><BaseURL>video.mp4</BaseURL>
+          <SegmentBase indexRange="5-{index_end}"/>
+        </Representation>
+        <Representation id="720" bandwidth="2000000" width="1280" height="720"><BaseURL>video.mp4</BaseURL>
+          <SegmentBase indexRange="5-{index_end}"/>
+        </Representation>
+      </AdaptationSet>
+      <AdaptationSet mimeType="audio/mp4" lang="en"><Representation id="a" bandwidth="64000"><BaseURL>a.mp4</BaseURL></Representation></AdaptationSet>
+      <AdaptationSet mimeType="audio/mp4" lang="fr"><Role value="main"/><Representation id="b" bandwidth="64000"><BaseURL>b.mp4</BaseURL></Representation></AdaptationSet>
+    </Period></MPD>"#,
+      index_end = 4 + sidx_length
+    );
+    server.route("/manifest.mpd", vec![Reply::Ok(mpd.clone().into())]);
+
+    let variant_playlist = variant_playlist(&server.url("/manifest.mpd"), HeaderMap::new()).await.unwrap();
+
+    let labels = variant_playlist.master_playlists.iter().map(|master_playlist| master_playlist.label()).collect::<Vec<_>>();
+    assert_eq!(labels, ["720p · ~15 MB · H.264", "360p · ~4 MB · H.264"]);
+    let languages = variant_playlist.audio_tracks.iter().map(|track| (track.media.language.as_deref(), track.media.default)).collect::<Vec<_>>();
+    assert_eq!(languages, [(Some("en"), false), (Some("fr"), true)]);
+
+    let manifest = Manifest::parse(&mpd, &server.url("/manifest.mpd")).unwrap();
+    let media_playlist = media_playlist(&manifest.representations[0].segments, true, &HeaderMap::new()).await.unwrap();
+    let path = server.temp_path("dash.mp4");
+    media_playlist.download_to(&path, &ProgressReporter::default()).await.unwrap();
+    let bytes = tokio::fs::read(&path).await.unwrap();
+    let _ = tokio::fs::remove_file(&path).await;
+
+    assert_eq!(bytes, b"init-one-two-three");
+  }
+}
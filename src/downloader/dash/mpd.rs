@@ -0,0 +1,529 @@
+//! Parser for static MPEG-DASH manifests (ISO/IEC 23009-1).
+//!
+//! Only the first period is read. Segment addressing is resolved to plain URLs
+//! and byte ranges, except for `SegmentBase`, whose segments are listed in the
+//! media file's own `sidx` box and have to be fetched separately.
+
+use roxmltree::{Document, Node};
+
+use crate::downloader::{downloader_error::DownloaderError, playlist::hls::ByteRange, playlist::hls::resolve_uri};
+
+/// Most segments a `SegmentTemplate` may expand to, well above a day of
+/// one-second segments. Templates are expanded up front, so without a cap a
+/// hostile manifest could claim billions of them.
+const MAX_TEMPLATE_SEGMENTS: u64 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentType {
+  Video,
+  Audio,
+  Text,
+  Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Representation {
+  pub id: String,
+  pub content_type: ContentType,
+  pub bandwidth: u64,
+  pub width: Option<u32>,
+  pub height: Option<u32>,
+  pub codecs: Option<String>,
+  pub frame_rate: Option<f64>,
+  pub mime_type: Option<String>,
+  pub language: Option<String>,
+  pub label: Option<String>,
+  /// Position of the enclosing `AdaptationSet` in the period; representations of
+  /// one set are alternative encodings of the same content.
+  pub adaptation_set: usize,
+  /// Whether the adaptation set has `Role` `main`.
+  pub main: bool,
+  pub segments: Segments,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segments {
+  /// Initialization section (if any) followed by the media segments.
+  List(Vec<(String, Option<ByteRange>)>),
+  /// `SegmentBase`: one file whose `sidx` box at `index` lists the segments.
+  Indexed { url: String, initialization: Option<ByteRange>, index: ByteRange },
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Manifest {
+  /// Seconds covered by the period, when the manifest states it.
+  pub duration: Option<f64>,
+  pub representations: Vec<Representation>,
+}
+
+impl Manifest {
+  /// Parses the manifest fetched from `url`, which relative `BaseURL`s and
+  /// segment URLs are resolved against.
+  pub fn parse(input: &str, url: &str) -> Result<Self, DownloaderError> {
+    let document = Document::parse(input).map_err(|e| parse_error(&e.to_string()))?;
+    let mpd = document.root_element();
+    if mpd.tag_name().name() != "MPD" {
+      return Err(parse_error("missing MPD element"));
+    }
+    if mpd.attribute("type") == Some("dynamic") {
+      return Err(parse_error("live DASH streams are not supported"));
+    }
+
+    let Some(period) = children(mpd, "Period").next() else {
+      return Err(parse_error("MPD has no Period"));
+    };
+    let period_duration = period
+      .attribute("duration")
+      .or(mpd.attribute("mediaPresentationDuration"))
+      .map(|duration| parse_duration(duration).ok_or_else(|| parse_error("invalid duration")))
+      .transpose()?;
+    let period_url = base_url(&base_url(url, mpd)?, period)?;
+
+    let mut manifest = Manifest { duration: period_duration, ..Manifest::default() };
+    for (adaptation_set_index, adaptation_set) in children(period, "AdaptationSet").enumerate() {
+      let adaptation_set_url = base_url(&period_url, adaptation_set)?;
+      let main = children(adaptation_set, "Role").any(|role| role.attribute("value") == Some("main"));
+      let label = children(adaptation_set, "Label").next().and_then(|label| label.text()).map(str::to_string);
+
+      for representation in children(adaptation_set, "Representation") {
+        let inherited = |name: &str| representation.attribute(name).or(adaptation_set.attribute(name));
+        let id = representation.attribute("id").ok_or_else(|| parse_error("Representation is missing id"))?.to_string();
+        let mime_type = inherited("mimeType").map(str::to_string);
+        let content_type = match adaptation_set.attribute("contentType").or(mime_type.as_deref().and_then(|mime| mime.split('/').next())) {
+          Some("video") => ContentType::Video,
+          Some("audio") => ContentType::Audio,
+          Some("text") => ContentType::Text,
+          _ => ContentType::Other,
+        };
+        let bandwidth = number(representation, "bandwidth")?.ok_or_else(|| parse_error("Representation is missing bandwidth"))?;
+
+        let levels = [representation, adaptation_set, period];
+        let representation_url = base_url(&adaptation_set_url, representation)?;
+        let segments = segments(&levels, &representation_url, &id, bandwidth, period_duration)?;
+
+        manifest.representations.push(Representation {
+          id,
+          content_type,
+          bandwidth,
+          width: number(representation, "width")?.or(number(adaptation_set, "width")?),
+          height: number(representation, "height")?.or(number(adaptation_set, "height")?),
+          codecs: inherited("codecs").map(str::to_string),
+          frame_rate: inherited("frameRate").map(|frame_rate| parse_frame_rate(frame_rate).ok_or_else(|| parse_error("invalid frameRate"))).transpose()?,
+          mime_type,
+          language: inherited("lang").map(str::to_string),
+          label: label.clone(),
+          adaptation_set: adaptation_set_index,
+          main,
+          segments,
+        });
+      }
+    }
+
+    Ok(manifest)
+  }
+}
+
+/// Resolves the addressing of a representation. `levels` are the representation
+/// and its ancestors; addressing elements are inherited from the nearest level
+/// that has them, attribute by attribute.
+fn segments(levels: &[Node], url: &str, id: &str, bandwidth: u64, period_duration: Option<f64>) -> Result<Segments, DownloaderError> {
+  let find = |name: &'static str| levels.iter().filter_map(|level| children(*level, name).next()).collect::<Vec<_>>();
+
+  let templates = find("SegmentTemplate");
+  if !templates.is_empty() {
+    return segment_template(&templates, url, id, bandwidth, period_duration);
+  }
+
+  let lists = find("SegmentList");
+  if let Some(list) = lists.first() {
+    let mut segments = vec![];
+    if let Some(initialization) = lists.iter().find_map(|list| children(*list, "Initialization").next()) {
+      segments.push(source_url(url, initialization, "sourceURL", "range")?);
+    }
+    for segment_url in children(*list, "SegmentURL") {
+      segments.push(source_url(url, segment_url, "media", "mediaRange")?);
+    }
+    return Ok(Segments::List(segments));
+  }
+
+  let bases = find("SegmentBase");
+  let index = bases.iter().find_map(|base| base.attribute("indexRange"));
+  match index {
+    Some(index) => {
+      let initialization = bases.iter().find_map(|base| children(*base, "Initialization").next()).and_then(|initialization| initialization.attribute("range"));
+      Ok(Segments::Indexed { url: url.to_string(), initialization: initialization.map(parse_range).transpose()?, index: parse_range(index)? })
+    }
+    // A plain file without an index is downloaded as a single segment.
+    None => Ok(Segments::List(vec![(url.to_string(), None)])),
+  }
+}
+
+fn segment_template(templates: &[Node], url: &str, id: &str, bandwidth: u64, period_duration: Option<f64>) -> Result<Segments, DownloaderError> {
+  let attribute = |name: &str| templates.iter().find_map(|template| template.attribute(name));
+  let number_attribute = |name: &str| -> Result<Option<u64>, DownloaderError> {
+    attribute(name).map(|value| value.parse().map_err(|_| parse_error(&format!("invalid SegmentTemplate@{name}")))).transpose()
+  };
+
+  let media = attribute("media").ok_or_else(|| parse_error("SegmentTemplate is missing media"))?;
+  let start_number = number_attribute("startNumber")?.unwrap_or(1);
+  let timescale = number_attribute("timescale")?.unwrap_or(1).max(1);
+
+  let mut segments = vec![];
+  if let Some(initialization) = attribute("initialization") {
+    segments.push((resolve_uri(url, &expand_template(initialization, id, bandwidth, None, None)?)?, None));
+  }
+
+  // (start time, number) of every segment
+  let mut times = vec![];
+  if let Some(timeline) = templates.iter().find_map(|template| children(*template, "SegmentTimeline").next()) {
+    let period_end = period_duration.map(|duration| (duration * timescale as f64).round() as u64);
+    let entries = children(timeline, "S").collect::<Vec<_>>();
+    let mut time = 0;
+    for (index, entry) in entries.iter().enumerate() {
+      let duration = number(*entry, "d")?.ok_or_else(|| parse_error("SegmentTimeline S is missing d"))?;
+      if duration == 0 {
+        return Err(parse_error("SegmentTimeline S has zero duration"));
+      }
+      time = number(*entry, "t")?.unwrap_or(time);
+
+      let repeat = entry.attribute("r").map(|repeat| repeat.parse::<i64>().map_err(|_| parse_error("invalid SegmentTimeline S@r"))).transpose()?.unwrap_or(0);
+      let count = if repeat >= 0 {
+        repeat as u64 + 1
+      } else {
+        // Negative repeat counts run until the next S or the end of the period.
+        let end = entries.get(index + 1).and_then(|next| next.attribute("t")).and_then(|next| next.parse().ok()).or(period_end);
+        let end = end.ok_or_else(|| parse_error("open-ended SegmentTimeline without period duration"))?;
+        end.saturating_sub(time).div_ceil(duration)
+      };
+      if count > MAX_TEMPLATE_SEGMENTS - times.len() as u64 {
+        return Err(too_many_segments());
+      }
+
+      for _ in 0..count {
+        times.push((time, start_number + times.len() as u64));
+        time = time.checked_add(duration).ok_or_else(|| parse_error("SegmentTimeline time overflows"))?;
+      }
+    }
+  } else {
+    let duration = number_attribute("duration")?.ok_or_else(|| parse_error("SegmentTemplate has neither duration nor SegmentTimeline"))?;
+    let period_duration = period_duration.ok_or_else(|| parse_error("SegmentTemplate without period duration"))?;
+    let count = (period_duration * timescale as f64 / duration as f64).ceil() as u64;
+    if count > MAX_TEMPLATE_SEGMENTS {
+      return Err(too_many_segments());
+    }
+    times.extend((0..count).map(|index| (index * duration, start_number + index)));
+  }
+
+  for (time, number) in times {
+    segments.push((resolve_uri(url, &expand_template(media, id, bandwidth, Some(number), Some(time))?)?, None));
+  }
+  Ok(Segments::List(segments))
+}
+
+/// Substitutes `$RepresentationID$`, `$Bandwidth$`, `$Number$` and `$Time$`
+/// (optionally with a `%0Nd` width) and `$$` in a segment template.
+fn expand_template(template: &str, id: &str, bandwidth: u64, number: Option<u64>, time: Option<u64>) -> Result<String, DownloaderError> {
+  let mut output = String::new();
+  let mut parts = template.split('$');
+  output.push_str(parts.next().unwrap_or_default());
+
+  while let Some(identifier) = parts.next() {
+    let literal = parts.next().ok_or_else(|| parse_error("unterminated identifier in SegmentTemplate"))?;
+    let (name, width) = match identifier.split_once('%') {
+      Some((name, format)) => {
+        let width = format.strip_prefix('0').and_then(|format| format.strip_suffix('d')).and_then(|width| width.parse().ok());
+        (name, Some(width.ok_or_else(|| parse_error("invalid format tag in SegmentTemplate"))?))
+      }
+      None => (identifier, None),
+    };
+
+    let value = match name {
+      "" => Some("$".to_string()),
+      "RepresentationID" => Some(id.to_string()),
+      "Bandwidth" => Some(bandwidth.to_string()),
+      "Number" => number.map(|number| number.to_string()),
+      "Time" => time.map(|time| time.to_string()),
+      _ => None,
+    };
+    let value = value.ok_or_else(|| parse_error(&format!("unsupported identifier ${name}$ in SegmentTemplate")))?;
+    output.push_str(&format!("{value:0>width$}", width = width.unwrap_or(0)));
+    output.push_str(literal);
+  }
+
+  Ok(output)
+}
+
+fn source_url(url: &str, node: Node, url_attribute: &str, range_attribute: &str) -> Result<(String, Option<ByteRange>), DownloaderError> {
+  let url = match node.attribute(url_attribute) {
+    Some(source) => resolve_uri(url, source)?,
+    None => url.to_string(),
+  };
+  Ok((url, node.attribute(range_attribute).map(parse_range).transpose()?))
+}
+
+/// Resolves the `BaseURL` child of `node`, if any, against `url`.
+fn base_url(url: &str, node: Node) -> Result<String, DownloaderError> {
+  match children(node, "BaseURL").next().and_then(|base| base.text()) {
+    Some(base) => resolve_uri(url, base.trim()),
+    None => Ok(url.to_string()),
+  }
+}
+
+/// Parses a `first-last` byte range.
+fn parse_range(value: &str) -> Result<ByteRange, DownloaderError> {
+  let invalid = || parse_error("invalid byte range");
+  let (first, last) = value.split_once('-').ok_or_else(invalid)?;
+  let (first, last) = (first.trim().parse::<u64>().map_err(|_| invalid())?, last.trim().parse::<u64>().map_err(|_| invalid())?);
+  if last < first || last == u64::MAX {
+    return Err(invalid());
+  }
+  Ok(ByteRange { length: last - first + 1, offset: first })
+}
+
+/// `25` or `30000/1001`.
+fn parse_frame_rate(value: &str) -> Option<f64> {
+  let (numerator, denominator) = value.split_once('/').unwrap_or((value, "1"));
+  let denominator = denominator.parse::<f64>().ok().filter(|&denominator| denominator > 0.0)?;
+  Some(numerator.parse::<f64>().ok()? / denominator)
+}
+
+/// Parses an ISO 8601 duration such as `PT1H2M3.5S` into seconds.
+fn parse_duration(value: &str) -> Option<f64> {
+  let value = value.strip_prefix('P')?;
+  let (date, time) = value.split_once('T').unwrap_or((value, ""));
+
+  let mut seconds = 0.0;
+  for (part, units) in [(date, [('Y', 365.0 * 86400.0), ('M', 30.0 * 86400.0), ('D', 86400.0)]), (time, [('H', 3600.0), ('M', 60.0), ('S', 1.0)])] {
+    let mut rest = part;
+    for (designator, unit) in units {
+      if let Some((amount, after)) = rest.split_once(designator) {
+        seconds += amount.parse::<f64>().ok()? * unit;
+        rest = after;
+      }
+    }
+    if !rest.is_empty() {
+      return None;
+    }
+  }
+  Some(seconds)
+}
+
+fn number<T: std::str::FromStr>(node: Node, name: &str) -> Result<Option<T>, DownloaderError> {
+  match node.attribute(name) {
+    Some(value) => value.parse().map(Some).map_err(|_| parse_error(&format!("invalid {}@{name}", node.tag_name().name()))),
+    None => Ok(None),
+  }
+}
+
+fn children<'a, 'input>(node: Node<'a, 'input>, name: &'static str) -> impl Iterator<Item = Node<'a, 'input>> {
+  node.children().filter(move |child| child.tag_name().name() == name)
+}
+
+fn parse_error(message: &str) -> DownloaderError {
+  DownloaderError::PlaylistParseError(message.to_string())
+}
+
+fn too_many_segments() -> DownloaderError {
+  parse_error(&format!("SegmentTemplate expands to more than {MAX_TEMPLATE_SEGMENTS} segments"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const URL: &str = "https://cdn.example.com/video/manifest.mpd";
+
+  fn parse(input: &str) -> Manifest {
+    Manifest::parse(input, URL).unwrap()
+  }
+
+  fn urls(segments: &Segments) -> Vec<&str> {
+    let Segments::List(segments) = segments else { panic!("expected a segment list") };
+    segments.iter().map(|(url, _)| url.as_str()).collect()
+  }
+
+  #[test]
+  fn expands_number_template_over_the_period() {
+    let manifest = parse(
+      r#"<?xml version="1.0"?>
+      <MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT9.5S">
+        <Period>
+          <BaseURL>media/</BaseURL>
+          <AdaptationSet contentType="video" mimeType="video/mp4">
+            <SegmentTemplate timescale="1000" duration="4000" startNumber="0" initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/seg-$Number%03d$.m4s"/>
+            <Representation id="720p" bandwidth="2000000" width="1280" height="720" codecs="avc1.64001f"/>
+          </AdaptationSet>
+          <AdaptationSet mimeType="audio/mp4" lang="en">
+            <Role schemeIdUri="urn:mpeg:dash:role:2011" value="main"/>
+            <Representation id="audio" bandwidth="128000">
+              <SegmentTemplate timescale="1" duration="10" media="a-$Number$.m4s"/>
+            </Representation>
+          </AdaptationSet>
+        </Period>
+      </MPD>"#,
+    );
+
+    let [video, audio] = &manifest.representations[..] else { panic!("expected two representations") };
+    assert_eq!(video.content_type, ContentType::Video);
+    assert_eq!((video.width, video.height), (Some(1280), Some(720)));
+    assert_eq!(
+      urls(&video.segments),
+      [
+        "https://cdn.example.com/video/media/720p/init.mp4",
+        "https://cdn.example.com/video/media/720p/seg-000.m4s",
+        "https://cdn.example.com/video/media/720p/seg-001.m4s",
+        "https://cdn.example.com/video/media/720p/seg-002.m4s",
+      ]
+    );
+
+    assert_eq!(audio.content_type, ContentType::Audio);
+    assert_eq!(audio.language.as_deref(), Some("en"));
+    assert!(audio.main);
+    assert_eq!(urls(&audio.segments), ["https://cdn.example.com/video/media/a-1.m4s"]);
+  }
+
+  #[test]
+  fn expands_time_template_with_segment_timeline() {
+    let manifest = parse(
+      r#"<MPD type="static" mediaPresentationDuration="PT10S">
+        <Period>
+          <AdaptationSet contentType="video">
+            <SegmentTemplate timescale="90000" media="v/$Time$.m4s" initialization="v/init.mp4">
+              <SegmentTimeline>
+                <S t="900" d="180000" r="1"/>
+                <S d="90000"/>
+                <S d="180000" r="-1"/>
+              </SegmentTimeline>
+            </SegmentTemplate>
+            <Representation id="v" bandwidth="1000000" width="640" height="360"/>
+          </AdaptationSet>
+        </Period>
+      </MPD>"#,
+    );
+
+    let urls = urls(&manifest.representations[0].segments);
+    let times = urls.iter().skip(1).map(|url| url.rsplit('/').next().unwrap().trim_end_matches(".m4s")).collect::<Vec<_>>();
+    assert_eq!(times, ["900", "180900", "360900", "450900", "630900", "810900"]);
+  }
+
+  #[test]
+  fn rejects_templates_with_too_many_segments() {
+    let timeline = r#"<MPD type="static" mediaPresentationDuration="PT10S">
+        <Period>
+          <AdaptationSet contentType="video">
+            <SegmentTemplate media="$Time$.m4s">
+              <SegmentTimeline><S d="1" r="4000000000"/></SegmentTimeline>
+            </SegmentTemplate>
+            <Representation id="v" bandwidth="1000000"/>
+          </AdaptationSet>
+        </Period>
+      </MPD>"#;
+    let fixed_duration = r#"<MPD type="static" mediaPresentationDuration="PT1000H">
+        <Period>
+          <AdaptationSet contentType="video">
+            <SegmentTemplate duration="1" media="$Number$.m4s"/>
+            <Representation id="v" bandwidth="1000000"/>
+          </AdaptationSet>
+        </Period>
+      </MPD>"#;
+
+    // The open-ended S runs from 0 up to the next S@t, past the segments already counted.
+    let open_ended_timeline = r#"<MPD type="static" mediaPresentationDuration="PT10S">
+        <Period>
+          <AdaptationSet contentType="video">
+            <SegmentTemplate media="$Time$.m4s">
+              <SegmentTimeline><S t="0" d="1" r="2"/><S t="0" d="1" r="-1"/><S t="18446744073709551615" d="1"/></SegmentTimeline>
+            </SegmentTemplate>
+            <Representation id="v" bandwidth="1000000"/>
+          </AdaptationSet>
+        </Period>
+      </MPD>"#;
+
+    for manifest in [timeline, fixed_duration, open_ended_timeline] {
+      let Err(DownloaderError::PlaylistParseError(message)) = Manifest::parse(manifest, URL) else { panic!("expected a parse error") };
+      assert!(message.contains("more than"), "{message}");
+    }
+  }
+
+  #[test]
+  fn rejects_timelines_that_overflow() {
+    let manifest = r#"<MPD type="static" mediaPresentationDuration="PT10S">
+        <Period>
+          <AdaptationSet contentType="video">
+            <SegmentTemplate media="$Time$.m4s">
+              <SegmentTimeline><S t="18446744073709551615" d="1"/></SegmentTimeline>
+            </SegmentTemplate>
+            <Representation id="v" bandwidth="1000000"/>
+          </AdaptationSet>
+        </Period>
+      </MPD>"#;
+
+    assert!(matches!(Manifest::parse(manifest, URL), Err(DownloaderError::PlaylistParseError(_))));
+  }
+
+  #[test]
+  fn reads_segment_lists_and_segment_base() {
+    let manifest = parse(
+      r#"<MPD type="static" mediaPresentationDuration="PT4S">
+        <BaseURL>https://media.example.com/</BaseURL>
+        <Period>
+          <AdaptationSet mimeType="video/mp4">
+            <Representation id="list" bandwidth="500000" width="320" height="180">
+              <SegmentList>
+                <Initialization sourceURL="list.mp4" range="0-799"/>
+                <SegmentURL media="list.mp4" mediaRange="800-1999"/>
+                <SegmentURL media="list.mp4" mediaRange="2000-2999"/>
+              </SegmentList>
+            </Representation>
+            <Representation id="base" bandwidth="900000" width="640" height="360">
+              <BaseURL>base.mp4</BaseURL>
+              <SegmentBase indexRange="820-919">
+                <Initialization range="0-819"/>
+              </SegmentBase>
+            </Representation>
+          </AdaptationSet>
+        </Period>
+      </MPD>"#,
+    );
+
+    let Segments::List(list) = &manifest.representations[0].segments else { panic!("expected a segment list") };
+    assert_eq!(list[0], ("https://media.example.com/list.mp4".to_string(), Some(ByteRange { length: 800, offset: 0 })));
+    assert_eq!(list[2].1, Some(ByteRange { length: 1000, offset: 2000 }));
+
+    assert_eq!(
+      manifest.representations[1].segments,
+      Segments::Indexed {
+        url: "https://media.example.com/base.mp4".to_string(),
+        initialization: Some(ByteRange { length: 820, offset: 0 }),
+        index: ByteRange { length: 100, offset: 820 },
+      }
+    );
+  }
+
+  #[test]
+  fn rejects_dynamic_manifests() {
+    assert!(Manifest::parse(r#"<MPD type="dynamic"><Period/></MPD>"#, URL).is_err());
+  }
+
+  #[test]
+  fn parses_byte_ranges() {
+    assert_eq!(parse_range("800-1999").unwrap(), ByteRange { length: 1200, offset: 800 });
+    assert!(parse_range("2000-1999").is_err());
+    assert!(parse_range("0-18446744073709551615").is_err());
+  }
+
+  #[test]
+  fn parses_iso_durations() {
+    assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+    assert_eq!(parse_duration("P1DT1S"), Some(86401.0));
+    assert_eq!(parse_duration("PT0.040S"), Some(0.04));
+    assert_eq!(parse_duration("1H"), None);
+  }
+
+  #[test]
+  fn parses_frame_rates() {
+    assert_eq!(parse_frame_rate("25"), Some(25.0));
+    assert_eq!(parse_frame_rate("60000/1000"), Some(60.0));
+    assert_eq!(parse_frame_rate("30/0"), None);
+  }
+}
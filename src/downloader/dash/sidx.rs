@@ -0,0 +1,162 @@
+//! Segment index (`sidx`) lookup for `SegmentBase` representations.
+
+use reqwest::header::{HeaderMap, RANGE};
+
+use crate::downloader::{downloader_error::DownloaderError, http, playlist::hls::ByteRange};
+
+/// Fetches the `sidx` box at `index` in `url` and returns the byte ranges of the
+/// file's segments, preceded by the initialization section. Without an explicit
+/// `initialization` range everything before the index is taken to be the
+/// initialization section.
+pub async fn segments(
+  url: &str,
+  initialization: Option<ByteRange>,
+  index: ByteRange,
+  headers: &HeaderMap,
+) -> Result<Vec<(String, Option<ByteRange>)>, DownloaderError> {
+  let response = http::client()
+    .get(url)
+    .headers(headers.clone())
+    .header(RANGE, format!("bytes={}-{}", index.offset, index.last_byte()))
+    .send()
+    .await
+    .and_then(|response| response.error_for_status())
+    .map_err(|_| DownloaderError::FetchError)?;
+  let partial = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+  let bytes = response.bytes().await.map_err(|_| DownloaderError::FetchError)?;
+  // Servers that ignore `Range` send the whole file.
+  let bytes = if partial { &bytes[..] } else { bytes.get(index.offset as usize..).ok_or(DownloaderError::FetchError)? };
+
+  let initialization = initialization.or((index.offset > 0).then_some(ByteRange { length: index.offset, offset: 0 }));
+  let mut segments = vec![];
+  segments.extend(initialization.map(|range| (url.to_string(), Some(range))));
+  segments.extend(parse(bytes, index.offset)?.into_iter().map(|range| (url.to_string(), Some(range))));
+  Ok(segments)
+}
+
+/// Parses the first `sidx` box in `bytes`, which start at `offset` in the file,
+/// into the byte ranges of the referenced segments.
+pub fn parse(bytes: &[u8], offset: u64) -> Result<Vec<ByteRange>, DownloaderError> {
+  let mut position = 0;
+  while position + 8 <= bytes.len() {
+    let size = read_u32(bytes, position)? as usize;
+    let kind = &bytes[position + 4..position + 8];
+    if size < 8 {
+      return Err(index_error("invalid box size"));
+    }
+    if kind == b"sidx" {
+      let sidx = bytes.get(position..position + size).ok_or_else(|| index_error("truncated sidx box"))?;
+      return parse_sidx(sidx, offset + (position + size) as u64);
+    }
+    position += size;
+  }
+  Err(index_error("no sidx box in index range"))
+}
+
+/// `anchor` is the file offset of the first byte after the box, which segment
+/// offsets are relative to.
+fn parse_sidx(sidx: &[u8], anchor: u64) -> Result<Vec<ByteRange>, DownloaderError> {
+  let version = *sidx.get(8).ok_or_else(|| index_error("truncated sidx box"))?;
+  // size, type, version and flags, reference_ID, timescale
+  let mut position = 20;
+  let first_offset = if version == 0 {
+    position += 8;
+    read_u32(sidx, position - 4)? as u64
+  } else {
+    position += 16;
+    read_u64(sidx, position - 8)?
+  };
+  // reserved (16 bits), reference_count (16 bits)
+  let reference_count = read_u32(sidx, position)? & 0xffff;
+  position += 4;
+
+  let mut ranges = vec![];
+  let mut segment_offset = anchor.checked_add(first_offset).ok_or_else(|| index_error("offset overflows"))?;
+  for _ in 0..reference_count {
+    // The top bit marks references to further sidx boxes; their ranges still
+    // cover the media, so they are followed like any other.
+    let length = (read_u32(sidx, position)? & 0x7fff_ffff) as u64;
+    if length == 0 {
+      return Err(index_error("empty sidx reference"));
+    }
+    let segment_end = segment_offset.checked_add(length).ok_or_else(|| index_error("offset overflows"))?;
+    ranges.push(ByteRange { length, offset: segment_offset });
+    segment_offset = segment_end;
+    position += 12;
+  }
+  Ok(ranges)
+}
+
+fn read_u32(bytes: &[u8], position: usize) -> Result<u32, DownloaderError> {
+  let bytes = bytes.get(position..position + 4).ok_or_else(|| index_error("truncated sidx box"))?;
+  Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], position: usize) -> Result<u64, DownloaderError> {
+  let bytes = bytes.get(position..position + 8).ok_or_else(|| index_error("truncated sidx box"))?;
+  Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn index_error(message: &str) -> DownloaderError {
+  DownloaderError::PlaylistParseError(format!("segment index: {message}"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sidx(version: u8, first_offset: u64, sizes: &[u32]) -> Vec<u8> {
+    let mut payload = vec![version, 0, 0, 0];
+    payload.extend(1u32.to_be_bytes());
+    payload.extend(1000u32.to_be_bytes());
+    if version == 0 {
+      payload.extend(0u32.to_be_bytes());
+      payload.extend((first_offset as u32).to_be_bytes());
+    } else {
+      payload.extend(0u64.to_be_bytes());
+      payload.extend(first_offset.to_be_bytes());
+    }
+    payload.extend(0u16.to_be_bytes());
+    payload.extend((sizes.len() as u16).to_be_bytes());
+    for size in sizes {
+      payload.extend(size.to_be_bytes());
+      payload.extend(2000u32.to_be_bytes());
+      payload.extend(0x9000_0000u32.to_be_bytes());
+    }
+    [((payload.len() + 8) as u32).to_be_bytes().to_vec(), b"sidx".to_vec(), payload].concat()
+  }
+
+  #[test]
+  fn lists_referenced_segments_after_the_index() {
+    let bytes = sidx(0, 0, &[100, 250, 80]);
+    let anchor = 700 + bytes.len() as u64;
+
+    let ranges = parse(&bytes, 700).unwrap();
+
+    assert_eq!(
+      ranges,
+      [ByteRange { length: 100, offset: anchor }, ByteRange { length: 250, offset: anchor + 100 }, ByteRange { length: 80, offset: anchor + 350 }]
+    );
+  }
+
+  #[test]
+  fn honours_first_offset_and_version_1_fields() {
+    let bytes = [b"\0\0\0\x08free".to_vec(), sidx(1, 16, &[40])].concat();
+
+    let ranges = parse(&bytes, 0).unwrap();
+
+    assert_eq!(ranges, [ByteRange { length: 40, offset: bytes.len() as u64 + 16 }]);
+  }
+
+  #[test]
+  fn rejects_offsets_that_overflow() {
+    assert!(parse(&sidx(1, u64::MAX, &[40]), 0).is_err());
+    assert!(parse(&sidx(1, u64::MAX - 60, &[40]), 0).is_err());
+  }
+
+  #[test]
+  fn rejects_index_without_sidx() {
+    assert!(parse(b"\0\0\0\x08free", 0).is_err());
+    assert!(parse(&sidx(0, 0, &[10])[..30], 0).is_err());
+  }
+}
@@ -1,12 +1,15 @@
-use headless_chrome::{Browser, LaunchOptions};
-use std::time::Duration;
+use headless_chrome::{Browser, LaunchOptions, Tab};
+use std::{ops::Deref, path::PathBuf, sync::Arc, time::Duration};
 
-use super::{downloader_error::DownloaderError, playlist::variant_playlist::VariantPlaylist};
+use super::{downloader_error::DownloaderError, job_dir::JobDir, playlist::variant_playlist::VariantPlaylist, progress::ProgressReporter};
 
 pub trait PlatformDownloader {
-  async fn download(browser: &Browser, url: &str) -> Result<String, DownloaderError>;
+  async fn download(browser: &Browser, url: &str, job_dir: &JobDir, progress: &ProgressReporter) -> Result<PathBuf, DownloaderError>;
   async fn get_variant_playlist(browser: &Browser, url: &str) -> Result<VariantPlaylist, DownloaderError>;
   fn validate_url(url: &str) -> Result<(), DownloaderError>;
+  /// Identifies the media behind `url` independently of how the link is
+  /// written, e.g. `twitter:<status id>`.
+  fn media_id(url: &str) -> Option<String>;
 }
 
 pub struct Downloader {
@@ -31,11 +34,11 @@ impl Downloader {
 
       #[cfg(target_os = "windows")]
       {
-        let _ = Command::new("taskkill").args(&["/PID", &pid.to_string(), "/T", "/F"]).output();
+        let _ = Command::new("taskkill").args(["/PID", &pid.to_string(), "/T", "/F"]).output();
       }
       #[cfg(target_os = "linux")]
       {
-        let _ = Command::new("kill").args(&["-9", &format!("-{}", pid)]).output();
+        let _ = Command::new("kill").args(["-9", &format!("-{}", pid)]).output();
       }
       std::process::exit(0);
     });
@@ -43,3 +46,27 @@ impl Downloader {
     Self { browser }
   }
 }
+
+/// Browser tab that is closed when dropped, including when the job using it
+/// is cancelled.
+pub struct TabGuard(Arc<Tab>);
+
+impl TabGuard {
+  pub fn new(tab: Arc<Tab>) -> Self {
+    TabGuard(tab)
+  }
+}
+
+impl Deref for TabGuard {
+  type Target = Tab;
+
+  fn deref(&self) -> &Tab {
+    &self.0
+  }
+}
+
+impl Drop for TabGuard {
+  fn drop(&mut self) {
+    let _ = self.0.close(false);
+  }
+}
@@ -1,12 +1,20 @@
 use std::fmt::Display;
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug)]
 pub enum DownloaderError {
   _InvalidInputError,
   UnsupportedPlatformError,
   FetchError,
   NoMasterPlaylistError,
   IOError,
-  FfmpegError,
+  FfmpegError(String),
+  PlaylistParseError(String),
+  SegmentFetchError(usize, Option<u16>),
+  InitSectionFetchError(Option<u16>),
+  RemuxError(String),
+  DecryptionError(String),
+  OversizeError(String),
   OtherError(String),
 }
 
@@ -25,7 +33,15 @@ impl Display for DownloaderError {
       FetchError => write!(f, "Failed to fetch data from external source"),
       NoMasterPlaylistError => write!(f, "No master playlist found"),
       IOError => write!(f, "Failed to perform IO operation"),
-      FfmpegError => write!(f, "Failed to execute ffmpeg command"),
+      FfmpegError(e) => write!(f, "Failed to execute ffmpeg command: {}", e),
+      PlaylistParseError(e) => write!(f, "Failed to parse playlist: {}", e),
+      SegmentFetchError(index, Some(status)) => write!(f, "Failed to fetch segment #{} (HTTP {})", index, status),
+      SegmentFetchError(index, None) => write!(f, "Failed to fetch segment #{} (no response)", index),
+      InitSectionFetchError(Some(status)) => write!(f, "Failed to fetch init section (HTTP {})", status),
+      InitSectionFetchError(None) => write!(f, "Failed to fetch init section (no response)"),
+      RemuxError(e) => write!(f, "Failed to merge video and audio: {}", e),
+      DecryptionError(e) => write!(f, "Failed to decrypt stream: {}", e),
+      OversizeError(e) => write!(f, "Video does not fit the upload limit: {}", e),
       OtherError(e) => write!(f, "Error: {}", e),
     }
   }
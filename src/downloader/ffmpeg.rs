@@ -0,0 +1,171 @@
+//! Locating and running the ffmpeg binary.
+
+use std::{
+  ffi::OsStr,
+  path::{Path, PathBuf},
+  process::Stdio,
+  sync::OnceLock,
+};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+  process::Command,
+};
+
+use crate::{config, downloader::downloader_error::DownloaderError};
+
+/// How much of ffmpeg's stderr is kept in `FfmpegError`.
+const STDERR_TAIL_LINES: usize = 8;
+
+static FFMPEG: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+/// Finds the ffmpeg binary (`FFMPEG_PATH`, otherwise the first `ffmpeg` on
+/// `PATH`) and checks that it runs. The result is cached for the process.
+pub fn discover() -> Result<&'static Path, DownloaderError> {
+  FFMPEG
+    .get_or_init(|| {
+      let candidate = match &config::get().ffmpeg_path {
+        Some(path) => path.clone(),
+        None => find_on_path().ok_or("ffmpeg was not found on PATH; install it or set FFMPEG_PATH")?,
+      };
+
+      match std::process::Command::new(&candidate).arg("-version").stdout(Stdio::null()).stderr(Stdio::null()).status() {
+        Ok(status) if status.success() => Ok(candidate),
+        Ok(status) => Err(format!("{} -version exited with {status}", candidate.display())),
+        Err(e) => Err(format!("{} cannot be executed: {e}", candidate.display())),
+      }
+    })
+    .as_deref()
+    .map_err(|e| DownloaderError::FfmpegError(e.clone()))
+}
+
+/// Runs ffmpeg with `args`, failing with the tail of its stderr when it exits
+/// with a non-zero status.
+pub async fn run<I, S>(args: I) -> Result<(), DownloaderError>
+where
+  I: IntoIterator<Item = S>,
+  S: AsRef<OsStr>,
+{
+  let output = Command::new(discover()?)
+    .args(["-hide_banner", "-nostdin", "-y"])
+    .args(args)
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true)
+    .output()
+    .await
+    .map_err(|e| DownloaderError::FfmpegError(format!("failed to start ffmpeg: {e}")))?;
+
+  if output.status.success() {
+    return Ok(());
+  }
+
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  Err(DownloaderError::FfmpegError(format!("ffmpeg exited with {}: {}", output.status, stderr_tail(&stderr))))
+}
+
+/// Like `run`, but calls `on_progress` with the share of `duration` seconds of
+/// output written so far, as ffmpeg reports it on `-progress`.
+pub async fn run_with_progress<I, S>(args: I, duration: f64, on_progress: impl Fn(f64)) -> Result<(), DownloaderError>
+where
+  I: IntoIterator<Item = S>,
+  S: AsRef<OsStr>,
+{
+  let mut child = Command::new(discover()?)
+    .args(["-hide_banner", "-nostdin", "-y", "-nostats", "-progress", "pipe:1"])
+    .args(args)
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true)
+    .spawn()
+    .map_err(|e| DownloaderError::FfmpegError(format!("failed to start ffmpeg: {e}")))?;
+
+  let (stdout, mut stderr) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+  let read_progress = async {
+    let mut lines = BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+      if let Some(position) = parse_progress(&line) {
+        on_progress(position / duration);
+      }
+    }
+  };
+  let read_stderr = async {
+    let mut output = vec![];
+    let _ = stderr.read_to_end(&mut output).await;
+    output
+  };
+  let ((), stderr) = tokio::join!(read_progress, read_stderr);
+  let status = child.wait().await.map_err(|e| DownloaderError::FfmpegError(format!("failed to wait for ffmpeg: {e}")))?;
+
+  if status.success() {
+    return Ok(());
+  }
+
+  let stderr = String::from_utf8_lossy(&stderr);
+  Err(DownloaderError::FfmpegError(format!("ffmpeg exited with {status}: {}", stderr_tail(&stderr))))
+}
+
+/// Length of the media file at `path` in seconds, as reported by ffmpeg.
+pub async fn duration(path: &Path) -> Result<f64, DownloaderError> {
+  // Without an output ffmpeg only prints the input's details and exits with an
+  // error, so the status is ignored.
+  let output = Command::new(discover()?)
+    .args(["-hide_banner", "-nostdin", "-i"])
+    .arg(path)
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true)
+    .output()
+    .await
+    .map_err(|e| DownloaderError::FfmpegError(format!("failed to start ffmpeg: {e}")))?;
+
+  parse_duration(&String::from_utf8_lossy(&output.stderr))
+    .ok_or_else(|| DownloaderError::FfmpegError(format!("no duration for {}", path.display())))
+}
+
+/// Reads `Duration: 00:01:02.50` from ffmpeg's description of an input.
+fn parse_duration(stderr: &str) -> Option<f64> {
+  let (_, rest) = stderr.split_once("Duration: ")?;
+  let timestamp = rest.split(',').next()?.trim();
+  let mut parts = timestamp.splitn(3, ':').map(|part| part.parse::<f64>().ok());
+  let (hours, minutes, seconds) = (parts.next()??, parts.next()??, parts.next()??);
+  Some(hours * 3600.0 + minutes * 60.0 + seconds).filter(|&duration| duration > 0.0)
+}
+
+/// Reads the output position in seconds from an `out_time_us=` line of
+/// ffmpeg's progress report.
+fn parse_progress(line: &str) -> Option<f64> {
+  let microseconds = line.strip_prefix("out_time_us=")?.trim().parse::<u64>().ok()?;
+  Some(microseconds as f64 / 1_000_000.0)
+}
+
+fn stderr_tail(stderr: &str) -> String {
+  let lines = stderr.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>();
+  lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+}
+
+fn find_on_path() -> Option<PathBuf> {
+  let name = if cfg!(target_os = "windows") { "ffmpeg.exe" } else { "ffmpeg" };
+  std::env::split_paths(&std::env::var_os("PATH")?).map(|dir| dir.join(name)).find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_duration_from_input_description() {
+    let stderr = "Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'video.mp4':\n  Duration: 01:02:03.50, start: 0.000000, bitrate: 2130 kb/s\n\
+      At least one output file must be specified\n";
+
+    assert_eq!(parse_duration(stderr), Some(3723.5));
+    assert_eq!(parse_duration("  Duration: N/A, bitrate: N/A"), None);
+    assert_eq!(parse_duration("No such file or directory"), None);
+  }
+
+  #[test]
+  fn reads_position_from_progress_report() {
+    assert_eq!(parse_progress("out_time_us=12500000"), Some(12.5));
+    assert_eq!(parse_progress("out_time_us=N/A"), None);
+    assert_eq!(parse_progress("out_time=00:00:12.500000"), None);
+  }
+}
@@ -0,0 +1,42 @@
+//! Process-wide HTTP client shared by all downloads, so connections to the CDN
+//! are pooled and reused instead of being opened per request.
+
+use reqwest::{Response, StatusCode};
+use std::{sync::OnceLock, time::Duration};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::config;
+
+pub fn client() -> &'static reqwest::Client {
+  static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+  CLIENT.get_or_init(|| {
+    reqwest::Client::builder()
+      .pool_max_idle_per_host(config::get().global_segment_concurrency)
+      .pool_idle_timeout(Duration::from_secs(90))
+      .connect_timeout(Duration::from_secs(15))
+      .build()
+      .unwrap()
+  })
+}
+
+/// Reads the whole body of `response`, giving up when no data arrives for
+/// `read_timeout`, so a server that stalls mid-body cannot hold a request open
+/// forever. On failure returns the status of the error, if any.
+pub async fn read_body(mut response: Response, read_timeout: Duration) -> Result<Vec<u8>, Option<StatusCode>> {
+  let mut body = vec![];
+  loop {
+    match tokio::time::timeout(read_timeout, response.chunk()).await {
+      Ok(Ok(Some(chunk))) => body.extend_from_slice(&chunk),
+      Ok(Ok(None)) => return Ok(body),
+      Ok(Err(e)) => return Err(e.status()),
+      Err(_) => return Err(None),
+    }
+  }
+}
+
+/// Waits for one of the process-wide segment fetch slots.
+pub async fn acquire_segment_slot() -> SemaphorePermit<'static> {
+  static SLOTS: OnceLock<Semaphore> = OnceLock::new();
+  let slots = SLOTS.get_or_init(|| Semaphore::new(config::get().global_segment_concurrency));
+  slots.acquire().await.unwrap()
+}
@@ -0,0 +1,117 @@
+//! Scratch directories for download jobs.
+//!
+//! Every job writes its intermediate and output files into its own directory
+//! under `TEMP_DIR`, so concurrent jobs never share file names. The directory is
+//! removed when the `JobDir` is dropped, which covers success, errors and
+//! unwinding panics. Release builds abort on panic without running destructors,
+//! so `clean_stale` sweeps leftovers from previous runs at startup.
+//!
+//! Directory names carry the pid of the bot that created them, so bots sharing
+//! `TEMP_DIR` leave each other's running jobs alone.
+
+use std::{
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicU64, Ordering},
+  time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use crate::{config, downloader::downloader_error::DownloaderError};
+
+pub struct JobDir {
+  path: PathBuf,
+}
+
+impl JobDir {
+  pub fn create() -> Result<Self, DownloaderError> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let root = &config::get().temp_dir;
+    std::fs::create_dir_all(root).map_err(|_| DownloaderError::IOError)?;
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.subsec_nanos()).unwrap_or_default();
+    let name = format!("job-{}-{}-{nanos:09}", std::process::id(), NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let path = root.join(name);
+    std::fs::create_dir(&path).map_err(|_| DownloaderError::IOError)?;
+
+    Ok(JobDir { path })
+  }
+
+  /// Path of a file inside this job's directory.
+  pub fn file(&self, name: &str) -> PathBuf {
+    self.path.join(name)
+  }
+}
+
+impl Drop for JobDir {
+  fn drop(&mut self) {
+    if let Err(e) = std::fs::remove_dir_all(&self.path) {
+      warn!("Failed to remove job directory {}: {e}", self.path.display());
+    }
+  }
+}
+
+/// Removes job directories left behind by earlier runs of the bot.
+pub fn clean_stale() {
+  clean_stale_in(&config::get().temp_dir);
+}
+
+/// Removes the job directories in `root` whose bot is no longer running. This
+/// runs before the first job, so directories with our own pid are leftovers of
+/// an earlier run that had the same pid.
+fn clean_stale_in(root: &Path) {
+  let Ok(entries) = std::fs::read_dir(root) else {
+    return;
+  };
+
+  for entry in entries.flatten() {
+    let name = entry.file_name().to_string_lossy().into_owned();
+    let Some(pid) = name.strip_prefix("job-").map(|rest| rest.split('-').next().unwrap_or_default().parse::<u32>()) else {
+      continue;
+    };
+    if pid.is_ok_and(|pid| pid != std::process::id() && is_running(pid)) {
+      continue;
+    }
+    let _ = std::fs::remove_dir_all(entry.path());
+  }
+}
+
+fn is_running(pid: u32) -> bool {
+  #[cfg(target_os = "linux")]
+  {
+    Path::new("/proc").join(pid.to_string()).exists()
+  }
+  #[cfg(target_os = "windows")]
+  {
+    std::process::Command::new("tasklist")
+      .args(["/FI", &format!("PID eq {pid}"), "/NH"])
+      .output()
+      .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).split_whitespace().any(|word| word == pid.to_string()))
+  }
+  #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+  {
+    std::process::Command::new("kill").args(["-0", &pid.to_string()]).output().is_ok_and(|output| output.status.success())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_directories_of_running_bots() {
+    let root = std::env::temp_dir().join(format!("vid-downloader-test-clean-stale-{}", std::process::id()));
+    // pid 1 always runs; no system hands out pids this large.
+    let names = ["job-1-0-000000001", "job-4294967295-0-000000001", &format!("job-{}-0-000000001", std::process::id()), "other"];
+    for name in names {
+      std::fs::create_dir_all(root.join(name)).unwrap();
+    }
+
+    clean_stale_in(&root);
+
+    let mut left = std::fs::read_dir(&root).unwrap().flatten().map(|entry| entry.file_name().to_string_lossy().into_owned()).collect::<Vec<_>>();
+    left.sort();
+    let _ = std::fs::remove_dir_all(&root);
+    assert_eq!(left, ["job-1-0-000000001", "other"]);
+  }
+}
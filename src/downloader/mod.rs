@@ -1,6 +1,15 @@
+pub mod dash;
+#[allow(clippy::module_inception)]
 pub mod downloader;
 pub mod downloader_error;
+pub mod ffmpeg;
+pub mod http;
+pub mod job_dir;
+pub mod oversize;
 pub mod playlist;
 pub mod platforms;
+pub mod progress;
+#[cfg(test)]
+pub mod test_server;
 
 pub use downloader::Downloader;
\ No newline at end of file
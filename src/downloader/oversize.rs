@@ -0,0 +1,197 @@
+//! Making finished videos fit the upload limit.
+//!
+//! A video over the limit is either re-encoded in two passes to the bitrate
+//! that fills the limit, or cut into parts without re-encoding. Cuts can only
+//! fall on keyframes, so parts come out unevenly sized and are cut again,
+//! shorter, when one of them is still too large.
+
+use std::{
+  ffi::OsStr,
+  fmt,
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use crate::downloader::{
+  downloader_error::DownloaderError,
+  ffmpeg,
+  job_dir::JobDir,
+  progress::{ProgressReporter, Stage},
+};
+
+/// Bitrate of the audio track in re-encoded videos.
+const AUDIO_BITRATE: u64 = 128_000;
+/// Below this the picture is not worth sending.
+const MIN_VIDEO_BITRATE: u64 = 150_000;
+/// Share of the limit aimed for, leaving room for container overhead and rate
+/// control overshooting.
+const HEADROOM: f64 = 0.92;
+const SPLIT_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizeStrategy {
+  /// Two-pass H.264 encode to the bitrate that fits.
+  Reencode,
+  /// Numbered parts cut at keyframes, sent as separate messages.
+  Split,
+}
+
+impl FromStr for OversizeStrategy {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "reencode" => Ok(OversizeStrategy::Reencode),
+      "split" => Ok(OversizeStrategy::Split),
+      _ => Err(()),
+    }
+  }
+}
+
+impl fmt::Display for OversizeStrategy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      OversizeStrategy::Reencode => "reencode",
+      OversizeStrategy::Split => "split",
+    })
+  }
+}
+
+/// Returns `path` itself when it is at most `limit` bytes, otherwise the
+/// re-encoded file or the parts, in order, written to `job_dir`.
+pub async fn fit(
+  path: &Path,
+  limit: u64,
+  strategy: OversizeStrategy,
+  job_dir: &JobDir,
+  progress: &ProgressReporter,
+) -> Result<Vec<PathBuf>, DownloaderError> {
+  let size = file_size(path).await?;
+  if size <= limit {
+    return Ok(vec![path.to_path_buf()]);
+  }
+
+  let duration = ffmpeg::duration(path).await?;
+  match strategy {
+    OversizeStrategy::Reencode => {
+      progress.start(Stage::Reencoding);
+      reencode(path, duration, limit, job_dir, progress).await.map(|output| vec![output])
+    }
+    OversizeStrategy::Split => {
+      progress.start(Stage::Splitting);
+      split(path, size, duration, limit, job_dir, progress).await
+    }
+  }
+}
+
+async fn reencode(path: &Path, duration: f64, limit: u64, job_dir: &JobDir, progress: &ProgressReporter) -> Result<PathBuf, DownloaderError> {
+  let bitrate = video_bitrate(limit, duration)
+    .ok_or_else(|| DownloaderError::OversizeError(format!("{duration:.0} s of video cannot be encoded into {} MB", limit / 1024 / 1024)))?;
+  let bitrate = bitrate.to_string();
+  let pass_log = job_dir.file("reencode");
+  let output = job_dir.file(&format!("reencoded_{}", path.file_name().unwrap_or(OsStr::new("video.mp4")).to_string_lossy()));
+  let null = if cfg!(target_os = "windows") { "NUL" } else { "/dev/null" };
+
+  let encode = |pass: &'static str| {
+    let mut args = vec![OsStr::new("-i"), path.as_os_str()];
+    args.extend(["-c:v", "libx264", "-preset", "medium", "-b:v", &bitrate, "-pass", pass, "-passlogfile"].map(OsStr::new));
+    args.push(pass_log.as_os_str());
+    args
+  };
+
+  let mut first_pass = encode("1");
+  first_pass.extend(["-an", "-f", "null", null].map(OsStr::new));
+  // Each pass reads the whole video, so each is half of the work.
+  ffmpeg::run_with_progress(first_pass, duration, |fraction| progress.set_fraction(fraction / 2.0)).await?;
+
+  let audio_bitrate = AUDIO_BITRATE.to_string();
+  let mut second_pass = encode("2");
+  second_pass.extend(["-c:a", "aac", "-b:a", &audio_bitrate, "-movflags", "+faststart"].map(OsStr::new));
+  second_pass.push(output.as_os_str());
+  ffmpeg::run_with_progress(second_pass, duration, |fraction| progress.set_fraction(0.5 + fraction / 2.0)).await?;
+
+  Ok(output)
+}
+
+async fn split(path: &Path, size: u64, duration: f64, limit: u64, job_dir: &JobDir, progress: &ProgressReporter) -> Result<Vec<PathBuf>, DownloaderError> {
+  let extension = path.extension().and_then(OsStr::to_str).unwrap_or("mp4");
+  let mut segment_time = part_duration(duration, size, limit);
+
+  for attempt in 0..SPLIT_ATTEMPTS {
+    let pattern = job_dir.file(&format!("part{attempt}_%03d.{extension}"));
+    let segment_time_arg = format!("{segment_time:.3}");
+    let mut args = vec![OsStr::new("-i"), path.as_os_str()];
+    args.extend(["-map", "0", "-c", "copy", "-f", "segment", "-segment_time", &segment_time_arg, "-reset_timestamps", "1"].map(OsStr::new));
+    args.push(pattern.as_os_str());
+    ffmpeg::run_with_progress(args, duration, |fraction| progress.set_fraction(fraction)).await?;
+
+    let mut parts = vec![];
+    let mut largest = 0;
+    loop {
+      let part = job_dir.file(&format!("part{attempt}_{:03}.{extension}", parts.len()));
+      if !part.exists() {
+        break;
+      }
+      largest = largest.max(file_size(&part).await?);
+      parts.push(part);
+    }
+
+    if largest <= limit {
+      return Ok(parts);
+    }
+    for part in &parts {
+      let _ = tokio::fs::remove_file(part).await;
+    }
+    segment_time = part_duration(segment_time, largest, limit);
+  }
+
+  Err(DownloaderError::OversizeError(format!("keyframes are too far apart to cut parts under {} MB", limit / 1024 / 1024)))
+}
+
+/// Video bitrate that makes `duration` seconds, with audio, fit in `limit`
+/// bytes.
+fn video_bitrate(limit: u64, duration: f64) -> Option<u64> {
+  let total = (limit as f64 * 8.0 * HEADROOM / duration) as u64;
+  total.checked_sub(AUDIO_BITRATE).filter(|&bitrate| bitrate >= MIN_VIDEO_BITRATE)
+}
+
+/// Length of parts cut from `duration` seconds that took `size` bytes, such
+/// that each takes at most `limit` bytes at the same bitrate.
+fn part_duration(duration: f64, size: u64, limit: u64) -> f64 {
+  duration * limit as f64 / size as f64 * HEADROOM
+}
+
+async fn file_size(path: &Path) -> Result<u64, DownloaderError> {
+  tokio::fs::metadata(path).await.map(|metadata| metadata.len()).map_err(|_| DownloaderError::IOError)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MB: u64 = 1024 * 1024;
+
+  #[test]
+  fn targets_bitrate_that_fills_the_limit() {
+    // 50 MB over 10 minutes is about 643 kb/s, 128 kb/s of it audio.
+    assert_eq!(video_bitrate(50 * MB, 600.0), Some(515_126));
+    assert_eq!(video_bitrate(50 * MB, 3.0 * 3600.0), None);
+  }
+
+  #[test]
+  fn shortens_parts_in_proportion_to_the_excess() {
+    assert_eq!(part_duration(600.0, 200 * MB, 50 * MB), 138.0);
+    assert_eq!(part_duration(100.0, 50 * MB, 50 * MB), 92.0);
+  }
+
+  #[tokio::test]
+  async fn keeps_files_within_the_limit() {
+    let job_dir = JobDir::create().unwrap();
+    let path = job_dir.file("video.mp4");
+    tokio::fs::write(&path, [0; 100]).await.unwrap();
+
+    let parts = fit(&path, 100, OversizeStrategy::Split, &job_dir, &ProgressReporter::default()).await.unwrap();
+
+    assert_eq!(parts, [path]);
+  }
+}
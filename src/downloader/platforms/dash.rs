@@ -0,0 +1,45 @@
+use headless_chrome::Browser;
+use reqwest::header::HeaderMap;
+use std::path::PathBuf;
+
+use crate::downloader::{
+    dash, downloader::PlatformDownloader, downloader_error::DownloaderError, job_dir::JobDir,
+    playlist::variant_playlist::VariantPlaylist, progress::ProgressReporter,
+};
+
+/// Direct links to DASH manifests (`.mpd`).
+pub struct DashDownloader {}
+
+impl PlatformDownloader for DashDownloader {
+    async fn download(
+        _browser: &Browser,
+        _url: &str,
+        _job_dir: &JobDir,
+        _progress: &ProgressReporter,
+    ) -> Result<PathBuf, DownloaderError> {
+        Err(DownloaderError::OtherError("DASH downloader supports variant playlist. Please use get_variant_playlist function".into()))
+    }
+
+    async fn get_variant_playlist(
+        _browser: &Browser,
+        url: &str,
+    ) -> Result<VariantPlaylist, DownloaderError> {
+        dash::variant_playlist(url, HeaderMap::new()).await
+    }
+
+    fn validate_url(url: &str) -> Result<(), DownloaderError> {
+        let url = reqwest::Url::parse(url).map_err(|_| DownloaderError::UnsupportedPlatformError)?;
+
+        if !matches!(url.scheme(), "http" | "https") || !url.path().ends_with(".mpd") {
+            return Err(DownloaderError::UnsupportedPlatformError);
+        }
+
+        Ok(())
+    }
+
+    fn media_id(url: &str) -> Option<String> {
+        let mut url = reqwest::Url::parse(url).ok()?;
+        url.set_fragment(None);
+        Some(format!("dash:{url}"))
+    }
+}
@@ -1,2 +1,3 @@
+pub mod dash;
 pub mod tiktok;
-pub mod twitter;
\ No newline at end of file
+pub mod twitter;
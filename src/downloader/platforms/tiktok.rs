@@ -10,21 +10,27 @@ use headless_chrome::{
     },
     Browser,
 };
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
 
 use crate::downloader::{
-    downloader::PlatformDownloader, downloader_error::DownloaderError,
-    playlist::variant_playlist::VariantPlaylist,
+    downloader::{PlatformDownloader, TabGuard}, downloader_error::DownloaderError, http, job_dir::JobDir,
+    playlist::variant_playlist::VariantPlaylist, progress::ProgressReporter,
 };
 
 pub struct TiktokDownloader {}
 
 impl PlatformDownloader for TiktokDownloader {
-    async fn download(browser: &Browser, url: &str) -> Result<String, DownloaderError> {
+    async fn download(
+        browser: &Browser,
+        url: &str,
+        job_dir: &JobDir,
+        progress: &ProgressReporter,
+    ) -> Result<PathBuf, DownloaderError> {
         let target = get_initial_tab_create_target();
-        let tab = browser.new_tab_with_options(target)?;
+        let tab = TabGuard::new(browser.new_tab_with_options(target)?);
         let intercepted_url = Arc::new(Mutex::new(String::new()));
         let intercepted_cookie = Arc::new(Mutex::new(String::new()));
         let interceptor = get_interceptor(intercepted_url.clone(), intercepted_cookie.clone());
@@ -34,23 +40,23 @@ impl PlatformDownloader for TiktokDownloader {
         tab.navigate_to(url)?;
 
         let mut found = false;
-        let mut timeout = 10.0 as f32;
+        let mut timeout = 10.0_f32;
         while !found && timeout >= 0.0 {
             found = !intercepted_url.lock().await.is_empty();
             tokio::time::sleep(Duration::from_millis(100)).await;
             timeout -= 0.1;
         }
-        let _ = tab.close(false);
+        drop(tab);
 
         if !found {
             return Err(DownloaderError::FetchError);
         }
 
-        let client = reqwest::Client::new();
+        let client = http::client();
 
         let url_mutex_guard = intercepted_url.lock().await.to_owned();
         let cookie_mutex_guard = intercepted_cookie.lock().await.to_owned();
-        let response = client
+        let mut response = client
             .get(url_mutex_guard)
             .header(
                 "User-Agent",
@@ -62,15 +68,9 @@ impl PlatformDownloader for TiktokDownloader {
             .await
             .map_err(|_| DownloaderError::FetchError)?;
 
-        let bytes = response
-            .bytes()
-            .await
-            .map_err(|_| DownloaderError::FetchError)?;
-
         let mut output_name = url
             .split('/')
-            .filter(|s| !s.is_empty())
-            .last()
+            .rfind(|s| !s.is_empty())
             .unwrap_or("video")
             .to_string();
         if output_name.contains('?') {
@@ -78,11 +78,26 @@ impl PlatformDownloader for TiktokDownloader {
         }
         output_name.push_str(".mp4");
 
-        tokio::fs::write(&output_name, bytes)
+        let output_path = job_dir.file(&output_name);
+        let mut file = tokio::fs::File::create(&output_path)
             .await
             .map_err(|_| DownloaderError::IOError)?;
+        if let Some(length) = response.content_length() {
+            progress.expect(length);
+        }
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|_| DownloaderError::FetchError)?
+        {
+            file.write_all(&chunk)
+                .await
+                .map_err(|_| DownloaderError::IOError)?;
+            progress.advance(chunk.len() as u64, chunk.len() as u64);
+        }
+        file.flush().await.map_err(|_| DownloaderError::IOError)?;
 
-        Ok(output_name)
+        Ok(output_path)
     }
 
     async fn get_variant_playlist(
@@ -105,6 +120,18 @@ impl PlatformDownloader for TiktokDownloader {
 
         Ok(())
     }
+
+    fn media_id(url: &str) -> Option<String> {
+        let video_regex = regex::Regex::new(r"\/video\/(\d+)").unwrap();
+        if let Some(video_id) = video_regex.captures(url).and_then(|captures| captures.get(1)) {
+            return Some(format!("tiktok:{}", video_id.as_str()));
+        }
+
+        // Short links only reveal the video ID after a redirect, so the link
+        // itself stands in for it.
+        let url = reqwest::Url::parse(url).ok()?;
+        Some(format!("tiktok:{}{}", url.host_str()?, url.path().trim_end_matches('/')))
+    }
 }
 
 fn get_interceptor(
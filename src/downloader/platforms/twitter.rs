@@ -10,19 +10,26 @@ use headless_chrome::{
     },
     Browser,
 };
+use reqwest::header::HeaderMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::Mutex;
 
 use crate::downloader::{
-    downloader::PlatformDownloader, downloader_error::DownloaderError,
-    playlist::variant_playlist::VariantPlaylist,
+    downloader::{PlatformDownloader, TabGuard}, downloader_error::DownloaderError, job_dir::JobDir,
+    playlist::variant_playlist::VariantPlaylist, progress::ProgressReporter,
 };
 
 pub struct TwitterDownloader {}
 
 impl PlatformDownloader for TwitterDownloader {
-    async fn download(_browser: &Browser, _url: &str) -> Result<String, DownloaderError> {
+    async fn download(
+        _browser: &Browser,
+        _url: &str,
+        _job_dir: &JobDir,
+        _progress: &ProgressReporter,
+    ) -> Result<PathBuf, DownloaderError> {
         Err(DownloaderError::OtherError("Twitter downloader supports variant playlist. Please use get_variant_playlist function".into()))
     }
 
@@ -31,28 +38,28 @@ impl PlatformDownloader for TwitterDownloader {
         url: &str,
     ) -> Result<VariantPlaylist, DownloaderError> {
         let target = get_initial_tab_create_target();
-        let tab = browser.new_tab_with_options(target)?;
+        let tab = TabGuard::new(browser.new_tab_with_options(target)?);
         let intercepted_url = Arc::new(Mutex::new(String::new()));
         let interceptor = get_interceptor(intercepted_url.clone());
 
-        tab.enable_fetch(Some(&vec![get_request_pattern()]), None)?;
+        tab.enable_fetch(Some(&[get_request_pattern()]), None)?;
         tab.enable_request_interception(interceptor)?;
         tab.navigate_to(url)?;
 
         let mut found = false;
-        let mut timeout = 10.0 as f32;
+        let mut timeout = 10.0_f32;
         while !found && timeout >= 0.0 {
             found = !intercepted_url.lock().await.is_empty();
             tokio::time::sleep(Duration::from_millis(100)).await;
             timeout -= 0.1;
         }
-        let _ = tab.close(false);
+        drop(tab);
         if !found {
             return Err(DownloaderError::FetchError);
         }
 
         let variant_playlist_url = intercepted_url.lock().await.to_owned();
-        VariantPlaylist::from_url(&variant_playlist_url).await
+        VariantPlaylist::from_url(&variant_playlist_url, HeaderMap::new()).await
     }
 
     fn validate_url(url: &str) -> Result<(), DownloaderError> {
@@ -66,6 +73,12 @@ impl PlatformDownloader for TwitterDownloader {
 
         Ok(())
     }
+
+    fn media_id(url: &str) -> Option<String> {
+        let status_regex = regex::Regex::new(r"\/status\/(\d+)").unwrap();
+        let status_id = status_regex.captures(url)?.get(1)?.as_str();
+        Some(format!("twitter:{status_id}"))
+    }
 }
 
 fn get_interceptor(
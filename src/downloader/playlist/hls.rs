@@ -0,0 +1,704 @@
+//! Tag and attribute-list parser for HLS playlists (RFC 8216).
+//!
+//! `VariantManifest` is the parsed form of a multivariant ("master") playlist and
+//! `MediaManifest` the parsed form of a media playlist. Unknown tags are ignored,
+//! as required by the spec.
+
+use std::collections::HashMap;
+
+use crate::downloader::downloader_error::DownloaderError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Resolution {
+  pub width: u32,
+  pub height: u32,
+}
+
+impl Resolution {
+  pub fn pixels(&self) -> u64 {
+    self.width as u64 * self.height as u64
+  }
+}
+
+impl std::fmt::Display for Resolution {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}x{}", self.width, self.height)
+  }
+}
+
+/// `#EXT-X-STREAM-INF` together with the URI line that follows it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInf {
+  pub bandwidth: u64,
+  pub average_bandwidth: Option<u64>,
+  pub codecs: Option<String>,
+  pub resolution: Option<Resolution>,
+  pub frame_rate: Option<f64>,
+  pub audio: Option<String>,
+  pub video: Option<String>,
+  pub subtitles: Option<String>,
+  pub uri: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+  Audio,
+  Video,
+  Subtitles,
+  ClosedCaptions,
+}
+
+/// `#EXT-X-MEDIA` rendition belonging to a media group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Media {
+  pub media_type: MediaType,
+  pub group_id: String,
+  pub name: String,
+  pub language: Option<String>,
+  pub default: bool,
+  pub autoselect: bool,
+  pub uri: Option<String>,
+}
+
+impl Media {
+  /// Name shown to users, e.g. `English (en)`.
+  pub fn label(&self) -> String {
+    match &self.language {
+      Some(language) if !self.name.eq_ignore_ascii_case(language) => format!("{} ({language})", self.name),
+      _ => self.name.clone(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VariantManifest {
+  pub variants: Vec<StreamInf>,
+  pub media: Vec<Media>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistType {
+  Event,
+  Vod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyMethod {
+  Aes128,
+  SampleAes,
+  SampleAesCtr,
+}
+
+/// `#EXT-X-KEY` in effect for a segment. `METHOD=NONE` is represented by the
+/// absence of a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+  pub method: KeyMethod,
+  pub uri: String,
+  pub iv: Option<[u8; 16]>,
+  pub key_format: Option<String>,
+}
+
+impl Key {
+  /// Whether the key file holds the raw key bytes, as opposed to a DRM system's
+  /// license data.
+  pub fn is_identity(&self) -> bool {
+    self.key_format.as_deref().is_none_or(|format| format == "identity")
+  }
+}
+
+/// Sub-range of a resource, with the implicit offset of `#EXT-X-BYTERANGE`
+/// already resolved. Parsers only build ranges whose end fits in a `u64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+  pub length: u64,
+  pub offset: u64,
+}
+
+impl ByteRange {
+  /// Offset of the last byte, as used in an HTTP `Range` header.
+  pub fn last_byte(&self) -> u64 {
+    self.offset + self.length - 1
+  }
+}
+
+/// `#EXT-X-MAP` media initialization section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Map {
+  pub uri: String,
+  pub byte_range: Option<ByteRange>,
+  pub key: Option<Key>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+  pub uri: String,
+  pub duration: f64,
+  pub title: Option<String>,
+  pub discontinuity: bool,
+  pub map: Option<Map>,
+  pub key: Option<Key>,
+  pub byte_range: Option<ByteRange>,
+  /// Media sequence number of this segment.
+  pub sequence: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MediaManifest {
+  pub target_duration: u64,
+  pub media_sequence: u64,
+  pub playlist_type: Option<PlaylistType>,
+  pub end_list: bool,
+  pub segments: Vec<Segment>,
+}
+
+impl VariantManifest {
+  pub fn parse(input: &str) -> Result<Self, DownloaderError> {
+    let mut manifest = VariantManifest::default();
+    let mut pending_stream: Option<StreamInf> = None;
+
+    for line in playlist_lines(input)? {
+      match line {
+        Line::Tag("EXT-X-STREAM-INF", value) => {
+          let attributes = parse_attribute_list(value.unwrap_or_default())?;
+          pending_stream = Some(parse_stream_inf(&attributes)?);
+        }
+        Line::Tag("EXT-X-MEDIA", value) => {
+          let attributes = parse_attribute_list(value.unwrap_or_default())?;
+          manifest.media.push(parse_media(&attributes)?);
+        }
+        Line::Tag(_, _) => {}
+        Line::Uri(uri) => {
+          if let Some(mut stream) = pending_stream.take() {
+            stream.uri = uri.to_string();
+            manifest.variants.push(stream);
+          }
+        }
+      }
+    }
+
+    if pending_stream.is_some() {
+      return Err(parse_error("EXT-X-STREAM-INF is not followed by a URI"));
+    }
+
+    Ok(manifest)
+  }
+
+  /// Renditions of the given type belonging to `group_id`, in playlist order.
+  pub fn group(&self, media_type: MediaType, group_id: &str) -> impl Iterator<Item = &Media> {
+    let group_id = group_id.to_string();
+    self.media.iter().filter(move |media| media.media_type == media_type && media.group_id == group_id)
+  }
+}
+
+impl MediaManifest {
+  /// Total duration of the listed segments, in seconds.
+  pub fn duration(&self) -> f64 {
+    self.segments.iter().map(|segment| segment.duration).sum()
+  }
+
+  pub fn parse(input: &str) -> Result<Self, DownloaderError> {
+    let mut manifest = MediaManifest::default();
+    let mut pending_duration: Option<(f64, Option<String>)> = None;
+    let mut discontinuity = false;
+    let mut map: Option<Map> = None;
+    let mut key: Option<Key> = None;
+    // Whether an EXT-X-KEY was seen since the last segment, i.e. the current one
+    // is listed alongside another for the same segments.
+    let mut key_listed_since_segment = false;
+    let mut pending_byte_range: Option<(u64, Option<u64>)> = None;
+    // URI and end offset of the previous segment's sub-range, which is where a
+    // byte range without an explicit offset starts.
+    let mut previous_range_end: Option<(&str, u64)> = None;
+
+    for line in playlist_lines(input)? {
+      match line {
+        Line::Tag("EXT-X-TARGETDURATION", value) => manifest.target_duration = parse_integer(value, "EXT-X-TARGETDURATION")?,
+        Line::Tag("EXT-X-MEDIA-SEQUENCE", value) => manifest.media_sequence = parse_integer(value, "EXT-X-MEDIA-SEQUENCE")?,
+        Line::Tag("EXT-X-PLAYLIST-TYPE", value) => {
+          manifest.playlist_type = match value {
+            Some("VOD") => Some(PlaylistType::Vod),
+            Some("EVENT") => Some(PlaylistType::Event),
+            _ => return Err(parse_error("invalid EXT-X-PLAYLIST-TYPE")),
+          }
+        }
+        Line::Tag("EXT-X-ENDLIST", _) => manifest.end_list = true,
+        Line::Tag("EXT-X-DISCONTINUITY", _) => discontinuity = true,
+        Line::Tag("EXT-X-MAP", value) => {
+          let attributes = parse_attribute_list(value.unwrap_or_default())?;
+          let uri = required(&attributes, "URI", "EXT-X-MAP")?.to_string();
+          let byte_range = match attributes.get("BYTERANGE") {
+            Some(value) => {
+              let (length, offset) = parse_byte_range(value)?;
+              Some(ByteRange { length, offset: offset.unwrap_or(0) })
+            }
+            None => None,
+          };
+          map = Some(Map { uri, byte_range, key: key.clone() });
+        }
+        Line::Tag("EXT-X-KEY", value) => {
+          let attributes = parse_attribute_list(value.unwrap_or_default())?;
+          let new_key = parse_key(&attributes)?;
+          // Streams offering several key formats list the plain one next to DRM
+          // ones; keep the one we can actually use. A key listed on its own
+          // replaces the previous one even when it is unusable, so that its
+          // segments fail instead of being decrypted with a stale key.
+          let keeps_identity_key = key_listed_since_segment
+            && key.as_ref().is_some_and(Key::is_identity)
+            && new_key.as_ref().is_some_and(|new_key| !new_key.is_identity());
+          if !keeps_identity_key {
+            key = new_key;
+          }
+          key_listed_since_segment = true;
+        }
+        Line::Tag("EXTINF", value) => {
+          let value = value.ok_or_else(|| parse_error("EXTINF without duration"))?;
+          let (duration, title) = match value.split_once(',') {
+            Some((duration, title)) => (duration, Some(title.trim()).filter(|title| !title.is_empty())),
+            None => (value, None),
+          };
+          let duration = duration.trim().parse::<f64>().map_err(|_| parse_error("invalid EXTINF duration"))?;
+          pending_duration = Some((duration, title.map(str::to_string)));
+        }
+        Line::Tag("EXT-X-BYTERANGE", value) => {
+          pending_byte_range = Some(parse_byte_range(value.ok_or_else(|| parse_error("EXT-X-BYTERANGE without value"))?)?);
+        }
+        Line::Tag(_, _) => {}
+        Line::Uri(uri) => {
+          let (duration, title) = pending_duration.take().ok_or_else(|| parse_error("segment URI without EXTINF"))?;
+          let byte_range = match pending_byte_range.take() {
+            Some((length, Some(offset))) => Some(ByteRange { length, offset }),
+            Some((length, None)) => match previous_range_end {
+              Some((previous_uri, end)) if previous_uri == uri => {
+                end.checked_add(length).ok_or_else(|| parse_error("EXT-X-BYTERANGE ends past the largest offset"))?;
+                Some(ByteRange { length, offset: end })
+              }
+              _ => return Err(parse_error("EXT-X-BYTERANGE without offset does not follow a sub-range of the same resource")),
+            },
+            None => None,
+          };
+          previous_range_end = byte_range.map(|range| (uri, range.offset + range.length));
+
+          let sequence = manifest
+            .media_sequence
+            .checked_add(manifest.segments.len() as u64)
+            .ok_or_else(|| parse_error("EXT-X-MEDIA-SEQUENCE overflows"))?;
+          manifest.segments.push(Segment {
+            uri: uri.to_string(),
+            duration,
+            title,
+            discontinuity,
+            map: map.clone(),
+            key: key.clone(),
+            byte_range,
+            sequence,
+          });
+          discontinuity = false;
+          key_listed_since_segment = false;
+        }
+      }
+    }
+
+    Ok(manifest)
+  }
+}
+
+enum Line<'a> {
+  Tag(&'a str, Option<&'a str>),
+  Uri(&'a str),
+}
+
+/// Splits a playlist into tag and URI lines, dropping blanks and comments.
+fn playlist_lines(input: &str) -> Result<Vec<Line<'_>>, DownloaderError> {
+  let mut lines = input.lines().map(str::trim).filter(|line| !line.is_empty());
+
+  if lines.next() != Some("#EXTM3U") {
+    return Err(parse_error("missing #EXTM3U header"));
+  }
+
+  Ok(
+    lines
+      .filter_map(|line| match line.strip_prefix('#') {
+        Some(tag) if tag.starts_with("EXT") => match tag.split_once(':') {
+          Some((name, value)) => Some(Line::Tag(name, Some(value))),
+          None => Some(Line::Tag(tag, None)),
+        },
+        Some(_) => None,
+        None => Some(Line::Uri(line)),
+      })
+      .collect(),
+  )
+}
+
+/// Parses an attribute list (RFC 8216, section 4.2). Quoted string values are
+/// returned without their quotes and may contain commas.
+pub fn parse_attribute_list(input: &str) -> Result<HashMap<String, String>, DownloaderError> {
+  let mut attributes = HashMap::new();
+  let mut rest = input.trim();
+
+  while !rest.is_empty() {
+    let (name, after_name) = rest.split_once('=').ok_or_else(|| parse_error("attribute without value"))?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '-') {
+      return Err(parse_error(&format!("invalid attribute name `{name}`")));
+    }
+
+    let (value, after_value) = match after_name.strip_prefix('"') {
+      Some(quoted) => {
+        let end = quoted.find('"').ok_or_else(|| parse_error("unterminated quoted string"))?;
+        (&quoted[..end], &quoted[end + 1..])
+      }
+      None => match after_name.find(',') {
+        Some(end) => (after_name[..end].trim(), &after_name[end..]),
+        None => (after_name.trim(), ""),
+      },
+    };
+
+    attributes.insert(name.to_string(), value.to_string());
+
+    rest = after_value.trim_start();
+    rest = match rest.strip_prefix(',') {
+      Some(next) => next.trim_start(),
+      None if rest.is_empty() => rest,
+      None => return Err(parse_error("expected `,` between attributes")),
+    };
+  }
+
+  Ok(attributes)
+}
+
+/// Resolves a URI found in a playlist against the URL the playlist was fetched
+/// from (RFC 3986, section 5). Absolute URIs are returned unchanged.
+pub fn resolve_uri(base: &str, uri: &str) -> Result<String, DownloaderError> {
+  let base = reqwest::Url::parse(base).map_err(|_| parse_error(&format!("invalid playlist URL `{base}`")))?;
+  let resolved = base.join(uri).map_err(|_| parse_error(&format!("invalid URI `{uri}`")))?;
+  Ok(resolved.to_string())
+}
+
+fn parse_stream_inf(attributes: &HashMap<String, String>) -> Result<StreamInf, DownloaderError> {
+  let bandwidth = required(attributes, "BANDWIDTH", "EXT-X-STREAM-INF")?;
+  let resolution = match attributes.get("RESOLUTION") {
+    Some(resolution) => Some(parse_resolution(resolution)?),
+    None => None,
+  };
+
+  Ok(StreamInf {
+    bandwidth: bandwidth.parse().map_err(|_| parse_error("invalid BANDWIDTH"))?,
+    average_bandwidth: optional_number(attributes, "AVERAGE-BANDWIDTH")?,
+    codecs: attributes.get("CODECS").cloned(),
+    resolution,
+    frame_rate: optional_number(attributes, "FRAME-RATE")?,
+    audio: attributes.get("AUDIO").cloned(),
+    video: attributes.get("VIDEO").cloned(),
+    subtitles: attributes.get("SUBTITLES").cloned(),
+    uri: String::new(),
+  })
+}
+
+fn parse_media(attributes: &HashMap<String, String>) -> Result<Media, DownloaderError> {
+  let media_type = match required(attributes, "TYPE", "EXT-X-MEDIA")? {
+    "AUDIO" => MediaType::Audio,
+    "VIDEO" => MediaType::Video,
+    "SUBTITLES" => MediaType::Subtitles,
+    "CLOSED-CAPTIONS" => MediaType::ClosedCaptions,
+    other => return Err(parse_error(&format!("unknown EXT-X-MEDIA TYPE `{other}`"))),
+  };
+
+  Ok(Media {
+    media_type,
+    group_id: required(attributes, "GROUP-ID", "EXT-X-MEDIA")?.to_string(),
+    name: required(attributes, "NAME", "EXT-X-MEDIA")?.to_string(),
+    language: attributes.get("LANGUAGE").cloned(),
+    default: attributes.get("DEFAULT").is_some_and(|value| value == "YES"),
+    autoselect: attributes.get("AUTOSELECT").is_some_and(|value| value == "YES"),
+    uri: attributes.get("URI").cloned(),
+  })
+}
+
+fn parse_key(attributes: &HashMap<String, String>) -> Result<Option<Key>, DownloaderError> {
+  let method = match required(attributes, "METHOD", "EXT-X-KEY")? {
+    "NONE" => return Ok(None),
+    "AES-128" => KeyMethod::Aes128,
+    "SAMPLE-AES" => KeyMethod::SampleAes,
+    "SAMPLE-AES-CTR" => KeyMethod::SampleAesCtr,
+    other => return Err(parse_error(&format!("unknown EXT-X-KEY METHOD `{other}`"))),
+  };
+
+  let iv = match attributes.get("IV") {
+    Some(iv) => Some(parse_iv(iv)?),
+    None => None,
+  };
+
+  Ok(Some(Key { method, uri: required(attributes, "URI", "EXT-X-KEY")?.to_string(), iv, key_format: attributes.get("KEYFORMAT").cloned() }))
+}
+
+/// Parses a hexadecimal-sequence IV (`0x` followed by up to 32 hex digits).
+fn parse_iv(value: &str) -> Result<[u8; 16], DownloaderError> {
+  let digits = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).ok_or_else(|| parse_error("invalid IV"))?;
+  let number = u128::from_str_radix(digits, 16).map_err(|_| parse_error("invalid IV"))?;
+  Ok(number.to_be_bytes())
+}
+
+/// Parses `<length>[@<offset>]`, rejecting ranges that end past `u64::MAX`.
+fn parse_byte_range(value: &str) -> Result<(u64, Option<u64>), DownloaderError> {
+  let invalid = || parse_error("invalid BYTERANGE");
+  let (length, offset) = match value.trim().split_once('@') {
+    Some((length, offset)) => (length, Some(offset.parse::<u64>().map_err(|_| invalid())?)),
+    None => (value.trim(), None),
+  };
+  let length = length.parse().ok().filter(|&length| length > 0).ok_or_else(invalid)?;
+  if offset.is_some_and(|offset| offset.checked_add(length).is_none()) {
+    return Err(invalid());
+  }
+  Ok((length, offset))
+}
+
+fn parse_resolution(value: &str) -> Result<Resolution, DownloaderError> {
+  let (width, height) = value.split_once('x').ok_or_else(|| parse_error("invalid RESOLUTION"))?;
+  Ok(Resolution {
+    width: width.parse().map_err(|_| parse_error("invalid RESOLUTION"))?,
+    height: height.parse().map_err(|_| parse_error("invalid RESOLUTION"))?,
+  })
+}
+
+fn parse_integer(value: Option<&str>, tag: &str) -> Result<u64, DownloaderError> {
+  value.and_then(|value| value.trim().parse().ok()).ok_or_else(|| parse_error(&format!("invalid {tag}")))
+}
+
+fn optional_number<T: std::str::FromStr>(attributes: &HashMap<String, String>, name: &str) -> Result<Option<T>, DownloaderError> {
+  match attributes.get(name) {
+    Some(value) => value.parse().map(Some).map_err(|_| parse_error(&format!("invalid {name}"))),
+    None => Ok(None),
+  }
+}
+
+fn required<'a>(attributes: &'a HashMap<String, String>, name: &str, tag: &str) -> Result<&'a str, DownloaderError> {
+  attributes.get(name).map(String::as_str).ok_or_else(|| parse_error(&format!("{tag} is missing {name}")))
+}
+
+fn parse_error(message: &str) -> DownloaderError {
+  DownloaderError::PlaylistParseError(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TWITTER_VARIANT: &str = include_str!("test_data/twitter_variant.m3u8");
+  const TWITTER_MEDIA: &str = include_str!("test_data/twitter_media.m3u8");
+
+  #[test]
+  fn parses_attribute_list_with_quoted_commas() {
+    let attributes = parse_attribute_list(r#"BANDWIDTH=1280000,CODECS="mp4a.40.2,avc1.4d401f",RESOLUTION=1280x720"#).unwrap();
+
+    assert_eq!(attributes["BANDWIDTH"], "1280000");
+    assert_eq!(attributes["CODECS"], "mp4a.40.2,avc1.4d401f");
+    assert_eq!(attributes["RESOLUTION"], "1280x720");
+  }
+
+  #[test]
+  fn rejects_malformed_attribute_lists() {
+    assert!(parse_attribute_list(r#"URI="/unterminated"#).is_err());
+    assert!(parse_attribute_list("BANDWIDTH").is_err());
+    assert!(parse_attribute_list("lower=1").is_err());
+  }
+
+  #[test]
+  fn parses_twitter_variant_playlist() {
+    let manifest = VariantManifest::parse(TWITTER_VARIANT).unwrap();
+
+    assert_eq!(manifest.variants.len(), 3);
+    assert_eq!(manifest.media.len(), 3);
+
+    let top = &manifest.variants[2];
+    assert_eq!(top.bandwidth, 2176000);
+    assert_eq!(top.average_bandwidth, Some(1598000));
+    assert_eq!(top.codecs.as_deref(), Some("mp4a.40.2,avc1.640020"));
+    assert_eq!(top.resolution, Some(Resolution { width: 1280, height: 720 }));
+    assert_eq!(top.frame_rate, Some(29.97));
+    assert_eq!(top.audio.as_deref(), Some("audio-128000"));
+    assert_eq!(top.uri, "/ext_tw_video/1867/pu/pl/avc1/1280x720/JFcx2mmYpQqDy7cD.m3u8");
+
+    let audio = manifest.group(MediaType::Audio, "audio-128000").next().unwrap();
+    assert_eq!(audio.name, "Audio");
+    assert!(audio.autoselect);
+    assert_eq!(audio.uri.as_deref(), Some("/ext_tw_video/1867/pu/pl/mp4a/128000/lhyHT0tEOc0Q6fba.m3u8"));
+  }
+
+  #[test]
+  fn tolerates_attribute_order_and_missing_audio() {
+    let playlist = "#EXTM3U\n\
+      #EXT-X-STREAM-INF:RESOLUTION=640x360,AUDIO=\"aac\",BANDWIDTH=800000\n\
+      low.m3u8\n\
+      #EXT-X-STREAM-INF:BANDWIDTH=1600000,RESOLUTION=1280x720\n\
+      high.m3u8\n";
+    let manifest = VariantManifest::parse(playlist).unwrap();
+
+    assert_eq!(manifest.variants[0].audio.as_deref(), Some("aac"));
+    assert_eq!(manifest.variants[0].resolution, Some(Resolution { width: 640, height: 360 }));
+    assert_eq!(manifest.variants[1].audio, None);
+    assert_eq!(manifest.variants[1].uri, "high.m3u8");
+  }
+
+  #[test]
+  fn parses_twitter_media_playlist() {
+    let manifest = MediaManifest::parse(TWITTER_MEDIA).unwrap();
+
+    assert_eq!(manifest.target_duration, 4);
+    assert_eq!(manifest.media_sequence, 0);
+    assert_eq!(manifest.playlist_type, Some(PlaylistType::Vod));
+    assert!(manifest.end_list);
+    assert_eq!(manifest.segments.len(), 4);
+    assert_eq!(manifest.segments[3].duration, 4.2);
+    assert!((manifest.duration() - 13.2).abs() < 1e-9);
+
+    let first = &manifest.segments[0];
+    assert_eq!(first.uri, "/ext_tw_video/1867/pu/vid/avc1/0/3000/1280x720/Sx4vWzS3Zq9vIfD3.m4s");
+    assert_eq!(first.map.as_ref().unwrap().uri, "/ext_tw_video/1867/pu/vid/avc1/1280x720/init.mp4");
+  }
+
+  #[test]
+  fn tracks_keys_and_sequence_numbers() {
+    let manifest = MediaManifest::parse(
+      "#EXTM3U\n\
+       #EXT-X-TARGETDURATION:2\n\
+       #EXT-X-MEDIA-SEQUENCE:7\n\
+       #EXTINF:2,\nclear.ts\n\
+       #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x0000000000000000000000000000002A\n\
+       #EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://drm\",KEYFORMAT=\"com.apple.streamingkeydelivery\"\n\
+       #EXTINF:2,\nencrypted.ts\n\
+       #EXT-X-KEY:METHOD=NONE\n\
+       #EXTINF:2,\nclear-again.ts\n",
+    )
+    .unwrap();
+
+    let sequences = manifest.segments.iter().map(|segment| segment.sequence).collect::<Vec<_>>();
+    assert_eq!(sequences, [7, 8, 9]);
+    assert_eq!(manifest.segments[0].key, None);
+    assert_eq!(manifest.segments[2].key, None);
+
+    let key = manifest.segments[1].key.as_ref().unwrap();
+    assert_eq!(key.method, KeyMethod::Aes128);
+    assert_eq!(key.uri, "key.bin");
+    assert_eq!(key.iv.unwrap()[15], 0x2A);
+    assert!(key.is_identity());
+  }
+
+  #[test]
+  fn does_not_keep_a_plain_key_across_rotation_to_drm() {
+    let manifest = MediaManifest::parse(
+      "#EXTM3U\n\
+       #EXT-X-TARGETDURATION:2\n\
+       #EXT-X-KEY:METHOD=AES-128,URI=\"key1.bin\"\n\
+       #EXTINF:2,\nfirst.ts\n\
+       #EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://drm\",KEYFORMAT=\"com.apple.streamingkeydelivery\"\n\
+       #EXTINF:2,\nsecond.ts\n",
+    )
+    .unwrap();
+
+    assert!(manifest.segments[0].key.as_ref().unwrap().is_identity());
+    assert!(!manifest.segments[1].key.as_ref().unwrap().is_identity());
+  }
+
+  #[test]
+  fn resolves_implicit_byte_range_offsets() {
+    let manifest = MediaManifest::parse(
+      "#EXTM3U\n\
+       #EXT-X-TARGETDURATION:2\n\
+       #EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"720@0\"\n\
+       #EXTINF:2,\n#EXT-X-BYTERANGE:1000@720\nmain.mp4\n\
+       #EXTINF:2,\n#EXT-X-BYTERANGE:500\nmain.mp4\n\
+       #EXTINF:2,\n#EXT-X-BYTERANGE:300\nmain.mp4\n",
+    )
+    .unwrap();
+
+    let map = manifest.segments[0].map.as_ref().unwrap();
+    assert_eq!(map.byte_range, Some(ByteRange { length: 720, offset: 0 }));
+    let ranges = manifest.segments.iter().map(|segment| segment.byte_range.unwrap()).collect::<Vec<_>>();
+    assert_eq!(
+      ranges,
+      [ByteRange { length: 1000, offset: 720 }, ByteRange { length: 500, offset: 1720 }, ByteRange { length: 300, offset: 2220 }]
+    );
+    assert_eq!(ranges[2].last_byte(), 2519);
+  }
+
+  #[test]
+  fn rejects_implicit_offset_without_previous_sub_range() {
+    assert!(MediaManifest::parse("#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXTINF:2,\n#EXT-X-BYTERANGE:500\nmain.mp4\n").is_err());
+    assert!(MediaManifest::parse(
+      "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXTINF:2,\n#EXT-X-BYTERANGE:500@0\na.mp4\n#EXTINF:2,\n#EXT-X-BYTERANGE:500\nb.mp4\n"
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn rejects_byte_ranges_past_the_largest_offset() {
+    let playlist = |byte_ranges: &str| format!("#EXTM3U\n#EXT-X-TARGETDURATION:2\n{byte_ranges}");
+
+    assert!(MediaManifest::parse(&playlist("#EXTINF:2,\n#EXT-X-BYTERANGE:2@18446744073709551615\nmain.mp4\n")).is_err());
+    assert!(MediaManifest::parse(&playlist("#EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"2@18446744073709551615\"\n#EXTINF:2,\na.mp4\n")).is_err());
+    assert!(MediaManifest::parse(&playlist(
+      "#EXTINF:2,\n#EXT-X-BYTERANGE:5@18446744073709551610\nmain.mp4\n#EXTINF:2,\n#EXT-X-BYTERANGE:1\nmain.mp4\n"
+    ))
+    .is_err());
+  }
+
+  #[test]
+  fn rejects_media_sequence_overflow() {
+    let playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:18446744073709551615\n#EXTINF:2,\na.ts\n";
+    assert_eq!(MediaManifest::parse(playlist).unwrap().segments[0].sequence, u64::MAX);
+    assert!(MediaManifest::parse(&format!("{playlist}#EXTINF:2,\nb.ts\n")).is_err());
+  }
+
+  #[test]
+  fn rejects_malformed_keys() {
+    let playlist = |key: &str| format!("#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-KEY:{key}\n#EXTINF:2,\na.ts\n");
+
+    assert!(MediaManifest::parse(&playlist("METHOD=AES-256,URI=\"k\"")).is_err());
+    assert!(MediaManifest::parse(&playlist("METHOD=AES-128")).is_err());
+    assert!(MediaManifest::parse(&playlist("METHOD=AES-128,URI=\"k\",IV=42")).is_err());
+  }
+
+  #[test]
+  fn resolves_uris_against_playlist_url() {
+    const BASE: &str = "https://video.twimg.com/ext_tw_video/1867/pu/pl/avc1/1280x720/JFcx2mmYpQqDy7cD.m3u8";
+
+    assert_eq!(resolve_uri(BASE, "https://cdn.example.com/a.m4s").unwrap(), "https://cdn.example.com/a.m4s");
+    assert_eq!(resolve_uri(BASE, "/ext_tw_video/init.mp4").unwrap(), "https://video.twimg.com/ext_tw_video/init.mp4");
+    assert_eq!(resolve_uri(BASE, "seg1.m4s").unwrap(), "https://video.twimg.com/ext_tw_video/1867/pu/pl/avc1/1280x720/seg1.m4s");
+    assert_eq!(resolve_uri(BASE, "../480x270/low.m3u8").unwrap(), "https://video.twimg.com/ext_tw_video/1867/pu/pl/avc1/480x270/low.m3u8");
+    assert_eq!(resolve_uri(BASE, "//other.example.com/x.ts").unwrap(), "https://other.example.com/x.ts");
+    assert_eq!(resolve_uri(BASE, "seg.ts?token=1").unwrap(), "https://video.twimg.com/ext_tw_video/1867/pu/pl/avc1/1280x720/seg.ts?token=1");
+  }
+
+  #[test]
+  fn resolves_rfc3986_reference_examples() {
+    const BASE: &str = "http://a/b/c/d;p?q";
+
+    for (reference, expected) in [
+      ("g", "http://a/b/c/g"),
+      ("./g", "http://a/b/c/g"),
+      ("g/", "http://a/b/c/g/"),
+      ("/g", "http://a/g"),
+      ("?y", "http://a/b/c/d;p?y"),
+      ("g?y", "http://a/b/c/g?y"),
+      ("..", "http://a/b/"),
+      ("../g", "http://a/b/g"),
+      ("../../g", "http://a/g"),
+      ("../../../g", "http://a/g"),
+    ] {
+      assert_eq!(resolve_uri(BASE, reference).unwrap(), expected, "resolving `{reference}`");
+    }
+  }
+
+  #[test]
+  fn rejects_playlists_without_header() {
+    assert!(VariantManifest::parse("#EXT-X-VERSION:3\n").is_err());
+    assert!(MediaManifest::parse("#EXTINF:1,\nsegment.ts\n").is_err());
+  }
+
+  #[test]
+  fn rejects_segment_without_extinf() {
+    assert!(MediaManifest::parse("#EXTM3U\n#EXT-X-TARGETDURATION:2\nsegment.ts\n").is_err());
+  }
+}
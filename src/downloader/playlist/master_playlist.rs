@@ -1,71 +1,318 @@
-use crate::downloader::{downloader_error::DownloaderError, playlist::media_playlist::MediaPlaylist};
-use tokio::process::Command;
+use crate::downloader::{
+  dash::{self, mpd::Segments},
+  downloader_error::DownloaderError,
+  ffmpeg,
+  job_dir::JobDir,
+  playlist::{
+    hls::{Media, MediaManifest, Resolution},
+    media_playlist::{self, MediaPlaylist},
+    remuxer,
+  },
+  progress::{ProgressReporter, Stage},
+};
+use reqwest::header::HeaderMap;
+use std::{ffi::OsStr, path::PathBuf};
+use tracing::warn;
+
+/// Where the segments of a track are listed.
+#[derive(Clone)]
+pub enum MediaSource {
+  /// HLS media playlist URL.
+  Playlist(String),
+  /// DASH representation; `duration` is the period's.
+  Dash { segments: Segments, fragmented_mp4: bool, duration: Option<f64> },
+}
+
+impl MediaSource {
+  /// Opens the track for download. An HLS playlist is only fetched when
+  /// `manifest` does not already hold it.
+  async fn open(&self, headers: &HeaderMap, manifest: Option<&MediaManifest>) -> Result<MediaPlaylist, DownloaderError> {
+    match (self, manifest) {
+      (MediaSource::Playlist(url), Some(manifest)) => MediaPlaylist::from_manifest(url, manifest, headers),
+      (MediaSource::Playlist(url), None) => MediaPlaylist::from_url(url, headers).await,
+      (MediaSource::Dash { segments, fragmented_mp4, .. }, _) => dash::media_playlist(segments, *fragmented_mp4, headers).await,
+    }
+  }
+}
+
+/// What the manifest says about one quality.
+#[derive(Debug, Clone, Default)]
+pub struct VariantInfo {
+  pub resolution: Resolution,
+  /// Peak bits per second of all tracks together.
+  pub bandwidth: u64,
+  pub average_bandwidth: Option<u64>,
+  pub codecs: Option<String>,
+  pub frame_rate: Option<f64>,
+}
+
+/// An audio rendition from the variant's audio group, described as an
+/// `#EXT-X-MEDIA` tag (DASH adaptation sets are mapped onto one).
+#[derive(Clone)]
+pub struct AudioRendition {
+  pub media: Media,
+  source: MediaSource,
+}
+
+impl AudioRendition {
+  pub fn new(media: Media, source: MediaSource) -> Self {
+    AudioRendition { media, source }
+  }
+
+  /// Whether both renditions carry the same track, possibly from different
+  /// groups (which usually differ only in bitrate).
+  pub fn same_track(&self, other: &AudioRendition) -> bool {
+    self.media.name == other.media.name && self.media.language == other.media.language
+  }
+}
 
 pub struct MasterPlaylist {
-  pub resolution: String,
-  video_media_playlist: Option<MediaPlaylist>,
-  audio_media_playlist: Option<MediaPlaylist>,
-  video_media_url: String,
-  audio_media_url: Option<String>
+  pub info: VariantInfo,
+  /// Seconds; unknown for live streams.
+  pub duration: Option<f64>,
+  video_source: MediaSource,
+  /// Media playlist of the video track, kept from `from_sources` for
+  /// `download` unless it is live and still changing.
+  video_manifest: Option<MediaManifest>,
+  pub audio_renditions: Vec<AudioRendition>,
+  headers: HeaderMap,
 }
 
 impl MasterPlaylist {
-  pub async fn from_urls(video_url: String, audio_url: Option<String>) -> Result<Self, DownloaderError> {
+  /// Looks up the duration of the video track, fetching its playlist for HLS;
+  /// the media itself is fetched by `download`.
+  pub async fn from_sources(
+    info: VariantInfo,
+    video_source: MediaSource,
+    audio_renditions: Vec<AudioRendition>,
+    headers: HeaderMap,
+  ) -> Result<Self, DownloaderError> {
+    let (duration, video_manifest) = match &video_source {
+      MediaSource::Playlist(url) => match media_playlist::fetch_manifest(url, &headers).await {
+        Ok(manifest) if manifest.end_list => (Some(manifest.duration()), Some(manifest)),
+        // Live playlists gain segments until the download starts, so they are fetched again then.
+        Ok(_) => (None, None),
+        Err(e) => {
+          warn!("Failed to get duration of {}: {e}", info.resolution);
+          (None, None)
+        }
+      },
+      MediaSource::Dash { duration, .. } => (*duration, None),
+    };
+
     Ok(MasterPlaylist {
-      resolution: String::new(),
-      video_media_playlist: None,
-      audio_media_playlist: None,
-      video_media_url: video_url,
-      audio_media_url: audio_url
+      info,
+      duration,
+      video_source,
+      video_manifest,
+      audio_renditions,
+      headers,
     })
   }
 
-  pub async fn download(&mut self) -> Result<String, DownloaderError> {
-    self.video_media_playlist = Some(MediaPlaylist::from_url(&self.video_media_url).await?);
-    let video_media_playlist = self.video_media_playlist.as_ref().unwrap();
-    let video_bytes = video_media_playlist.get_byte_data();
-
-    let video_name = video_media_playlist.name.split('/').last().unwrap().split('.').next().unwrap().to_string();
-    // let output_name = format!("{}_{}.mp4", video_name, self.resolution);
-    let output_name = String::from("output.mp4");
-
-    tokio::fs::write(video_name.clone(), video_bytes).await.map_err(|_| DownloaderError::IOError)?;
-
-    match &self.audio_media_url {
-      Some(audio_media_url) => {
-        self.audio_media_playlist = Some(MediaPlaylist::from_url(&audio_media_url).await?);
-        let audio_media_playlist = self.audio_media_playlist.as_ref().unwrap();
-        let audio_bytes = audio_media_playlist.get_byte_data();
-
-        let audio_name = audio_media_playlist.name.split('/').last().unwrap().split('.').next().unwrap().to_string();
-        tokio::fs::write(audio_name.clone(), audio_bytes).await.map_err(|_| DownloaderError::IOError)?;
-
-        Command::new("ffmpeg")
-          .args(&["-i", &video_name])
-          .args(&["-i", &audio_name])
-          .args(["-c", "copy"])
-          .arg("-y")
-          .arg(&output_name)
-          .output()
-          .await
-          .map_err(|_| DownloaderError::FfmpegError)?;
-
-        tokio::fs::remove_file(audio_name).await.map_err(|_| DownloaderError::IOError)?;
+  /// Size of the download implied by its bitrate, in bytes.
+  pub fn estimated_size(&self) -> Option<u64> {
+    let bandwidth = self.info.average_bandwidth.unwrap_or(self.info.bandwidth);
+    Some((bandwidth as f64 * self.duration? / 8.0) as u64).filter(|&size| size > 0)
+  }
+
+  /// Button text such as `720p · ~38 MB · H.264`.
+  pub fn label(&self) -> String {
+    let Resolution { width, height } = self.info.resolution;
+    let mut label = format!("{}p", width.min(height));
+    if let Some(frame_rate) = self.info.frame_rate.filter(|&frame_rate| frame_rate > 31.0) {
+      label.push_str(&format!("{}", frame_rate.round()));
+    }
+
+    if let Some(size) = self.estimated_size() {
+      label.push_str(&format!(" · ~{}", format_size(size)));
+    }
+    if let Some(codec) = self.info.codecs.as_deref().and_then(video_codec_name) {
+      label.push_str(&format!(" · {codec}"));
+    }
+    label
+  }
+
+  /// Key of the variant in the file cache. Variants of one resolution can
+  /// differ in bitrate, codec or frame rate, so all of them are part of it.
+  pub fn cache_quality(&self) -> String {
+    let mut quality = format!("{} bandwidth={}", self.info.resolution, self.info.bandwidth);
+    if let Some(codecs) = &self.info.codecs {
+      quality.push_str(&format!(" codecs={codecs}"));
+    }
+    if let Some(frame_rate) = self.info.frame_rate {
+      quality.push_str(&format!(" fps={frame_rate}"));
+    }
+    quality
+  }
+
+  /// The rendition carrying the same track as `preferred`, otherwise the
+  /// group's DEFAULT rendition, otherwise its first one.
+  pub fn audio_rendition(&self, preferred: Option<&AudioRendition>) -> Option<&AudioRendition> {
+    preferred
+      .and_then(|preferred| self.audio_renditions.iter().find(|rendition| rendition.same_track(preferred)))
+      .or_else(|| self.audio_renditions.iter().find(|rendition| rendition.media.default))
+      .or(self.audio_renditions.first())
+  }
+
+  /// Downloads and merges the video and audio tracks into `job_dir`, returning
+  /// the path of the resulting file. The audio track is picked with
+  /// `audio_rendition`. fMP4 streams are merged by the built-in remuxer;
+  /// anything else (e.g. MPEG-TS segments) needs ffmpeg.
+  pub async fn download(&self, job_dir: &JobDir, audio: Option<&AudioRendition>, progress: &ProgressReporter) -> Result<PathBuf, DownloaderError> {
+    let output_name = job_dir.file(&format!("video_{}.mp4", self.info.resolution));
+
+    // Both tracks are fetched at once. Live renditions are each recorded by
+    // their own reload loop, so their first and last segments may differ.
+    let video_name = job_dir.file("video");
+    let video = async {
+      let video_media_playlist = self.video_source.open(&self.headers, self.video_manifest.as_ref()).await?;
+      video_media_playlist.download_to(&video_name, progress).await?;
+      Ok::<_, DownloaderError>(video_media_playlist)
+    };
+    let audio_name = job_dir.file("audio");
+    let audio_source = self.audio_rendition(audio).map(|rendition| &rendition.source);
+    let audio = async {
+      match audio_source {
+        Some(audio_source) => {
+          let audio_media_playlist = audio_source.open(&self.headers, None).await?;
+          audio_media_playlist.download_to(&audio_name, progress).await?;
+          Ok(Some(audio_media_playlist))
+        }
+        None => Ok::<_, DownloaderError>(None),
       }
-      None => {
-        Command::new("ffmpeg")
-          .args(&["-i", &video_name])
-          .args(["-c", "copy"])
-          .arg("-y")
-          .arg(&output_name)
-          .output()
-          .await
-          .map_err(|_| DownloaderError::FfmpegError)?;
+    };
+    let (video_media_playlist, audio_media_playlist) = tokio::try_join!(video, audio)?;
+
+    let mut fragmented_mp4 = video_media_playlist.is_fragmented_mp4();
+    let mut inputs = vec![video_name];
+    if let Some(audio_media_playlist) = audio_media_playlist {
+      fragmented_mp4 &= audio_media_playlist.is_fragmented_mp4();
+      inputs.push(audio_name);
+    }
+
+    progress.start(Stage::Merging);
+    if fragmented_mp4 {
+      let (remux_inputs, remux_output) = (inputs.clone(), output_name.clone());
+      tokio::task::spawn_blocking(move || remuxer::remux(&remux_inputs, &remux_output))
+        .await
+        .map_err(|e| DownloaderError::RemuxError(e.to_string()))??;
+    } else {
+      let mut args = vec![];
+      for input in &inputs {
+        args.extend([OsStr::new("-i"), input.as_os_str()]);
+      }
+      args.extend([OsStr::new("-c"), OsStr::new("copy"), output_name.as_os_str()]);
+      match self.duration {
+        Some(duration) => ffmpeg::run_with_progress(args, duration, |fraction| progress.set_fraction(fraction)).await?,
+        None => ffmpeg::run(args).await?,
       }
     }
 
-    tokio::fs::remove_file(video_name).await.map_err(|_| DownloaderError::IOError)?;
+    for input in inputs {
+      tokio::fs::remove_file(input).await.map_err(|_| DownloaderError::IOError)?;
+    }
 
     Ok(output_name)
   }
 }
+
+/// `38 MB`, `1.4 GB`.
+pub fn format_size(bytes: u64) -> String {
+  const MB: f64 = 1024.0 * 1024.0;
+  let megabytes = bytes as f64 / MB;
+  if megabytes < 1000.0 {
+    format!("{} MB", megabytes.round().max(1.0))
+  } else {
+    format!("{:.1} GB", megabytes / 1024.0)
+  }
+}
+
+/// Common name of the first video codec in an RFC 6381 `CODECS` list.
+fn video_codec_name(codecs: &str) -> Option<&'static str> {
+  codecs.split(',').find_map(|codec| match codec.trim().split('.').next()? {
+    "avc1" | "avc3" => Some("H.264"),
+    "hvc1" | "hev1" => Some("H.265"),
+    "vp09" | "vp9" => Some("VP9"),
+    "vp08" | "vp8" => Some("VP8"),
+    "av01" => Some("AV1"),
+    _ => None,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::downloader::test_server::{Reply, TestServer};
+
+  fn master_playlist(info: VariantInfo, duration: Option<f64>) -> MasterPlaylist {
+    MasterPlaylist {
+      info,
+      duration,
+      video_source: MediaSource::Playlist(String::new()),
+      video_manifest: None,
+      audio_renditions: vec![],
+      headers: HeaderMap::new(),
+    }
+  }
+
+  #[test]
+  fn labels_resolution_size_and_codec() {
+    let info = VariantInfo {
+      resolution: Resolution { width: 1280, height: 720 },
+      bandwidth: 2_176_000,
+      average_bandwidth: Some(1_598_000),
+      codecs: Some("mp4a.40.2,avc1.640020".to_string()),
+      frame_rate: Some(29.97),
+    };
+
+    let master_playlist = master_playlist(info.clone(), Some(200.0));
+
+    assert_eq!(master_playlist.estimated_size(), Some(39_950_000));
+    assert_eq!(master_playlist.label(), "720p · ~38 MB · H.264");
+
+    let vertical = VariantInfo { resolution: Resolution { width: 1080, height: 1920 }, frame_rate: Some(60.0), codecs: Some("hvc1.1.6.L120".to_string()), ..info };
+    assert_eq!(self::master_playlist(vertical, None).label(), "1080p60 · H.265");
+  }
+
+  #[test]
+  fn keeps_variants_of_one_resolution_apart_in_the_cache() {
+    let h264 = VariantInfo {
+      resolution: Resolution { width: 1920, height: 1080 },
+      bandwidth: 5_000_000,
+      codecs: Some("avc1.640028".to_string()),
+      ..Default::default()
+    };
+    let h265 = VariantInfo { codecs: Some("hvc1.1.6.L120".to_string()), ..h264.clone() };
+    let low_bitrate = VariantInfo { bandwidth: 3_000_000, ..h264.clone() };
+
+    let key = |info: &VariantInfo| master_playlist(info.clone(), None).cache_quality();
+    assert_ne!(key(&h264), key(&h265));
+    assert_ne!(key(&h264), key(&low_bitrate));
+    assert_eq!(key(&h264), key(&h264));
+  }
+
+  #[tokio::test]
+  async fn reuses_the_playlist_fetched_for_the_duration() {
+    let server = TestServer::start().await;
+    let playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXTINF:2.0,\na.ts\n#EXTINF:1.5,\nb.ts\n";
+    server.route("/vod.m3u8", vec![Reply::Ok(format!("{playlist}#EXT-X-ENDLIST\n").into())]);
+    server.route("/live.m3u8", vec![Reply::Ok(playlist.into())]);
+
+    for (path, duration, fetches) in [("/vod.m3u8", Some(3.5), 1), ("/live.m3u8", None, 2)] {
+      let source = MediaSource::Playlist(server.url(path));
+      let master_playlist = MasterPlaylist::from_sources(VariantInfo::default(), source, vec![], HeaderMap::new()).await.unwrap();
+      assert_eq!(master_playlist.duration, duration);
+
+      master_playlist.video_source.open(&master_playlist.headers, master_playlist.video_manifest.as_ref()).await.unwrap();
+      assert_eq!(server.hits(path), fetches);
+    }
+  }
+
+  #[test]
+  fn formats_sizes() {
+    assert_eq!(format_size(200_000), "1 MB");
+    assert_eq!(format_size(140 * 1024 * 1024), "140 MB");
+    assert_eq!(format_size(1536 * 1024 * 1024), "1.5 GB");
+  }
+}
@@ -1,65 +1,753 @@
-use regex::Regex;
-use std::sync::{Arc, Mutex};
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use futures::{StreamExt, TryStreamExt};
+use reqwest::{
+  header::{HeaderMap, RANGE},
+  StatusCode,
+};
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
 
-use crate::downloader::downloader_error::DownloaderError;
+use crate::config;
+use crate::downloader::{
+  downloader_error::DownloaderError,
+  http,
+  progress::ProgressReporter,
+  playlist::hls::{resolve_uri, ByteRange, Key, KeyMethod, Map, MediaManifest},
+};
+
+type Aes128CbcDecryptor = cbc::Decryptor<aes::Aes128>;
+
+/// Retry schedule for a single segment; the delay doubles after every failure.
+/// An attempt also fails when the server sends nothing for `read_timeout`.
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+  pub attempts: u32,
+  pub initial_backoff: Duration,
+  pub read_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy { attempts: 4, initial_backoff: Duration::from_millis(500), read_timeout: Duration::from_secs(30) }
+  }
+}
+
+/// Reloads in a row that may bring no new segments before a live playlist is
+/// considered abandoned.
+const MAX_STALE_RELOADS: u32 = 6;
+
+#[derive(Clone)]
+struct SegmentSource {
+  url: String,
+  byte_range: Option<ByteRange>,
+  encryption: Option<Encryption>,
+  /// Media sequence number and duration; `None` for init sections.
+  media: Option<(u64, f64)>,
+  /// Set for `#EXT-X-MAP` sections, which are not counted as segments in errors.
+  init_section: bool,
+}
+
+/// AES-128-CBC parameters of an encrypted segment.
+#[derive(Clone)]
+struct Encryption {
+  key_url: String,
+  iv: [u8; 16],
+}
 
 pub struct MediaPlaylist {
-  pub name: String,
-  byte_data: Vec<u8>,
+  url: String,
+  segments: Vec<SegmentSource>,
+  headers: HeaderMap,
+  fragmented_mp4: bool,
+  /// Set when the playlist has no `#EXT-X-ENDLIST` and has to be reloaded.
+  live: bool,
+  target_duration: Duration,
+  last_map: Option<Map>,
+  retry_policy: RetryPolicy,
+  max_live_duration: Duration,
 }
 
 impl MediaPlaylist {
-  pub async fn from_url(url: &str) -> Result<Self, DownloaderError> {
-    let mut name = String::new();
-    const BASE_URL: &str = "https://video.twimg.com";
-
-    let response =
-      reqwest::get(url).await.map_err(|_| DownloaderError::FetchError)?.text().await.map_err(|_| DownloaderError::FetchError)?;
-    let lines = response.lines().filter(|&line| !line.is_empty()).collect::<Vec<&str>>();
-
-    const BASE_SEGMENT_REGEX_STR: &str = r#"#EXT-X-MAP:URI="(?P<base_segment_url>.*)""#;
-    const OTHER_SEGMENTS_REGEX_STR: &str = r#"^(?P<segment_url>\/.*)$"#;
-    let final_regex_string = format!("{BASE_SEGMENT_REGEX_STR}|{OTHER_SEGMENTS_REGEX_STR}");
-
-    let regex = Regex::new(&final_regex_string).unwrap();
-    let mut ordered_urls = Vec::<String>::new();
-    for line in lines {
-      match regex.captures(line) {
-        Some(base_captures) if base_captures.name("base_segment_url").is_some() => {
-          let url = base_captures.name("base_segment_url").unwrap().as_str().to_string();
-          name = url.to_string();
-          ordered_urls.push(format!("{BASE_URL}{url}"));
+  /// Fetches and parses the media playlist. `headers` are sent with the playlist
+  /// request and reused for its segments and decryption keys.
+  pub async fn from_url(url: &str, headers: &HeaderMap) -> Result<Self, DownloaderError> {
+    let manifest = fetch_manifest(url, headers).await?;
+    Self::from_manifest(url, &manifest, headers)
+  }
+
+  /// Wraps a media playlist that was already fetched from `url`.
+  pub fn from_manifest(url: &str, manifest: &MediaManifest, headers: &HeaderMap) -> Result<Self, DownloaderError> {
+    let mut segments = vec![];
+    let mut last_map = None;
+    append_segments(url, manifest, None, &mut last_map, &mut segments)?;
+
+    let fragmented_mp4 = manifest.segments.iter().all(|segment| segment.map.is_some());
+
+    Ok(MediaPlaylist {
+      url: url.to_string(),
+      segments,
+      headers: headers.clone(),
+      fragmented_mp4,
+      live: !manifest.end_list,
+      target_duration: Duration::from_secs(manifest.target_duration.max(1)),
+      last_map,
+      retry_policy: RetryPolicy::default(),
+      max_live_duration: config::get().live_max_duration,
+    })
+  }
+
+  /// Wraps a segment list that is already known, such as a DASH
+  /// representation's, so it is downloaded like an HLS playlist.
+  pub fn from_segments(segments: Vec<(String, Option<ByteRange>)>, fragmented_mp4: bool, headers: &HeaderMap) -> Self {
+    let segments = segments.into_iter().map(|(url, byte_range)| SegmentSource { url, byte_range, encryption: None, media: None, init_section: false }).collect();
+    MediaPlaylist {
+      url: String::new(),
+      segments,
+      headers: headers.clone(),
+      fragmented_mp4,
+      live: false,
+      target_duration: Duration::ZERO,
+      last_map: None,
+      retry_policy: RetryPolicy::default(),
+      max_live_duration: config::get().live_max_duration,
+    }
+  }
+
+  /// Whether every segment is an fMP4 fragment preceded by an init section.
+  pub fn is_fragmented_mp4(&self) -> bool {
+    self.fragmented_mp4
+  }
+
+  /// Downloads every segment and appends it to `path` in playlist order. Segments
+  /// are fetched concurrently but written as soon as all earlier ones are on disk,
+  /// so memory use does not grow with the length of the video. A segment that still
+  /// fails after all retries aborts the download with `SegmentFetchError`.
+  /// Encrypted segments are decrypted before they are written.
+  ///
+  /// At most `SEGMENT_CONCURRENCY` segments are fetched ahead of the one being
+  /// written, which also bounds the reorder buffer held in memory.
+  ///
+  /// Live playlists are reloaded every target duration (half of it when nothing
+  /// changed) and recorded until `#EXT-X-ENDLIST` appears, the next segment
+  /// would take the recording past `LIVE_MAX_DURATION` seconds or the playlist
+  /// stops updating.
+  ///
+  /// Every segment written is reported to `progress`; the total is only known,
+  /// and reported, for playlists that are not live.
+  pub async fn download_to(&self, path: &Path, progress: &ProgressReporter) -> Result<(), DownloaderError> {
+    let mut file = tokio::fs::File::create(path).await.map_err(|_| DownloaderError::IOError)?;
+    if !self.live {
+      progress.expect(self.segments.len() as u64);
+    }
+    let mut keys = HashMap::new();
+    let mut written = 0;
+
+    let mut batch = self.segments.clone();
+    let mut last_map = self.last_map.clone();
+    let mut live = self.live;
+    let mut recorded = 0.0;
+    let mut last_sequence = None;
+    let mut stale_reloads = 0;
+    loop {
+      let mut capped = false;
+      if self.live {
+        let max_duration = self.max_live_duration.as_secs_f64();
+        // A segment is only kept if the recording still fits the cap with it.
+        let within_cap = batch
+          .iter()
+          .take_while(|segment| match segment.media {
+            Some((_, duration)) if recorded + duration > max_duration => false,
+            Some((_, duration)) => {
+              recorded += duration;
+              true
+            }
+            None => true,
+          })
+          .count();
+        capped = within_cap < batch.len() || recorded >= max_duration;
+        batch.truncate(within_cap);
+        // An init section whose segments fell past the cap would be left dangling.
+        while capped && batch.last().is_some_and(|segment| segment.media.is_none()) {
+          batch.pop();
         }
-        Some(other_captures) if other_captures.name("segment_url").is_some() => {
-          let url = other_captures.name("segment_url").unwrap().as_str().to_string();
-          ordered_urls.push(format!("{BASE_URL}{url}"));
+      }
+
+      self.write_segments(&mut file, &batch, &mut keys, written, progress).await?;
+      written += batch.iter().filter(|segment| !segment.init_section).count();
+      if !live || capped {
+        break;
+      }
+
+      let batch_sequence = batch.iter().rev().find_map(|segment| segment.media).map(|(sequence, _)| sequence);
+      let reload_delay = if batch_sequence.is_some() { self.target_duration } else { self.target_duration / 2 };
+      last_sequence = batch_sequence.or(last_sequence);
+      tokio::time::sleep(reload_delay).await;
+
+      batch = vec![];
+      match fetch_manifest(&self.url, &self.headers).await {
+        Ok(manifest) => {
+          live = !manifest.end_list;
+          if let (Some(previous), Some(first)) = (last_sequence, manifest.segments.first()) {
+            if let Some(next) = previous.checked_add(1).filter(|&next| first.sequence > next) {
+              warn!("Live playlist {} skipped segments {next}..{}", self.url, first.sequence);
+            }
+          }
+          append_segments(&self.url, &manifest, last_sequence, &mut last_map, &mut batch)?;
+        }
+        Err(e) => warn!("Failed to reload live playlist {}: {e}", self.url),
+      }
+
+      if batch.iter().any(|segment| segment.media.is_some()) {
+        stale_reloads = 0;
+      } else if live {
+        stale_reloads += 1;
+        if stale_reloads >= MAX_STALE_RELOADS {
+          warn!("Live playlist {} stopped updating, finishing the recording", self.url);
+          break;
         }
-        _ => {}
       }
     }
 
-    let ordered_bytes = Arc::new(Mutex::new(vec![vec![]; ordered_urls.len()]));
-    let mut tasks = vec![];
-    for (i, url) in ordered_urls.iter().enumerate() {
-      let result_clone = ordered_bytes.clone();
-      let url_clone = url.clone();
-      tasks.push(tokio::spawn(async move {
-        let client = reqwest::Client::new();
-        if let Ok(response) = client.get(url_clone).send().await {
-          if let Ok(bytes) = response.bytes().await {
-            result_clone.lock().unwrap()[i] = bytes.to_vec();
+    file.flush().await.map_err(|_| DownloaderError::IOError)
+  }
+
+  /// Fetches and writes `batch`. Errors number its segments after the
+  /// `segments_before` already written, skipping init sections, which are
+  /// reported on their own. Keys not yet in `keys` are fetched first.
+  async fn write_segments(
+    &self,
+    file: &mut tokio::fs::File,
+    batch: &[SegmentSource],
+    keys: &mut HashMap<String, [u8; 16]>,
+    segments_before: usize,
+    progress: &ProgressReporter,
+  ) -> Result<(), DownloaderError> {
+    self.fetch_keys(batch, keys).await?;
+    let keys = Arc::new(keys.clone());
+    let retry_policy = self.retry_policy;
+    let headers = self.headers.clone();
+
+    let mut number = segments_before;
+    let numbered = batch
+      .iter()
+      .map(|segment| {
+        if segment.init_section {
+          return (segment.clone(), None);
+        }
+        number += 1;
+        (segment.clone(), Some(number))
+      })
+      .collect::<Vec<_>>();
+
+    let mut segments = futures::stream::iter(numbered)
+      .map(|(segment, number)| {
+        let (keys, headers) = (keys.clone(), headers.clone());
+        async move {
+          let bytes = fetch_with_retries(&segment.url, segment.byte_range, &headers, retry_policy).await.map_err(|status| {
+            let status = status.map(|status| status.as_u16());
+            match number {
+              Some(number) => DownloaderError::SegmentFetchError(number, status),
+              None => DownloaderError::InitSectionFetchError(status),
+            }
+          })?;
+          match &segment.encryption {
+            Some(encryption) => decrypt(bytes, &keys[&encryption.key_url], &encryption.iv, number),
+            None => Ok(bytes),
           }
-        };
-      }));
+        }
+      })
+      .buffered(config::get().segment_concurrency);
+    while let Some(bytes) = segments.try_next().await? {
+      file.write_all(&bytes).await.map_err(|_| DownloaderError::IOError)?;
+      progress.advance(1, bytes.len() as u64);
+    }
+
+    Ok(())
+  }
+
+  /// Fetches every distinct key referenced by `batch` that is not in `keys` yet.
+  async fn fetch_keys(&self, batch: &[SegmentSource], keys: &mut HashMap<String, [u8; 16]>) -> Result<(), DownloaderError> {
+    for encryption in batch.iter().filter_map(|segment| segment.encryption.as_ref()) {
+      if keys.contains_key(&encryption.key_url) {
+        continue;
+      }
+
+      let key = fetch_with_retries(&encryption.key_url, None, &self.headers, self.retry_policy).await.map_err(|status| {
+        let status = status.map_or("no response".to_string(), |status| format!("HTTP {}", status.as_u16()));
+        DownloaderError::DecryptionError(format!("failed to fetch key ({status})"))
+      })?;
+      let key = <[u8; 16]>::try_from(key.as_slice()).map_err(|_| DownloaderError::DecryptionError("key is not 16 bytes long".to_string()))?;
+      keys.insert(encryption.key_url.clone(), key);
+    }
+    Ok(())
+  }
+}
+
+pub async fn fetch_manifest(url: &str, headers: &HeaderMap) -> Result<MediaManifest, DownloaderError> {
+  let response = http::client()
+    .get(url)
+    .headers(headers.clone())
+    .send()
+    .await
+    .map_err(|_| DownloaderError::FetchError)?
+    .text()
+    .await
+    .map_err(|_| DownloaderError::FetchError)?;
+  MediaManifest::parse(&response)
+}
+
+/// Appends the segments of `manifest` with a sequence number after `after` to
+/// `segments`, each preceded by its init section when that differs from
+/// `current_map`.
+fn append_segments(
+  playlist_url: &str,
+  manifest: &MediaManifest,
+  after: Option<u64>,
+  current_map: &mut Option<Map>,
+  segments: &mut Vec<SegmentSource>,
+) -> Result<(), DownloaderError> {
+  for segment in manifest.segments.iter().filter(|segment| after.is_none_or(|after| segment.sequence > after)) {
+    if segment.map != *current_map {
+      if let Some(map) = &segment.map {
+        segments.push(SegmentSource {
+          url: resolve_uri(playlist_url, &map.uri)?,
+          byte_range: map.byte_range,
+          encryption: encryption(playlist_url, map.key.as_ref(), None)?,
+          media: None,
+          init_section: true,
+        });
+      }
+      current_map.clone_from(&segment.map);
+    }
+    segments.push(SegmentSource {
+      url: resolve_uri(playlist_url, &segment.uri)?,
+      byte_range: segment.byte_range,
+      encryption: encryption(playlist_url, segment.key.as_ref(), Some(segment.sequence))?,
+      media: Some((segment.sequence, segment.duration)),
+      init_section: false,
+    });
+  }
+  Ok(())
+}
+
+/// Resolves the AES-128 parameters for a segment. The IV defaults to the media
+/// sequence number; init sections have none, so theirs must be explicit.
+fn encryption(playlist_url: &str, key: Option<&Key>, sequence: Option<u64>) -> Result<Option<Encryption>, DownloaderError> {
+  let Some(key) = key else {
+    return Ok(None);
+  };
+
+  if !key.is_identity() {
+    return Err(DownloaderError::DecryptionError(format!("unsupported KEYFORMAT `{}`", key.key_format.as_deref().unwrap_or_default())));
+  }
+  if key.method != KeyMethod::Aes128 {
+    return Err(DownloaderError::DecryptionError("SAMPLE-AES encrypted streams are not supported".to_string()));
+  }
+
+  let iv = key
+    .iv
+    .or(sequence.map(|sequence| (sequence as u128).to_be_bytes()))
+    .ok_or_else(|| DownloaderError::DecryptionError("encrypted EXT-X-MAP has no IV".to_string()))?;
+
+  Ok(Some(Encryption { key_url: resolve_uri(playlist_url, &key.uri)?, iv }))
+}
+
+/// Decrypts segment `number`, or an init section when `number` is `None`.
+fn decrypt(mut bytes: Vec<u8>, key: &[u8; 16], iv: &[u8; 16], number: Option<usize>) -> Result<Vec<u8>, DownloaderError> {
+  let length = Aes128CbcDecryptor::new(key.into(), iv.into())
+    .decrypt_padded_mut::<Pkcs7>(&mut bytes)
+    .map_err(|_| {
+      let source = number.map_or("init section".to_string(), |number| format!("segment #{number}"));
+      DownloaderError::DecryptionError(format!("{source} has invalid padding"))
+    })?
+    .len();
+  bytes.truncate(length);
+  Ok(bytes)
+}
+
+/// GETs `url` (or only `byte_range` of it), retrying failures per
+/// `retry_policy`. Only dropped or stalled connections, 5xx, 408 and 429 are
+/// retried; other statuses fail at once. On failure returns the status of the last
+/// attempt, if there was a response at all.
+async fn fetch_with_retries(
+  url: &str,
+  byte_range: Option<ByteRange>,
+  headers: &HeaderMap,
+  retry_policy: RetryPolicy,
+) -> Result<Vec<u8>, Option<StatusCode>> {
+  let mut backoff = retry_policy.initial_backoff;
+  let mut attempt = 1;
+  loop {
+    let slot = http::acquire_segment_slot().await;
+    let mut request = http::client().get(url).headers(headers.clone());
+    if let Some(range) = byte_range {
+      request = request.header(RANGE, format!("bytes={}-{}", range.offset, range.last_byte()));
     }
 
-    let _ = futures::future::join_all(tasks).await;
-    let bytes_data = ordered_bytes.lock().unwrap().iter().flatten().cloned().collect::<Vec<u8>>();
+    let status = match tokio::time::timeout(retry_policy.read_timeout, request.send()).await {
+      Ok(Ok(response)) if response.status().is_success() => {
+        let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        match http::read_body(response, retry_policy.read_timeout).await {
+          Ok(bytes) => match byte_range {
+            // Servers that ignore `Range` send the whole resource.
+            Some(range) if !partial => match bytes.get(range.offset as usize..=range.last_byte() as usize) {
+              Some(bytes) => return Ok(bytes.to_vec()),
+              None => Some(StatusCode::RANGE_NOT_SATISFIABLE),
+            },
+            _ => return Ok(bytes),
+          },
+          Err(status) => status,
+        }
+      }
+      Ok(Ok(response)) => Some(response.status()),
+      Ok(Err(e)) => e.status(),
+      Err(_) => None,
+    };
+    drop(slot);
 
-    Ok(MediaPlaylist { name: name, byte_data: bytes_data })
+    if attempt >= retry_policy.attempts || !is_transient(status) {
+      return Err(status);
+    }
+
+    warn!("Request for {url} failed (attempt {attempt}/{}), retrying in {backoff:?}", retry_policy.attempts);
+    tokio::time::sleep(backoff).await;
+    backoff *= 2;
+    attempt += 1;
   }
+}
+
+/// Whether a request that failed with `status` may succeed when repeated.
+/// Failures without a status, such as timeouts, always may.
+fn is_transient(status: Option<StatusCode>) -> bool {
+  status.is_none_or(|status| status.is_server_error() || matches!(status, StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::downloader::test_server::{Reply, TestServer};
+
+  const PLAYLIST: &str = "#EXTM3U\n\
+    #EXT-X-TARGETDURATION:2\n\
+    #EXT-X-MAP:URI=\"init.mp4\"\n\
+    #EXTINF:2.0,\n\
+    seg1.m4s\n\
+    #EXTINF:2.0,\n\
+    seg2.m4s\n\
+    #EXTINF:2.0,\n\
+    seg3.m4s\n\
+    #EXT-X-ENDLIST\n";
+
+  const FAST_RETRIES: RetryPolicy = RetryPolicy { attempts: 3, initial_backoff: Duration::from_millis(1), read_timeout: Duration::from_millis(200) };
+
+  async fn serve_playlist() -> TestServer {
+    let server = TestServer::start().await;
+    server.route("/media.m3u8", vec![Reply::Ok(PLAYLIST.into())]);
+    server.route("/init.mp4", vec![Reply::Ok(b"init-".to_vec())]);
+    server.route("/seg1.m4s", vec![Reply::Ok(b"one-".to_vec())]);
+    server.route("/seg2.m4s", vec![Reply::Ok(b"two-".to_vec())]);
+    server.route("/seg3.m4s", vec![Reply::Ok(b"three".to_vec())]);
+    server
+  }
+
+  async fn download(server: &TestServer) -> (Result<(), DownloaderError>, Vec<u8>) {
+    let mut playlist = MediaPlaylist::from_url(&server.url("/media.m3u8"), &HeaderMap::new()).await.unwrap();
+    playlist.retry_policy = FAST_RETRIES;
+
+    let path = server.temp_path("out.mp4");
+    let result = playlist.download_to(&path, &ProgressReporter::default()).await;
+    let bytes = tokio::fs::read(&path).await.unwrap_or_default();
+    let _ = tokio::fs::remove_file(&path).await;
+    (result, bytes)
+  }
+
+  #[tokio::test]
+  async fn writes_segments_in_order() {
+    let server = serve_playlist().await;
+
+    let (result, bytes) = download(&server).await;
+
+    assert!(result.is_ok());
+    assert_eq!(bytes, b"init-one-two-three");
+  }
+
+  #[tokio::test]
+  async fn reports_segments_and_bytes_written() {
+    let server = serve_playlist().await;
+    let playlist = MediaPlaylist::from_url(&server.url("/media.m3u8"), &HeaderMap::new()).await.unwrap();
+    let (reporter, progress) = ProgressReporter::new();
+
+    let path = server.temp_path("out.mp4");
+    playlist.download_to(&path, &reporter).await.unwrap();
+    let _ = tokio::fs::remove_file(&path).await;
+
+    assert_eq!((progress.borrow().fraction, progress.borrow().bytes), (Some(1.0), 18));
+  }
+
+  #[tokio::test]
+  async fn retries_transient_failures() {
+    let server = serve_playlist().await;
+    server.route("/seg2.m4s", vec![Reply::Drop, Reply::Status(503), Reply::Ok(b"two-".to_vec())]);
+
+    let (result, bytes) = download(&server).await;
+
+    assert!(result.is_ok());
+    assert_eq!(bytes, b"init-one-two-three");
+    assert_eq!(server.hits("/seg2.m4s"), 3);
+  }
+
+  #[tokio::test]
+  async fn names_segment_and_status_when_retries_run_out() {
+    let server = serve_playlist().await;
+    server.route("/seg3.m4s", vec![Reply::Status(503)]);
+
+    let (result, _) = download(&server).await;
+
+    assert!(matches!(result, Err(DownloaderError::SegmentFetchError(3, Some(503)))));
+    assert_eq!(server.hits("/seg3.m4s"), FAST_RETRIES.attempts as usize);
+  }
+
+  #[tokio::test]
+  async fn retries_stalled_segments() {
+    let server = serve_playlist().await;
+    server.route("/seg2.m4s", vec![Reply::Stall(b"tw".to_vec()), Reply::Ok(b"two-".to_vec())]);
+
+    let (result, bytes) = download(&server).await;
+
+    assert!(result.is_ok());
+    assert_eq!(bytes, b"init-one-two-three");
+    assert_eq!(server.hits("/seg2.m4s"), 2);
+  }
+
+  #[tokio::test]
+  async fn fails_missing_segments_without_retrying() {
+    let server = serve_playlist().await;
+    server.route("/seg2.m4s", vec![Reply::Status(404)]);
+
+    let (result, _) = download(&server).await;
+
+    assert!(matches!(result, Err(DownloaderError::SegmentFetchError(2, Some(404)))));
+    assert_eq!(server.hits("/seg2.m4s"), 1);
+  }
+
+  #[tokio::test]
+  async fn numbers_only_media_segments_in_errors() {
+    let server = serve_playlist().await;
+    server.route(
+      "/media.m3u8",
+      vec![Reply::Ok(
+        "#EXTM3U\n\
+         #EXT-X-TARGETDURATION:2\n\
+         #EXT-X-MAP:URI=\"init.mp4\"\n\
+         #EXTINF:2.0,\nseg1.m4s\n\
+         #EXT-X-MAP:URI=\"init2.mp4\"\n\
+         #EXTINF:2.0,\nseg2.m4s\n\
+         #EXTINF:2.0,\nseg3.m4s\n\
+         #EXT-X-ENDLIST\n"
+          .into(),
+      )],
+    );
+    server.route("/init2.mp4", vec![Reply::Ok(b"init2-".to_vec())]);
+    server.route("/seg3.m4s", vec![Reply::Status(404)]);
+
+    let (result, _) = download(&server).await;
+    assert!(matches!(result, Err(DownloaderError::SegmentFetchError(3, Some(404)))));
+
+    server.route("/seg3.m4s", vec![Reply::Ok(b"three".to_vec())]);
+    server.route("/init2.mp4", vec![Reply::Status(404)]);
+
+    let (result, _) = download(&server).await;
+    assert!(matches!(result, Err(DownloaderError::InitSectionFetchError(Some(404)))));
+  }
+
+  #[tokio::test]
+  async fn fetches_byte_range_segments_with_range_requests() {
+    let server = TestServer::start().await;
+    server.route(
+      "/media.m3u8",
+      vec![Reply::Ok(
+        "#EXTM3U\n\
+         #EXT-X-TARGETDURATION:2\n\
+         #EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"5@0\"\n\
+         #EXTINF:2.0,\n#EXT-X-BYTERANGE:4@5\nmain.mp4\n\
+         #EXTINF:2.0,\n#EXT-X-BYTERANGE:4\nmain.mp4\n\
+         #EXTINF:2.0,\n#EXT-X-BYTERANGE:5\nmain.mp4\n\
+         #EXT-X-ENDLIST\n"
+          .into(),
+      )],
+    );
+    server.route("/main.mp4", vec![Reply::Ok(b"init-one-two-three-trailer".to_vec())]);
+
+    let (result, bytes) = download(&server).await;
+
+    assert!(result.is_ok());
+    assert_eq!(bytes, b"init-one-two-three");
+    assert_eq!(server.hits("/main.mp4"), 4);
+  }
+
+  fn live_playlist(first_sequence: u64, segments: &[&str], end_list: bool) -> Reply {
+    let mut playlist = format!("#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{first_sequence}\n");
+    for segment in segments {
+      playlist.push_str(&format!("#EXTINF:2.0,\n{segment}\n"));
+    }
+    if end_list {
+      playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+    Reply::Ok(playlist.into())
+  }
+
+  async fn record(server: &TestServer, max_duration: Duration) -> (Result<(), DownloaderError>, Vec<u8>) {
+    let mut playlist = MediaPlaylist::from_url(&server.url("/live.m3u8"), &HeaderMap::new()).await.unwrap();
+    playlist.target_duration = Duration::from_millis(10);
+    playlist.max_live_duration = max_duration;
+
+    let path = server.temp_path("live.ts");
+    let result = playlist.download_to(&path, &ProgressReporter::default()).await;
+    let bytes = tokio::fs::read(&path).await.unwrap_or_default();
+    let _ = tokio::fs::remove_file(&path).await;
+    (result, bytes)
+  }
+
+  async fn serve_live_segments(server: &TestServer) {
+    for (path, body) in [("/s0.ts", "zero-"), ("/s1.ts", "one-"), ("/s2.ts", "two-"), ("/s3.ts", "three")] {
+      server.route(path, vec![Reply::Ok(body.into())]);
+    }
+  }
+
+  #[tokio::test]
+  async fn records_live_playlist_until_endlist() {
+    let server = TestServer::start().await;
+    serve_live_segments(&server).await;
+    server.route(
+      "/live.m3u8",
+      vec![
+        live_playlist(0, &["s0.ts", "s1.ts"], false),
+        live_playlist(0, &["s0.ts", "s1.ts"], false),
+        live_playlist(1, &["s1.ts", "s2.ts"], false),
+        live_playlist(2, &["s2.ts", "s3.ts"], true),
+      ],
+    );
+
+    let (result, bytes) = record(&server, Duration::from_secs(3600)).await;
+
+    assert!(result.is_ok());
+    assert_eq!(bytes, b"zero-one-two-three");
+    assert_eq!(server.hits("/live.m3u8"), 4);
+    assert_eq!(server.hits("/s1.ts"), 1);
+  }
+
+  #[tokio::test]
+  async fn stops_live_recording_at_max_duration() {
+    let server = TestServer::start().await;
+    serve_live_segments(&server).await;
+    server.route("/live.m3u8", vec![live_playlist(0, &["s0.ts"], false), live_playlist(0, &["s0.ts", "s1.ts", "s2.ts", "s3.ts"], false)]);
+
+    let (result, bytes) = record(&server, Duration::from_secs(4)).await;
+
+    assert!(result.is_ok());
+    assert_eq!(bytes, b"zero-one-");
+    assert_eq!(server.hits("/s2.ts"), 0);
+  }
+
+  #[tokio::test]
+  async fn does_not_overshoot_max_duration() {
+    let server = TestServer::start().await;
+    serve_live_segments(&server).await;
+    server.route("/live.m3u8", vec![live_playlist(0, &["s0.ts", "s1.ts", "s2.ts"], false)]);
+
+    let (result, bytes) = record(&server, Duration::from_secs(3)).await;
+
+    assert!(result.is_ok());
+    assert_eq!(bytes, b"zero-");
+    assert_eq!(server.hits("/s1.ts"), 0);
+  }
+
+  fn encrypt(plain: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Vec<u8> {
+    use aes::cipher::BlockEncryptMut;
+    let mut buffer = plain.to_vec();
+    buffer.resize(plain.len() / 16 * 16 + 16, 0);
+    cbc::Encryptor::<aes::Aes128>::new(key.into(), iv.into()).encrypt_padded_mut::<Pkcs7>(&mut buffer, plain.len()).unwrap().to_vec()
+  }
+
+  #[tokio::test]
+  async fn decrypts_aes_128_segments_with_playlist_headers() {
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+    let explicit_iv = [7u8; 16];
+    let server = TestServer::start().await;
+    server.route(
+      "/media.m3u8",
+      vec![Reply::Ok(
+        "#EXTM3U\n\
+         #EXT-X-TARGETDURATION:2\n\
+         #EXT-X-MEDIA-SEQUENCE:5\n\
+         #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\n\
+         #EXTINF:2.0,\nseg1.ts\n\
+         #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x07070707070707070707070707070707\n\
+         #EXTINF:2.0,\nseg2.ts\n\
+         #EXT-X-KEY:METHOD=NONE\n\
+         #EXTINF:2.0,\nseg3.ts\n\
+         #EXT-X-ENDLIST\n"
+          .into(),
+      )],
+    );
+    server.route("/key.bin", vec![Reply::Ok(KEY.to_vec())]);
+    server.route("/seg1.ts", vec![Reply::Ok(encrypt(b"sequence-iv-", &KEY, &5u128.to_be_bytes()))]);
+    server.route("/seg2.ts", vec![Reply::Ok(encrypt(b"explicit-iv-", &KEY, &explicit_iv))]);
+    server.route("/seg3.ts", vec![Reply::Ok(b"clear".to_vec())]);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(reqwest::header::COOKIE, "session=abc".parse().unwrap());
+    let playlist = MediaPlaylist::from_url(&server.url("/media.m3u8"), &headers).await.unwrap();
+    let path = server.temp_path("out.ts");
+    let result = playlist.download_to(&path, &ProgressReporter::default()).await;
+    let bytes = tokio::fs::read(&path).await.unwrap_or_default();
+    let _ = tokio::fs::remove_file(&path).await;
+
+    assert!(result.is_ok());
+    assert_eq!(bytes, b"sequence-iv-explicit-iv-clear");
+    assert_eq!(server.hits("/key.bin"), 1);
+    assert_eq!(server.last_header("/key.bin", "cookie").as_deref(), Some("session=abc"));
+  }
+
+  #[tokio::test]
+  async fn rejects_sample_aes() {
+    let server = TestServer::start().await;
+    server.route(
+      "/media.m3u8",
+      vec![Reply::Ok("#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"key.bin\"\n#EXTINF:2.0,\nseg1.ts\n".into())],
+    );
+
+    let result = MediaPlaylist::from_url(&server.url("/media.m3u8"), &HeaderMap::new()).await;
+
+    assert!(matches!(result, Err(DownloaderError::DecryptionError(_))));
+  }
+
+  #[tokio::test]
+  async fn rejects_rotation_to_an_unsupported_key() {
+    let server = TestServer::start().await;
+    server.route(
+      "/media.m3u8",
+      vec![Reply::Ok(
+        "#EXTM3U\n#EXT-X-TARGETDURATION:2\n\
+         #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\n#EXTINF:2.0,\nseg1.ts\n\
+         #EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://drm\",KEYFORMAT=\"com.apple.streamingkeydelivery\"\n#EXTINF:2.0,\nseg2.ts\n#EXT-X-ENDLIST\n"
+          .into(),
+      )],
+    );
+
+    let result = MediaPlaylist::from_url(&server.url("/media.m3u8"), &HeaderMap::new()).await;
+
+    assert!(matches!(result, Err(DownloaderError::DecryptionError(_))));
+  }
+
+  #[tokio::test]
+  async fn reports_dropped_connections_without_status() {
+    let server = serve_playlist().await;
+    server.route("/seg1.m4s", vec![Reply::Drop]);
+
+    let (result, _) = download(&server).await;
 
-  pub fn get_byte_data(&self) -> &Vec<u8> {
-    &self.byte_data
+    assert!(matches!(result, Err(DownloaderError::SegmentFetchError(1, None))));
   }
 }
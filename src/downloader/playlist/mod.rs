@@ -1,3 +1,6 @@
+pub mod hls;
 pub mod master_playlist;
 pub mod media_playlist;
+pub mod remuxer;
+pub mod subtitles;
 pub mod variant_playlist;
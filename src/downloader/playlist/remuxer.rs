@@ -0,0 +1,855 @@
+//! Remuxes fragmented MP4 streams (an `EXT-X-MAP` init segment followed by
+//! `moof`/`mdat` fragments, as served by Twitter HLS) into one progressive MP4
+//! containing every track of every input, without calling ffmpeg.
+//!
+//! Only the `moov` and `moof` boxes are held in memory. Sample data is copied
+//! from the inputs into the output's `mdat` run by run, interleaved by decode
+//! time, and the `moov` is written before the `mdat` so players can start
+//! playback before the whole file has arrived.
+
+use std::{
+  fs::File,
+  io::{BufWriter, Read, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+};
+
+use crate::downloader::downloader_error::DownloaderError;
+
+type FourCC = [u8; 4];
+
+const TFHD_BASE_DATA_OFFSET: u32 = 0x01;
+const TFHD_SAMPLE_DESCRIPTION_INDEX: u32 = 0x02;
+const TFHD_DEFAULT_DURATION: u32 = 0x08;
+const TFHD_DEFAULT_SIZE: u32 = 0x10;
+const TFHD_DEFAULT_FLAGS: u32 = 0x20;
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x2_0000;
+
+const TRUN_DATA_OFFSET: u32 = 0x01;
+const TRUN_FIRST_SAMPLE_FLAGS: u32 = 0x04;
+const TRUN_DURATION: u32 = 0x100;
+const TRUN_SIZE: u32 = 0x200;
+const TRUN_FLAGS: u32 = 0x400;
+const TRUN_COMPOSITION_OFFSET: u32 = 0x800;
+
+const SAMPLE_IS_NON_SYNC: u32 = 0x1_0000;
+
+#[derive(Clone, Copy, Default)]
+struct TrackDefaults {
+  description_index: u32,
+  duration: u32,
+  size: u32,
+  flags: u32,
+}
+
+struct Sample {
+  size: u32,
+  duration: u32,
+  composition_offset: i32,
+  sync: bool,
+}
+
+/// One `trun` worth of samples; contiguous in both the input and the output.
+struct Chunk {
+  source_offset: u64,
+  size: u64,
+  sample_count: u32,
+  description_index: u32,
+  decode_time: u64,
+  output_offset: u64,
+}
+
+struct Track {
+  input: usize,
+  id: u32,
+  trak: Vec<u8>,
+  timescale: u32,
+  defaults: TrackDefaults,
+  next_decode_time: u64,
+  samples: Vec<Sample>,
+  chunks: Vec<Chunk>,
+}
+
+impl Track {
+  fn media_duration(&self) -> u64 {
+    self.samples.iter().map(|sample| sample.duration as u64).sum()
+  }
+}
+
+/// Merges the fragmented MP4 files in `inputs` into a progressive MP4 at `output`.
+pub fn remux(inputs: &[PathBuf], output: &Path) -> Result<(), DownloaderError> {
+  let mut mvhd = None;
+  let mut tracks = vec![];
+  for (index, input) in inputs.iter().enumerate() {
+    let input_mvhd = read_input(input, index, &mut tracks)?;
+    mvhd = mvhd.or(input_mvhd);
+  }
+
+  let mvhd = mvhd.ok_or_else(|| remux_error("input has no movie header"))?;
+  tracks.retain(|track| !track.samples.is_empty());
+  if tracks.is_empty() {
+    return Err(remux_error("input has no samples"));
+  }
+
+  let ftyp = build_ftyp();
+  let payload_size: u64 = tracks.iter().flat_map(|track| &track.chunks).map(|chunk| chunk.size).sum();
+
+  // The moov size does not depend on the offset values, only on their width.
+  let mut large = false;
+  let mut moov = build_moov(&mvhd, &tracks, large)?;
+  if ftyp.len() as u64 + moov.len() as u64 + 8 + payload_size > u32::MAX as u64 {
+    large = true;
+    moov = build_moov(&mvhd, &tracks, large)?;
+  }
+
+  let mdat_header_size = if large { 16 } else { 8 };
+  let order = interleave(&tracks);
+  let mut offset = (ftyp.len() + moov.len() + mdat_header_size) as u64;
+  for &(track, chunk) in &order {
+    let chunk = &mut tracks[track].chunks[chunk];
+    chunk.output_offset = offset;
+    offset += chunk.size;
+  }
+  let moov = build_moov(&mvhd, &tracks, large)?;
+
+  let mut writer = BufWriter::new(File::create(output).map_err(io_error)?);
+  writer.write_all(&ftyp).map_err(io_error)?;
+  writer.write_all(&moov).map_err(io_error)?;
+  if large {
+    writer.write_all(&1u32.to_be_bytes()).map_err(io_error)?;
+    writer.write_all(b"mdat").map_err(io_error)?;
+    writer.write_all(&(payload_size + 16).to_be_bytes()).map_err(io_error)?;
+  } else {
+    writer.write_all(&(payload_size as u32 + 8).to_be_bytes()).map_err(io_error)?;
+    writer.write_all(b"mdat").map_err(io_error)?;
+  }
+
+  let mut files = inputs.iter().map(File::open).collect::<Result<Vec<_>, _>>().map_err(io_error)?;
+  for (track, chunk) in order {
+    let track = &tracks[track];
+    let chunk = &track.chunks[chunk];
+    let file = &mut files[track.input];
+    file.seek(SeekFrom::Start(chunk.source_offset)).map_err(io_error)?;
+    let copied = std::io::copy(&mut file.take(chunk.size), &mut writer).map_err(io_error)?;
+    if copied != chunk.size {
+      return Err(remux_error("sample data is truncated"));
+    }
+  }
+
+  writer.flush().map_err(io_error)
+}
+
+/// Reads the init segment and fragment headers of one input, appending its
+/// tracks to `tracks`. Returns the input's `mvhd` payload.
+fn read_input(path: &Path, input: usize, tracks: &mut Vec<Track>) -> Result<Option<Vec<u8>>, DownloaderError> {
+  let mut file = File::open(path).map_err(io_error)?;
+  let length = file.metadata().map_err(io_error)?.len();
+  let first_track = tracks.len();
+  let mut mvhd = None;
+
+  let mut position = 0;
+  while position < length {
+    file.seek(SeekFrom::Start(position)).map_err(io_error)?;
+    let mut header = [0u8; 16];
+    file.read_exact(&mut header[..8]).map_err(io_error)?;
+    let kind: FourCC = header[4..8].try_into().unwrap();
+    let (size, header_size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+      0 => (length - position, 8),
+      1 => {
+        file.read_exact(&mut header[8..]).map_err(io_error)?;
+        (u64::from_be_bytes(header[8..].try_into().unwrap()), 16)
+      }
+      size => (size as u64, 8),
+    };
+    if size < header_size || position + size > length {
+      return Err(remux_error(&format!("truncated `{}` box", String::from_utf8_lossy(&kind))));
+    }
+
+    match &kind {
+      b"moov" | b"moof" => {
+        let mut payload = vec![0u8; (size - header_size) as usize];
+        file.read_exact(&mut payload).map_err(io_error)?;
+        if &kind == b"moov" {
+          mvhd = Some(read_moov(&payload, input, tracks)?);
+        } else {
+          read_moof(&payload, position, &mut tracks[first_track..])?;
+        }
+      }
+      _ => {}
+    }
+
+    position += size;
+  }
+
+  Ok(mvhd)
+}
+
+fn read_moov(moov: &[u8], input: usize, tracks: &mut Vec<Track>) -> Result<Vec<u8>, DownloaderError> {
+  let first_track = tracks.len();
+  let mut mvhd = None;
+  let mut trex = vec![];
+
+  for (kind, payload) in children(moov)? {
+    match &kind {
+      b"mvhd" => mvhd = Some(payload.to_vec()),
+      b"trak" => {
+        let tkhd = child(payload, b"tkhd")?;
+        let mdhd = child(child(payload, b"mdia")?, b"mdhd")?;
+        let mut tkhd_reader = Reader::new(tkhd);
+        let version = tkhd_reader.u8()?;
+        tkhd_reader.skip(if version == 1 { 3 + 16 } else { 3 + 8 })?;
+        let id = tkhd_reader.u32()?;
+        let mut mdhd_reader = Reader::new(mdhd);
+        let version = mdhd_reader.u8()?;
+        mdhd_reader.skip(if version == 1 { 3 + 16 } else { 3 + 8 })?;
+        let timescale = mdhd_reader.u32()?;
+
+        tracks.push(Track {
+          input,
+          id,
+          trak: payload.to_vec(),
+          timescale,
+          defaults: TrackDefaults { description_index: 1, ..Default::default() },
+          next_decode_time: 0,
+          samples: vec![],
+          chunks: vec![],
+        });
+      }
+      b"mvex" => {
+        for (kind, payload) in children(payload)? {
+          if &kind == b"trex" {
+            let mut reader = Reader::new(payload);
+            reader.skip(4)?;
+            let id = reader.u32()?;
+            let defaults = TrackDefaults { description_index: reader.u32()?, duration: reader.u32()?, size: reader.u32()?, flags: reader.u32()? };
+            trex.push((id, defaults));
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+
+  for track in &mut tracks[first_track..] {
+    if let Some((_, defaults)) = trex.iter().find(|(id, _)| *id == track.id) {
+      track.defaults = *defaults;
+    }
+  }
+
+  mvhd.ok_or_else(|| remux_error("moov has no mvhd"))
+}
+
+fn read_moof(moof: &[u8], moof_start: u64, tracks: &mut [Track]) -> Result<(), DownloaderError> {
+  let mut previous_data_end = moof_start;
+
+  for (kind, traf) in children(moof)? {
+    if &kind != b"traf" {
+      continue;
+    }
+
+    let mut reader = Reader::new(child(traf, b"tfhd")?);
+    let tfhd_flags = reader.u32()? & 0xff_ffff;
+    let id = reader.u32()?;
+    let track = tracks.iter_mut().find(|track| track.id == id).ok_or_else(|| remux_error(&format!("fragment for unknown track {id}")))?;
+
+    let base_data_offset = if tfhd_flags & TFHD_BASE_DATA_OFFSET != 0 {
+      reader.u64()?
+    } else if tfhd_flags & TFHD_DEFAULT_BASE_IS_MOOF != 0 {
+      moof_start
+    } else {
+      previous_data_end
+    };
+    let mut defaults = track.defaults;
+    if tfhd_flags & TFHD_SAMPLE_DESCRIPTION_INDEX != 0 {
+      defaults.description_index = reader.u32()?;
+    }
+    if tfhd_flags & TFHD_DEFAULT_DURATION != 0 {
+      defaults.duration = reader.u32()?;
+    }
+    if tfhd_flags & TFHD_DEFAULT_SIZE != 0 {
+      defaults.size = reader.u32()?;
+    }
+    if tfhd_flags & TFHD_DEFAULT_FLAGS != 0 {
+      defaults.flags = reader.u32()?;
+    }
+
+    if let Some(tfdt) = children(traf)?.into_iter().find(|(kind, _)| kind == b"tfdt") {
+      let mut reader = Reader::new(tfdt.1);
+      track.next_decode_time = if reader.u32()? >> 24 == 1 { reader.u64()? } else { reader.u32()? as u64 };
+    }
+
+    let mut data_offset = base_data_offset;
+    for (kind, trun) in children(traf)? {
+      if &kind != b"trun" {
+        continue;
+      }
+
+      let mut reader = Reader::new(trun);
+      let trun_flags = reader.u32()? & 0xff_ffff;
+      let sample_count = reader.u32()?;
+      if trun_flags & TRUN_DATA_OFFSET != 0 {
+        data_offset = base_data_offset.checked_add_signed(reader.i32()? as i64).ok_or_else(|| remux_error("invalid trun data offset"))?;
+      }
+      let first_sample_flags = if trun_flags & TRUN_FIRST_SAMPLE_FLAGS != 0 { Some(reader.u32()?) } else { None };
+
+      let decode_time = track.next_decode_time;
+      let mut size = 0u64;
+      for i in 0..sample_count {
+        let duration = if trun_flags & TRUN_DURATION != 0 { reader.u32()? } else { defaults.duration };
+        let sample_size = if trun_flags & TRUN_SIZE != 0 { reader.u32()? } else { defaults.size };
+        let flags = if trun_flags & TRUN_FLAGS != 0 { reader.u32()? } else { defaults.flags };
+        let flags = if i == 0 { first_sample_flags.unwrap_or(flags) } else { flags };
+        let composition_offset = if trun_flags & TRUN_COMPOSITION_OFFSET != 0 { reader.i32()? } else { 0 };
+
+        track.samples.push(Sample { size: sample_size, duration, composition_offset, sync: flags & SAMPLE_IS_NON_SYNC == 0 });
+        track.next_decode_time += duration as u64;
+        size += sample_size as u64;
+      }
+
+      if sample_count > 0 {
+        track.chunks.push(Chunk {
+          source_offset: data_offset,
+          size,
+          sample_count,
+          description_index: defaults.description_index.max(1),
+          decode_time,
+          output_offset: 0,
+        });
+      }
+      data_offset += size;
+    }
+    previous_data_end = data_offset;
+  }
+
+  Ok(())
+}
+
+/// Orders all chunks by decode time so audio and video stay close together.
+fn interleave(tracks: &[Track]) -> Vec<(usize, usize)> {
+  let mut order = tracks.iter().enumerate().flat_map(|(t, track)| (0..track.chunks.len()).map(move |c| (t, c))).collect::<Vec<_>>();
+  order.sort_by(|&(a_track, a_chunk), &(b_track, b_chunk)| {
+    let (a, b) = (&tracks[a_track], &tracks[b_track]);
+    let a_time = a.chunks[a_chunk].decode_time as u128 * b.timescale as u128;
+    let b_time = b.chunks[b_chunk].decode_time as u128 * a.timescale as u128;
+    a_time.cmp(&b_time).then(a_track.cmp(&b_track))
+  });
+  order
+}
+
+fn build_ftyp() -> Vec<u8> {
+  let mut payload = b"isom".to_vec();
+  payload.extend_from_slice(&0x200u32.to_be_bytes());
+  for brand in [b"isom", b"iso2", b"avc1", b"mp41"] {
+    payload.extend_from_slice(brand);
+  }
+  mp4_box(b"ftyp", &payload)
+}
+
+fn build_moov(mvhd: &[u8], tracks: &[Track], large: bool) -> Result<Vec<u8>, DownloaderError> {
+  let movie_timescale = {
+    let mut reader = Reader::new(mvhd);
+    let version = reader.u8()?;
+    reader.skip(if version == 1 { 3 + 16 } else { 3 + 8 })?;
+    reader.u32()?
+  };
+
+  let mut traks = vec![];
+  let mut movie_duration = 0;
+  for (index, track) in tracks.iter().enumerate() {
+    let duration = rescale(track.media_duration(), track.timescale, movie_timescale);
+    movie_duration = movie_duration.max(duration);
+    traks.extend(build_trak(track, index as u32 + 1, movie_timescale, large)?);
+  }
+
+  let mut mvhd = mvhd.to_vec();
+  patch_duration(&mut mvhd, movie_duration)?;
+  let next_track_id = mvhd.len().checked_sub(4).ok_or_else(|| remux_error("mvhd is truncated"))?;
+  mvhd[next_track_id..].copy_from_slice(&(tracks.len() as u32 + 1).to_be_bytes());
+
+  let mut payload = mp4_box(b"mvhd", &mvhd);
+  payload.extend(traks);
+  Ok(mp4_box(b"moov", &payload))
+}
+
+fn build_trak(track: &Track, id: u32, movie_timescale: u32, large: bool) -> Result<Vec<u8>, DownloaderError> {
+  let media_duration = track.media_duration();
+  let movie_duration = rescale(media_duration, track.timescale, movie_timescale);
+  let mut payload = vec![];
+
+  for (kind, child_payload) in children(&track.trak)? {
+    match &kind {
+      b"tkhd" => {
+        let mut tkhd = child_payload.to_vec();
+        let version = *tkhd.first().ok_or_else(|| remux_error("tkhd is truncated"))?;
+        // enabled and in movie
+        tkhd[3] |= 0x03;
+        let (id_offset, duration_offset) = if version == 1 { (20, 28) } else { (12, 20) };
+        write_at(&mut tkhd, id_offset, &id.to_be_bytes())?;
+        if version == 1 {
+          write_at(&mut tkhd, duration_offset, &movie_duration.to_be_bytes())?;
+        } else {
+          write_at(&mut tkhd, duration_offset, &(movie_duration.min(u32::MAX as u64) as u32).to_be_bytes())?;
+        }
+        payload.extend(mp4_box(b"tkhd", &tkhd));
+      }
+      b"edts" => {
+        if let Some(elst) = children(child_payload)?.into_iter().find(|(kind, _)| kind == b"elst") {
+          let elst = build_elst(elst.1, media_duration, track.timescale, movie_timescale)?;
+          payload.extend(mp4_box(b"edts", &elst));
+        }
+      }
+      b"mdia" => {
+        let mut mdia = vec![];
+        for (kind, mdia_child) in children(child_payload)? {
+          match &kind {
+            b"mdhd" => {
+              let mut mdhd = mdia_child.to_vec();
+              patch_duration(&mut mdhd, media_duration)?;
+              mdia.extend(mp4_box(b"mdhd", &mdhd));
+            }
+            b"minf" => {
+              let mut minf = vec![];
+              for (kind, minf_child) in children(mdia_child)? {
+                match &kind {
+                  b"stbl" => minf.extend(build_stbl(track, child(minf_child, b"stsd")?, large)),
+                  _ => minf.extend(mp4_box(&kind, minf_child)),
+                }
+              }
+              mdia.extend(mp4_box(b"minf", &minf));
+            }
+            _ => mdia.extend(mp4_box(&kind, mdia_child)),
+          }
+        }
+        payload.extend(mp4_box(b"mdia", &mdia));
+      }
+      _ => {}
+    }
+  }
+
+  Ok(mp4_box(b"trak", &payload))
+}
+
+/// Fragmented files may use a zero segment duration to mean "the rest of the
+/// media", which is not allowed in progressive files.
+fn build_elst(elst: &[u8], media_duration: u64, media_timescale: u32, movie_timescale: u32) -> Result<Vec<u8>, DownloaderError> {
+  let mut reader = Reader::new(elst);
+  let version = reader.u32()? >> 24;
+  let entry_count = reader.u32()?;
+
+  let mut body = entry_count.to_be_bytes().to_vec();
+  for _ in 0..entry_count {
+    let (mut segment_duration, media_time) = if version == 1 { (reader.u64()?, reader.i64()?) } else { (reader.u32()? as u64, reader.i32()? as i64) };
+    let rate = reader.u32()?;
+    if segment_duration == 0 && media_time >= 0 {
+      segment_duration = rescale(media_duration.saturating_sub(media_time as u64), media_timescale, movie_timescale);
+    }
+    body.extend_from_slice(&segment_duration.to_be_bytes());
+    body.extend_from_slice(&media_time.to_be_bytes());
+    body.extend_from_slice(&rate.to_be_bytes());
+  }
+
+  Ok(full_box(b"elst", 1, 0, &body))
+}
+
+fn build_stbl(track: &Track, stsd: &[u8], large: bool) -> Vec<u8> {
+  let mut payload = mp4_box(b"stsd", stsd);
+
+  let mut stts: Vec<(u32, u32)> = vec![];
+  for sample in &track.samples {
+    match stts.last_mut() {
+      Some((count, duration)) if *duration == sample.duration => *count += 1,
+      _ => stts.push((1, sample.duration)),
+    }
+  }
+  payload.extend(full_box(b"stts", 0, 0, &table(&stts)));
+
+  if track.samples.iter().any(|sample| sample.composition_offset != 0) {
+    let mut ctts: Vec<(u32, u32)> = vec![];
+    for sample in &track.samples {
+      match ctts.last_mut() {
+        Some((count, offset)) if *offset == sample.composition_offset as u32 => *count += 1,
+        _ => ctts.push((1, sample.composition_offset as u32)),
+      }
+    }
+    let version = if track.samples.iter().any(|sample| sample.composition_offset < 0) { 1 } else { 0 };
+    payload.extend(full_box(b"ctts", version, 0, &table(&ctts)));
+  }
+
+  if track.samples.iter().any(|sample| !sample.sync) {
+    let sync_samples = track.samples.iter().enumerate().filter(|(_, sample)| sample.sync).map(|(i, _)| i as u32 + 1).collect::<Vec<_>>();
+    let mut body = (sync_samples.len() as u32).to_be_bytes().to_vec();
+    sync_samples.iter().for_each(|number| body.extend_from_slice(&number.to_be_bytes()));
+    payload.extend(full_box(b"stss", 0, 0, &body));
+  }
+
+  let mut stsc: Vec<(u32, u32, u32)> = vec![];
+  for (index, chunk) in track.chunks.iter().enumerate() {
+    if stsc.last().map(|&(_, count, description)| (count, description)) != Some((chunk.sample_count, chunk.description_index)) {
+      stsc.push((index as u32 + 1, chunk.sample_count, chunk.description_index));
+    }
+  }
+  let mut body = (stsc.len() as u32).to_be_bytes().to_vec();
+  for (first_chunk, count, description) in stsc {
+    body.extend_from_slice(&first_chunk.to_be_bytes());
+    body.extend_from_slice(&count.to_be_bytes());
+    body.extend_from_slice(&description.to_be_bytes());
+  }
+  payload.extend(full_box(b"stsc", 0, 0, &body));
+
+  let first_size = track.samples[0].size;
+  let mut body = vec![];
+  if track.samples.iter().all(|sample| sample.size == first_size) {
+    body.extend_from_slice(&first_size.to_be_bytes());
+    body.extend_from_slice(&(track.samples.len() as u32).to_be_bytes());
+  } else {
+    body.extend_from_slice(&0u32.to_be_bytes());
+    body.extend_from_slice(&(track.samples.len() as u32).to_be_bytes());
+    track.samples.iter().for_each(|sample| body.extend_from_slice(&sample.size.to_be_bytes()));
+  }
+  payload.extend(full_box(b"stsz", 0, 0, &body));
+
+  let mut body = (track.chunks.len() as u32).to_be_bytes().to_vec();
+  for chunk in &track.chunks {
+    if large {
+      body.extend_from_slice(&chunk.output_offset.to_be_bytes());
+    } else {
+      body.extend_from_slice(&(chunk.output_offset as u32).to_be_bytes());
+    }
+  }
+  payload.extend(full_box(if large { b"co64" } else { b"stco" }, 0, 0, &body));
+
+  mp4_box(b"stbl", &payload)
+}
+
+fn table(entries: &[(u32, u32)]) -> Vec<u8> {
+  let mut body = (entries.len() as u32).to_be_bytes().to_vec();
+  for (a, b) in entries {
+    body.extend_from_slice(&a.to_be_bytes());
+    body.extend_from_slice(&b.to_be_bytes());
+  }
+  body
+}
+
+/// Sets the duration of an `mvhd` or `mdhd` payload, which share their layout
+/// up to that field.
+fn patch_duration(header: &mut [u8], duration: u64) -> Result<(), DownloaderError> {
+  match header.first() {
+    Some(1) => write_at(header, 24, &duration.to_be_bytes()),
+    Some(_) => write_at(header, 16, &(duration.min(u32::MAX as u64) as u32).to_be_bytes()),
+    None => Err(remux_error("media header is truncated")),
+  }
+}
+
+fn rescale(value: u64, from: u32, to: u32) -> u64 {
+  if from == 0 {
+    return 0;
+  }
+  (value as u128 * to as u128 / from as u128) as u64
+}
+
+fn write_at(data: &mut [u8], offset: usize, bytes: &[u8]) -> Result<(), DownloaderError> {
+  let target = data.get_mut(offset..offset + bytes.len()).ok_or_else(|| remux_error("header is truncated"))?;
+  target.copy_from_slice(bytes);
+  Ok(())
+}
+
+fn mp4_box(kind: &FourCC, payload: &[u8]) -> Vec<u8> {
+  let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+  data.extend_from_slice(kind);
+  data.extend_from_slice(payload);
+  data
+}
+
+fn full_box(kind: &FourCC, version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+  let mut payload = ((version as u32) << 24 | flags).to_be_bytes().to_vec();
+  payload.extend_from_slice(body);
+  mp4_box(kind, &payload)
+}
+
+/// Splits a box payload into its child boxes.
+fn children(data: &[u8]) -> Result<Vec<(FourCC, &[u8])>, DownloaderError> {
+  let mut boxes = vec![];
+  let mut reader = Reader::new(data);
+  while reader.remaining() > 0 {
+    let start = reader.position;
+    let size = reader.u32()? as u64;
+    let kind: FourCC = reader.bytes(4)?.try_into().unwrap();
+    let size = match size {
+      0 => (data.len() - start) as u64,
+      1 => reader.u64()?,
+      size => size,
+    };
+    let header_size = (reader.position - start) as u64;
+    let payload_size = size.checked_sub(header_size).ok_or_else(|| remux_error("invalid box size"))?;
+    boxes.push((kind, reader.bytes(payload_size as usize)?));
+  }
+  Ok(boxes)
+}
+
+fn child<'a>(data: &'a [u8], kind: &FourCC) -> Result<&'a [u8], DownloaderError> {
+  children(data)?
+    .into_iter()
+    .find(|(child_kind, _)| child_kind == kind)
+    .map(|(_, payload)| payload)
+    .ok_or_else(|| remux_error(&format!("missing `{}` box", String::from_utf8_lossy(kind))))
+}
+
+struct Reader<'a> {
+  data: &'a [u8],
+  position: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn new(data: &'a [u8]) -> Self {
+    Reader { data, position: 0 }
+  }
+
+  fn remaining(&self) -> usize {
+    self.data.len() - self.position
+  }
+
+  fn bytes(&mut self, count: usize) -> Result<&'a [u8], DownloaderError> {
+    if self.remaining() < count {
+      return Err(remux_error("box is truncated"));
+    }
+    let bytes = &self.data[self.position..self.position + count];
+    self.position += count;
+    Ok(bytes)
+  }
+
+  fn skip(&mut self, count: usize) -> Result<(), DownloaderError> {
+    self.bytes(count).map(|_| ())
+  }
+
+  fn u8(&mut self) -> Result<u8, DownloaderError> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn u32(&mut self) -> Result<u32, DownloaderError> {
+    Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+  }
+
+  fn i32(&mut self) -> Result<i32, DownloaderError> {
+    Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+  }
+
+  fn u64(&mut self) -> Result<u64, DownloaderError> {
+    Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+  }
+
+  fn i64(&mut self) -> Result<i64, DownloaderError> {
+    Ok(i64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+  }
+}
+
+fn io_error(e: std::io::Error) -> DownloaderError {
+  remux_error(&e.to_string())
+}
+
+fn remux_error(message: &str) -> DownloaderError {
+  DownloaderError::RemuxError(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct FixtureSample {
+    data: Vec<u8>,
+    duration: u32,
+    sync: bool,
+  }
+
+  fn sample(track: u8, index: u8, length: usize, duration: u32, sync: bool) -> FixtureSample {
+    FixtureSample { data: vec![track * 16 + index; length], duration, sync }
+  }
+
+  fn init_segment(handler: &FourCC, timescale: u32) -> Vec<u8> {
+    let mut mvhd = [0u8; 100];
+    mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+    mvhd[96..100].copy_from_slice(&2u32.to_be_bytes());
+
+    let mut tkhd = [0u8; 84];
+    tkhd[12..16].copy_from_slice(&1u32.to_be_bytes());
+    let mut mdhd = [0u8; 24];
+    mdhd[12..16].copy_from_slice(&timescale.to_be_bytes());
+    let mut hdlr = [0u8; 25];
+    hdlr[8..12].copy_from_slice(handler);
+
+    let sample_entry = mp4_box(if handler == b"vide" { b"avc1" } else { b"mp4a" }, &[0u8; 16]);
+    let mut stsd = 1u32.to_be_bytes().to_vec();
+    stsd.extend(sample_entry);
+    let stbl = [
+      full_box(b"stsd", 0, 0, &stsd),
+      full_box(b"stts", 0, 0, &[0; 4]),
+      full_box(b"stsc", 0, 0, &[0; 4]),
+      full_box(b"stsz", 0, 0, &[0; 8]),
+      full_box(b"stco", 0, 0, &[0; 4]),
+    ]
+    .concat();
+    let media_header = if handler == b"vide" { full_box(b"vmhd", 0, 1, &[0; 8]) } else { full_box(b"smhd", 0, 0, &[0; 4]) };
+    let minf = [media_header, mp4_box(b"dinf", &full_box(b"dref", 0, 0, &0u32.to_be_bytes())), mp4_box(b"stbl", &stbl)].concat();
+    let mdia = [full_box(b"mdhd", 0, 0, &mdhd[4..]), full_box(b"hdlr", 0, 0, &hdlr[4..]), mp4_box(b"minf", &minf)].concat();
+    let elst = full_box(b"elst", 0, 0, &[1u32.to_be_bytes(), 0u32.to_be_bytes(), 0u32.to_be_bytes(), 0x0001_0000u32.to_be_bytes()].concat());
+    let trak = [full_box(b"tkhd", 0, 3, &tkhd[4..]), mp4_box(b"edts", &elst), mp4_box(b"mdia", &mdia)].concat();
+    let trex = full_box(b"trex", 0, 0, &[1u32, 1, 0, 0, 0].iter().flat_map(|value| value.to_be_bytes()).collect::<Vec<_>>());
+
+    let moov = [full_box(b"mvhd", 0, 0, &mvhd[4..]), mp4_box(b"trak", &trak), mp4_box(b"mvex", &trex)].concat();
+    [mp4_box(b"ftyp", b"iso5\0\0\0\x01iso5dash"), mp4_box(b"moov", &moov)].concat()
+  }
+
+  fn fragment(sequence: u32, decode_time: u64, samples: &[FixtureSample]) -> Vec<u8> {
+    let mfhd = full_box(b"mfhd", 0, 0, &sequence.to_be_bytes());
+    let tfhd = full_box(b"tfhd", 0, TFHD_DEFAULT_BASE_IS_MOOF, &1u32.to_be_bytes());
+    let tfdt = full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes());
+
+    let build_moof = |data_offset: i32| {
+      let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+      trun.extend_from_slice(&data_offset.to_be_bytes());
+      for sample in samples {
+        trun.extend_from_slice(&sample.duration.to_be_bytes());
+        trun.extend_from_slice(&(sample.data.len() as u32).to_be_bytes());
+        trun.extend_from_slice(&(if sample.sync { 0 } else { SAMPLE_IS_NON_SYNC }).to_be_bytes());
+      }
+      let trun = full_box(b"trun", 0, TRUN_DATA_OFFSET | TRUN_DURATION | TRUN_SIZE | TRUN_FLAGS, &trun);
+      let traf = [tfhd.clone(), tfdt.clone(), trun].concat();
+      mp4_box(b"moof", &[mfhd.clone(), mp4_box(b"traf", &traf)].concat())
+    };
+
+    let moof_size = build_moof(0).len() as i32;
+    let mdat = mp4_box(b"mdat", &samples.iter().flat_map(|sample| sample.data.clone()).collect::<Vec<_>>());
+    [build_moof(moof_size + 8), mdat].concat()
+  }
+
+  fn write_fixture(name: &str, parts: &[Vec<u8>]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("remuxer-{}-{name}", std::process::id()));
+    std::fs::write(&path, parts.concat()).unwrap();
+    path
+  }
+
+  fn read_u32s(data: &[u8]) -> Vec<u32> {
+    data.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect()
+  }
+
+  /// Reads every sample of `trak` back out of the progressive `file`.
+  fn samples_of(file: &[u8], trak: &[u8]) -> Vec<Vec<u8>> {
+    let stbl = child(child(child(trak, b"mdia").unwrap(), b"minf").unwrap(), b"stbl").unwrap();
+    let stsz = read_u32s(&child(stbl, b"stsz").unwrap()[4..]);
+    let stsc = read_u32s(&child(stbl, b"stsc").unwrap()[8..]);
+    let stco = read_u32s(&child(stbl, b"stco").unwrap()[8..]);
+    let sizes = &stsz[2..];
+
+    let mut samples = vec![];
+    for (chunk_index, &offset) in stco.iter().enumerate() {
+      let entry = stsc.chunks(3).rfind(|entry| entry[0] as usize <= chunk_index + 1).unwrap();
+      let mut offset = offset as usize;
+      for _ in 0..entry[1] {
+        let size = sizes[samples.len()] as usize;
+        samples.push(file[offset..offset + size].to_vec());
+        offset += size;
+      }
+    }
+    samples
+  }
+
+  fn remux_fixture(name: &str) -> (Vec<u8>, Vec<Vec<FixtureSample>>) {
+    let video = vec![
+      vec![sample(1, 0, 40, 3000, true), sample(1, 1, 12, 3000, false), sample(1, 2, 9, 3000, false)],
+      vec![sample(1, 3, 35, 3000, true), sample(1, 4, 11, 3000, false)],
+    ];
+    let audio = vec![vec![sample(2, 0, 6, 1024, true), sample(2, 1, 7, 1024, true)], vec![sample(2, 2, 6, 1024, true), sample(2, 3, 5, 1024, true)]];
+
+    let video_path = write_fixture(&format!("{name}-video"), &[init_segment(b"vide", 90000), fragment(1, 0, &video[0]), fragment(2, 9000, &video[1])]);
+    let audio_path = write_fixture(&format!("{name}-audio"), &[init_segment(b"soun", 48000), fragment(1, 0, &audio[0]), fragment(2, 2048, &audio[1])]);
+    let output_path = std::env::temp_dir().join(format!("remuxer-{}-{name}-output.mp4", std::process::id()));
+
+    remux(&[video_path.clone(), audio_path.clone()], &output_path).unwrap();
+    let output = std::fs::read(&output_path).unwrap();
+    for path in [video_path, audio_path, output_path] {
+      let _ = std::fs::remove_file(path);
+    }
+
+    (output, vec![video.into_iter().flatten().collect(), audio.into_iter().flatten().collect()])
+  }
+
+  #[test]
+  fn writes_progressive_layout_without_fragments() {
+    let (output, _) = remux_fixture("layout");
+
+    let top_level = children(&output).unwrap().into_iter().map(|(kind, _)| kind).collect::<Vec<_>>();
+    assert_eq!(top_level, [*b"ftyp", *b"moov", *b"mdat"]);
+
+    let moov = child(&output, b"moov").unwrap();
+    let moov_children = children(moov).unwrap().into_iter().map(|(kind, _)| kind).collect::<Vec<_>>();
+    assert_eq!(moov_children, [*b"mvhd", *b"trak", *b"trak"]);
+  }
+
+  #[test]
+  fn keeps_every_sample_of_both_tracks() {
+    let (output, expected) = remux_fixture("samples");
+    let moov = child(&output, b"moov").unwrap();
+    let traks = children(moov).unwrap().into_iter().filter(|(kind, _)| kind == b"trak").map(|(_, trak)| trak).collect::<Vec<_>>();
+
+    for (trak, expected) in traks.iter().zip(expected) {
+      let expected = expected.into_iter().map(|sample| sample.data).collect::<Vec<_>>();
+      assert_eq!(samples_of(&output, trak), expected);
+    }
+  }
+
+  #[test]
+  fn renumbers_tracks_and_sets_durations() {
+    let (output, _) = remux_fixture("headers");
+    let moov = child(&output, b"moov").unwrap();
+    let mvhd = read_u32s(child(moov, b"mvhd").unwrap());
+    // 5 video samples of 3000 at 90 kHz, 4 audio samples of 1024 at 48 kHz
+    assert_eq!(mvhd[4], 166);
+    assert_eq!(mvhd[24], 3);
+
+    let traks = children(moov).unwrap().into_iter().filter(|(kind, _)| kind == b"trak").map(|(_, trak)| trak).collect::<Vec<_>>();
+    let ids = traks.iter().map(|trak| read_u32s(child(trak, b"tkhd").unwrap())[3]).collect::<Vec<_>>();
+    assert_eq!(ids, [1, 2]);
+
+    let video_mdhd = read_u32s(child(child(traks[0], b"mdia").unwrap(), b"mdhd").unwrap());
+    assert_eq!(video_mdhd[4], 15000);
+
+    let elst = child(child(traks[0], b"edts").unwrap(), b"elst").unwrap();
+    assert_eq!(u64::from_be_bytes(elst[8..16].try_into().unwrap()), 166);
+  }
+
+  #[test]
+  fn marks_only_keyframes_as_sync_samples() {
+    let (output, _) = remux_fixture("sync");
+    let moov = child(&output, b"moov").unwrap();
+    let traks = children(moov).unwrap().into_iter().filter(|(kind, _)| kind == b"trak").map(|(_, trak)| trak).collect::<Vec<_>>();
+    let stbl = |trak: &[u8]| child(child(child(trak, b"mdia").unwrap(), b"minf").unwrap(), b"stbl").unwrap().to_vec();
+
+    assert_eq!(read_u32s(&child(&stbl(traks[0]), b"stss").unwrap()[4..]), [2, 1, 4]);
+    assert!(child(&stbl(traks[1]), b"stss").is_err());
+  }
+
+  #[test]
+  fn interleaves_chunks_by_decode_time() {
+    let (output, _) = remux_fixture("interleave");
+    let moov = child(&output, b"moov").unwrap();
+    let traks = children(moov).unwrap().into_iter().filter(|(kind, _)| kind == b"trak").map(|(_, trak)| trak).collect::<Vec<_>>();
+    let offsets = |trak: &[u8]| {
+      let stbl = child(child(child(trak, b"mdia").unwrap(), b"minf").unwrap(), b"stbl").unwrap();
+      read_u32s(&child(stbl, b"stco").unwrap()[8..])
+    };
+    let (video, audio) = (offsets(traks[0]), offsets(traks[1]));
+
+    // video 0s, audio 0s, audio 0.043s, video 0.1s
+    assert!(video[0] < audio[0] && audio[0] < audio[1] && audio[1] < video[1]);
+  }
+
+  #[test]
+  fn rejects_input_without_init_segment() {
+    let path = write_fixture("no-init", &[fragment(1, 0, &[sample(1, 0, 4, 1, true)])]);
+    let output = path.with_extension("out");
+
+    let result = remux(std::slice::from_ref(&path), &output);
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(output);
+
+    assert!(matches!(result, Err(DownloaderError::RemuxError(_))));
+  }
+}
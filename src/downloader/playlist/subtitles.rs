@@ -0,0 +1,320 @@
+//! Subtitle renditions (`#EXT-X-MEDIA:TYPE=SUBTITLES`).
+//!
+//! HLS splits WebVTT subtitles into segments that each carry their own header,
+//! and cues spanning a segment boundary are repeated in both. The segments are
+//! stitched into a single WebVTT document and converted to SubRip, which
+//! Telegram clients and ffmpeg's `mov_text` encoder both understand.
+
+use reqwest::header::HeaderMap;
+use std::{
+  collections::HashSet,
+  ffi::OsStr,
+  path::{Path, PathBuf},
+};
+
+use crate::downloader::{
+  downloader_error::DownloaderError,
+  ffmpeg,
+  job_dir::JobDir,
+  playlist::{hls::Media, media_playlist::MediaPlaylist},
+  progress::ProgressReporter,
+};
+
+pub struct SubtitleTrack {
+  pub media: Media,
+  url: String,
+  headers: HeaderMap,
+}
+
+impl SubtitleTrack {
+  pub fn new(media: Media, url: String, headers: HeaderMap) -> Self {
+    SubtitleTrack { media, url, headers }
+  }
+
+  /// Downloads every segment of the track and writes it to `job_dir` as one
+  /// `.srt` file, returning its path.
+  pub async fn download(&self, job_dir: &JobDir) -> Result<PathBuf, DownloaderError> {
+    let segments_name = job_dir.file("subtitles.segments");
+    let media_playlist = MediaPlaylist::from_url(&self.url, &self.headers).await?;
+    // Subtitles are small next to the video, so they are left out of its progress.
+    media_playlist.download_to(&segments_name, &ProgressReporter::default()).await?;
+
+    let segments = tokio::fs::read(&segments_name).await.map_err(|_| DownloaderError::IOError)?;
+    let vtt = stitch_webvtt(&String::from_utf8_lossy(&segments));
+    let _ = tokio::fs::remove_file(&segments_name).await;
+
+    let output_name = job_dir.file(&file_name(self.media.language.as_deref()));
+    tokio::fs::write(&output_name, webvtt_to_srt(&vtt)).await.map_err(|_| DownloaderError::IOError)?;
+    Ok(output_name)
+  }
+}
+
+/// `subtitles.{language}.srt`. The language comes from the remote playlist, so
+/// it is only used when it looks like a language tag.
+fn file_name(language: Option<&str>) -> String {
+  match language {
+    Some(language) if !language.is_empty() && language.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') => format!("subtitles.{language}.srt"),
+    _ => "subtitles.srt".to_string(),
+  }
+}
+
+/// Muxes `subtitles` into `video` as a soft `mov_text` track, writing `output`.
+pub async fn embed(video: &Path, subtitles: &Path, language: Option<&str>, output: &Path) -> Result<(), DownloaderError> {
+  let language = format!("language={}", language.unwrap_or("und"));
+  let mut args = vec![OsStr::new("-i"), video.as_os_str(), OsStr::new("-i"), subtitles.as_os_str()];
+  args.extend(["-map", "0", "-map", "1", "-c", "copy", "-c:s", "mov_text", "-metadata:s:s:0"].map(OsStr::new));
+  args.extend([OsStr::new(&language), output.as_os_str()]);
+  ffmpeg::run(args).await
+}
+
+/// Joins concatenated WebVTT segments into one document: headers are dropped
+/// and cues repeated across segment boundaries are written once.
+///
+/// A segment's `X-TIMESTAMP-MAP` ties its cue times to the stream's MPEG-TS
+/// clock. The video starts at the first segment's mapped time, so cues are
+/// moved by how far their segment's mapping is from that.
+pub fn stitch_webvtt(segments: &str) -> String {
+  let mut output = String::from("WEBVTT\n\n");
+  let mut seen = HashSet::new();
+  let mut first_mpegts = None;
+  let mut shift = 0;
+
+  for mut block in all_blocks(segments) {
+    if block[0].starts_with("WEBVTT") {
+      if let Some((mpegts, local)) = block.iter().find_map(|line| parse_timestamp_map(line)) {
+        let first_mpegts = *first_mpegts.get_or_insert(mpegts);
+        // The MPEG-TS clock is 33 bits wide and wraps around.
+        shift = (mpegts - first_mpegts).rem_euclid(1 << 33) / 90 - local;
+      }
+      continue;
+    }
+    if is_skipped(&block) {
+      continue;
+    }
+
+    // Repeated cues may come with a different identifier.
+    let Some(timing_index) = block.iter().position(|line| line.contains("-->")) else {
+      continue;
+    };
+    if shift != 0 {
+      block[timing_index] = shift_timing(&block[timing_index], shift);
+    }
+    if seen.insert(block[timing_index..].to_vec()) {
+      output.push_str(&block.join("\n"));
+      output.push_str("\n\n");
+    }
+  }
+
+  output
+}
+
+/// Converts a WebVTT document to SubRip. Cue settings, identifiers and styling
+/// other than `<b>`, `<i>` and `<u>` are dropped.
+pub fn webvtt_to_srt(vtt: &str) -> String {
+  let mut output = String::new();
+  let mut number = 0;
+
+  for block in webvtt_blocks(vtt) {
+    let Some(timing_index) = block.iter().position(|line| line.contains("-->")) else {
+      continue;
+    };
+    let Some((start, end)) = block[timing_index].split_once("-->") else {
+      continue;
+    };
+    let end = end.split_whitespace().next().unwrap_or_default();
+
+    number += 1;
+    output.push_str(&format!("{number}\n{} --> {}\n", srt_timestamp(start.trim()), srt_timestamp(end)));
+    for line in &block[timing_index + 1..] {
+      output.push_str(&strip_tags(line));
+      output.push('\n');
+    }
+    output.push('\n');
+  }
+
+  output
+}
+
+/// Splits WebVTT text into blank-line separated blocks, skipping `WEBVTT`
+/// headers and `NOTE`, `STYLE` and `REGION` blocks.
+fn webvtt_blocks(vtt: &str) -> Vec<Vec<String>> {
+  all_blocks(vtt).into_iter().filter(|block| !is_skipped(block)).collect()
+}
+
+/// Splits WebVTT text into its non-empty, blank-line separated blocks.
+fn all_blocks(vtt: &str) -> Vec<Vec<String>> {
+  let mut blocks = vec![];
+  let mut block = Vec::<String>::new();
+
+  for line in vtt.lines().map(|line| line.trim_start_matches('\u{feff}').trim_end()).chain([""]) {
+    if !line.is_empty() {
+      block.push(line.to_string());
+    } else if !block.is_empty() {
+      blocks.push(std::mem::take(&mut block));
+    }
+  }
+
+  blocks
+}
+
+fn is_skipped(block: &[String]) -> bool {
+  block.first().is_some_and(|first| ["WEBVTT", "NOTE", "STYLE", "REGION"].iter().any(|keyword| first.starts_with(keyword)))
+}
+
+/// Reads `X-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000` as the MPEG-TS
+/// time in 90 kHz ticks and the cue time it maps to in milliseconds.
+fn parse_timestamp_map(line: &str) -> Option<(i64, i64)> {
+  let attributes = line.strip_prefix("X-TIMESTAMP-MAP=")?;
+  let (mut mpegts, mut local) = (None, None);
+  for attribute in attributes.split(',') {
+    match attribute.trim().split_once(':') {
+      Some(("MPEGTS", value)) => mpegts = value.parse().ok(),
+      Some(("LOCAL", value)) => local = parse_timestamp(value),
+      _ => {}
+    }
+  }
+  Some((mpegts?, local?))
+}
+
+/// Moves both times of a cue timing line by `shift` milliseconds, keeping its
+/// settings.
+fn shift_timing(line: &str, shift: i64) -> String {
+  let Some((start, rest)) = line.split_once("-->") else {
+    return line.to_string();
+  };
+  let rest = rest.trim_start();
+  let (end, settings) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+  let (Some(start), Some(end)) = (parse_timestamp(start.trim()), parse_timestamp(end)) else {
+    return line.to_string();
+  };
+
+  let timing = format!("{} --> {}", format_timestamp(start + shift), format_timestamp(end + shift));
+  if settings.is_empty() {
+    timing
+  } else {
+    format!("{timing} {settings}")
+  }
+}
+
+/// `mm:ss.ttt` or `hh:mm:ss.ttt` in milliseconds.
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+  let (clock, milliseconds) = timestamp.split_once('.')?;
+  let mut seconds = 0;
+  for part in clock.split(':') {
+    seconds = seconds * 60 + part.parse::<i64>().ok()?;
+  }
+  Some(seconds * 1000 + milliseconds.parse::<i64>().ok()?)
+}
+
+/// Milliseconds as `hh:mm:ss.ttt`; times before the start become zero.
+fn format_timestamp(milliseconds: i64) -> String {
+  let milliseconds = milliseconds.max(0);
+  let seconds = milliseconds / 1000;
+  format!("{:02}:{:02}:{:02}.{:03}", seconds / 3600, seconds / 60 % 60, seconds % 60, milliseconds % 1000)
+}
+
+/// `mm:ss.ttt` or `hh:mm:ss.ttt` to SubRip's `hh:mm:ss,ttt`.
+fn srt_timestamp(timestamp: &str) -> String {
+  let timestamp = timestamp.replace('.', ",");
+  match timestamp.matches(':').count() {
+    1 => format!("00:{timestamp}"),
+    _ => timestamp,
+  }
+}
+
+fn strip_tags(line: &str) -> String {
+  let mut output = String::with_capacity(line.len());
+  let mut rest = line;
+  while let Some(start) = rest.find('<') {
+    output.push_str(&rest[..start]);
+    let Some(length) = rest[start..].find('>') else {
+      rest = &rest[start..];
+      break;
+    };
+
+    let tag = &rest[start..start + length + 1];
+    let name = tag.trim_start_matches(['<', '/']).trim_end_matches('>');
+    if ["b", "i", "u"].contains(&name) {
+      output.push_str(tag);
+    }
+    rest = &rest[start + length + 1..];
+  }
+  output.push_str(rest);
+  output.replace("&amp;", "&").replace("&lt;", "<").replace("&gt;", ">").replace("&nbsp;", "\u{a0}")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SEGMENTS: &str = "WEBVTT\n\
+    X-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\
+    \n\
+    1\n\
+    00:01.000 --> 00:03.500 align:start\n\
+    <c.yellow>Hello</c> <i>there</i>\n\
+    \n\
+    00:05.000 --> 00:07.000\n\
+    Spans the boundary\n\
+    \n\
+    \u{feff}WEBVTT\n\
+    X-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\
+    \n\
+    NOTE repeated from the previous segment\n\
+    \n\
+    00:05.000 --> 00:07.000\n\
+    Spans the boundary\n\
+    \n\
+    01:00:08.250 --> 01:00:09.000\n\
+    Fish &amp; chips\n";
+
+  #[test]
+  fn stitches_segments_without_repeated_headers_or_cues() {
+    let vtt = stitch_webvtt(SEGMENTS);
+
+    assert_eq!(vtt.matches("WEBVTT").count(), 1);
+    assert!(!vtt.contains("X-TIMESTAMP-MAP"));
+    assert_eq!(vtt.matches("Spans the boundary").count(), 1);
+    assert!(vtt.contains("Fish &amp; chips"));
+  }
+
+  #[test]
+  fn keeps_hostile_languages_out_of_file_names() {
+    assert_eq!(file_name(Some("pt-BR")), "subtitles.pt-BR.srt");
+    assert_eq!(file_name(Some("../../../home/bot/x")), "subtitles.srt");
+    assert_eq!(file_name(Some("")), "subtitles.srt");
+    assert_eq!(file_name(None), "subtitles.srt");
+  }
+
+  #[test]
+  fn applies_timestamp_maps_relative_to_the_first_segment() {
+    let segments = "WEBVTT\n\
+      X-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:10.000\n\
+      \n\
+      00:00:11.000 --> 00:00:12.500 line:90%\n\
+      First\n\
+      \n\
+      WEBVTT\n\
+      X-TIMESTAMP-MAP=LOCAL:00:00:00.000,MPEGTS:1350000\n\
+      \n\
+      00:00.000 --> 00:01.000\n\
+      Second\n";
+
+    let vtt = stitch_webvtt(segments);
+
+    assert!(vtt.contains("00:00:01.000 --> 00:00:02.500 line:90%\nFirst"));
+    assert!(vtt.contains("00:00:05.000 --> 00:00:06.000\nSecond"));
+  }
+
+  #[test]
+  fn converts_webvtt_to_srt() {
+    let srt = webvtt_to_srt(&stitch_webvtt(SEGMENTS));
+
+    assert_eq!(
+      srt,
+      "1\n00:00:01,000 --> 00:00:03,500\nHello <i>there</i>\n\n\
+       2\n00:00:05,000 --> 00:00:07,000\nSpans the boundary\n\n\
+       3\n01:00:08,250 --> 01:00:09,000\nFish & chips\n\n"
+    );
+  }
+}
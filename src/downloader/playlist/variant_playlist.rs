@@ -1,68 +1,85 @@
 use futures::future::join_all;
-use regex::Regex;
-use std::collections::HashMap;
+use reqwest::header::HeaderMap;
 
-use crate::downloader::{downloader_error::DownloaderError, playlist::master_playlist::MasterPlaylist};
+use crate::downloader::{
+  downloader_error::DownloaderError,
+  http,
+  playlist::{
+    hls::{resolve_uri, MediaType, VariantManifest},
+    master_playlist::{AudioRendition, MasterPlaylist, MediaSource, VariantInfo},
+    subtitles::SubtitleTrack,
+  },
+};
 
 pub struct VariantPlaylist {
   pub master_playlists: Vec<MasterPlaylist>,
+  /// Distinct audio tracks across all variants, e.g. one per language.
+  pub audio_tracks: Vec<AudioRendition>,
+  pub subtitles: Vec<SubtitleTrack>,
 }
 
 impl VariantPlaylist {
-  pub async fn from_url(url: &str) -> Result<Self, DownloaderError> {
-    const BASE_URL: &str = "https://video.twimg.com";
+  /// Fetches and parses the variant playlist. `headers` are sent with every
+  /// request made for it, including media playlists, segments and keys.
+  pub async fn from_url(url: &str, headers: HeaderMap) -> Result<Self, DownloaderError> {
+    let response = http::client()
+      .get(url)
+      .headers(headers.clone())
+      .send()
+      .await
+      .map_err(|_| DownloaderError::FetchError)?
+      .text()
+      .await
+      .map_err(|_| DownloaderError::FetchError)?;
+    let manifest = VariantManifest::parse(&response)?;
 
-    let response =
-      reqwest::get(url).await.map_err(|_| DownloaderError::FetchError)?.text().await.map_err(|_| DownloaderError::FetchError)?;
-
-    //audio pass
-    const AUDIO_REGEX_STR: &str = r#"(?m)^#EXT-X-MEDIA:NAME="Audio".*GROUP-ID="(?P<audio_tag>audio-\d*)".*URI="(?P<audio_url>.*)""#;
+    let mut variants = manifest.variants.iter().filter(|variant| variant.resolution.is_some()).collect::<Vec<_>>();
+    //sorting by resolution descending
+    variants.sort_by_key(|variant| std::cmp::Reverse(variant.resolution.map(|resolution| resolution.pixels())));
 
-    let mut audio_map = HashMap::<String, String>::new();
-    let audio_pass = Regex::new(&AUDIO_REGEX_STR).unwrap();
-    for capture in audio_pass.captures_iter(&response) {
-      let Some(tag) = capture.name("audio_tag") else {
+    let mut subtitles = Vec::<SubtitleTrack>::new();
+    for media in manifest.media.iter().filter(|media| media.media_type == MediaType::Subtitles) {
+      let Some(uri) = &media.uri else {
         continue;
       };
-      let Some(url) = capture.name("audio_url") else {
-        continue;
-      };
-      audio_map.insert(tag.as_str().to_string(), url.as_str().to_string());
+      let url = resolve_uri(url, uri)?;
+      // Variants usually share one subtitle group, but each may list its own copy.
+      if !subtitles.iter().any(|track| track.media.name == media.name && track.media.language == media.language) {
+        subtitles.push(SubtitleTrack::new(media.clone(), url, headers.clone()));
+      }
     }
 
-    //video pass
-    let contains_audio = audio_map.len() > 0;
-    let video_regex_str: &str = if contains_audio {
-      r#"(?m)^#EXT-X-STREAM-INF.*RESOLUTION=(?P<video_resolution>\d*x\d*).*AUDIO="(?P<audio_match_tag>audio-\d*)"\n(?P<video_url>.*)"#
-    } else {
-      r#"(?m)^#EXT-X-STREAM-INF.*RESOLUTION=(?P<video_resolution>\d*x\d*).*\n(?P<video_url>.*)"#
-    };
-
+    let mut audio_tracks = vec![];
     let mut tasks = vec![];
-    let video_pass = Regex::new(video_regex_str).unwrap();
-    for capture in video_pass.captures_iter(&response) {
-      let Some(resolution) = capture.name("video_resolution") else {
-        continue;
-      };
-      let Some(video_url) = capture.name("video_url") else {
-        continue;
-      };
-      let audio_url = if contains_audio {
-        capture.name("audio_match_tag").and_then(|tag| audio_map.get(tag.as_str()).cloned())
-      } else {
-        None
-      };
+    for variant in variants {
+      let mut audio_renditions = vec![];
+      if let Some(group_id) = variant.audio.as_deref() {
+        // Renditions without a URI are muxed into the variant stream itself.
+        for media in manifest.group(MediaType::Audio, group_id) {
+          if let Some(uri) = &media.uri {
+            audio_renditions.push(AudioRendition::new(media.clone(), MediaSource::Playlist(resolve_uri(url, uri)?)));
+          }
+        }
+      }
+      for rendition in &audio_renditions {
+        if !audio_tracks.iter().any(|track: &AudioRendition| track.same_track(rendition)) {
+          audio_tracks.push(rendition.clone());
+        }
+      }
 
-      let full_video_url = format!("{BASE_URL}{}", video_url.as_str());
-      let full_audio_url = audio_url.map(|url| format!("{BASE_URL}{}", url));
-      let resolution_string = resolution.as_str().to_string();
+      let video_source = MediaSource::Playlist(resolve_uri(url, &variant.uri)?);
+      let info = VariantInfo {
+        resolution: variant.resolution.unwrap_or_default(),
+        bandwidth: variant.bandwidth,
+        average_bandwidth: variant.average_bandwidth,
+        codecs: variant.codecs.clone(),
+        frame_rate: variant.frame_rate,
+      };
+      let headers = headers.clone();
 
       tasks.push(tokio::spawn(async move {
-        match MasterPlaylist::from_urls(full_video_url, full_audio_url).await {
-          Ok(mut master_playlist) => {
-            master_playlist.resolution = resolution_string;
-            Ok(master_playlist)
-          }
+        match MasterPlaylist::from_sources(info, video_source, audio_renditions, headers).await {
+          Ok(master_playlist) => Ok(master_playlist),
           Err(e) => {
             println!("Error: {}", e);
             Err(())
@@ -80,13 +97,75 @@ impl VariantPlaylist {
       }
     }
 
-    //sorting by resolution descending
-    master_playlists.sort_by(|a, b| {
-      let a_product: i32 = a.resolution.split('x').map(|s| s.parse::<i32>().unwrap()).product();
-      let b_product: i32 = b.resolution.split('x').map(|s| s.parse::<i32>().unwrap()).product();
-      b_product.cmp(&a_product)
-    });
+    Ok(VariantPlaylist { master_playlists, audio_tracks, subtitles })
+  }
+
+  /// Index of the highest quality whose estimated size is at most `limit`
+  /// bytes. Qualities of unknown size are never picked.
+  pub fn best_fitting(&self, limit: u64) -> Option<usize> {
+    self.master_playlists.iter().position(|master_playlist| master_playlist.estimated_size().is_some_and(|size| size <= limit))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::downloader::test_server::{Reply, TestServer};
+
+  const MULTI_LANGUAGE: &str = "#EXTM3U\n\
+    #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"lo\",NAME=\"English\",LANGUAGE=\"en\",DEFAULT=YES,URI=\"lo/en.m3u8\"\n\
+    #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"lo\",NAME=\"Deutsch\",LANGUAGE=\"de\",URI=\"lo/de.m3u8\"\n\
+    #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"hi\",NAME=\"English\",LANGUAGE=\"en\",DEFAULT=YES,URI=\"hi/en.m3u8\"\n\
+    #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"hi\",NAME=\"Deutsch\",LANGUAGE=\"de\",URI=\"hi/de.m3u8\"\n\
+    #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"hi\",NAME=\"English (described)\",LANGUAGE=\"en\",URI=\"hi/ad.m3u8\"\n\
+    #EXT-X-STREAM-INF:BANDWIDTH=500000,RESOLUTION=640x360,AUDIO=\"lo\"\n\
+    360.m3u8\n\
+    #EXT-X-STREAM-INF:BANDWIDTH=2000000,RESOLUTION=1280x720,AUDIO=\"hi\"\n\
+    720.m3u8\n";
+
+  #[tokio::test]
+  async fn keeps_every_audio_rendition() {
+    let server = TestServer::start().await;
+    server.route("/master.m3u8", vec![Reply::Ok(MULTI_LANGUAGE.into())]);
+
+    let variant_playlist = VariantPlaylist::from_url(&server.url("/master.m3u8"), HeaderMap::new()).await.unwrap();
+
+    let labels = variant_playlist.audio_tracks.iter().map(|track| track.media.label()).collect::<Vec<_>>();
+    assert_eq!(labels, ["English (en)", "Deutsch (de)", "English (described) (en)"]);
+
+    let [high, low] = &variant_playlist.master_playlists[..] else { panic!("expected two variants") };
+    assert_eq!(high.audio_renditions.len(), 3);
+    assert_eq!(low.audio_renditions.len(), 2);
+
+    let german = &variant_playlist.audio_tracks[1];
+    assert_eq!(low.audio_rendition(Some(german)).unwrap().media.name, "Deutsch");
+    assert!(low.audio_rendition(None).unwrap().media.default);
+    // The described track only exists in the high group; the low one falls back to its default.
+    assert_eq!(low.audio_rendition(Some(&variant_playlist.audio_tracks[2])).unwrap().media.name, "English");
+  }
+
+  #[tokio::test]
+  async fn picks_best_quality_within_size_limit() {
+    let server = TestServer::start().await;
+    let master = "#EXTM3U\n\
+      #EXT-X-STREAM-INF:BANDWIDTH=8000000,RESOLUTION=1920x1080\n\
+      1080.m3u8\n\
+      #EXT-X-STREAM-INF:BANDWIDTH=4000000,AVERAGE-BANDWIDTH=2000000,RESOLUTION=1280x720\n\
+      720.m3u8\n\
+      #EXT-X-STREAM-INF:BANDWIDTH=1000000,RESOLUTION=640x360\n\
+      360.m3u8\n";
+    // 100 seconds: 100 MB, 25 MB and 12.5 MB.
+    let media = "#EXTM3U\n#EXT-X-TARGETDURATION:50\n#EXTINF:50,\na.ts\n#EXTINF:50,\nb.ts\n#EXT-X-ENDLIST\n";
+    server.route("/master.m3u8", vec![Reply::Ok(master.into())]);
+    for path in ["/1080.m3u8", "/720.m3u8", "/360.m3u8"] {
+      server.route(path, vec![Reply::Ok(media.into())]);
+    }
+
+    let variant_playlist = VariantPlaylist::from_url(&server.url("/master.m3u8"), HeaderMap::new()).await.unwrap();
 
-    Ok(VariantPlaylist { master_playlists: master_playlists })
+    assert_eq!(variant_playlist.best_fitting(200_000_000), Some(0));
+    assert_eq!(variant_playlist.best_fitting(50_000_000), Some(1));
+    assert_eq!(variant_playlist.best_fitting(20_000_000), Some(2));
+    assert_eq!(variant_playlist.best_fitting(1_000_000), None);
   }
 }
@@ -0,0 +1,117 @@
+//! Progress of a running job, for the status message.
+//!
+//! Work is counted in units of the current stage: segments of a playlist,
+//! bytes of a direct download. Stages whose work cannot be counted, such as
+//! ffmpeg runs, set the share done directly. Updates go out through a `watch`
+//! channel, so a slow reader only ever sees the latest state.
+
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+  Downloading,
+  Merging,
+  Reencoding,
+  Splitting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+  pub stage: Stage,
+  /// Share of the stage done, when its total is known.
+  pub fraction: Option<f64>,
+  /// Bytes downloaded by the job so far.
+  pub bytes: u64,
+}
+
+/// Reports the progress of one job. The default reporter discards everything.
+#[derive(Clone, Default)]
+pub struct ProgressReporter {
+  shared: Option<Arc<Shared>>,
+}
+
+struct Shared {
+  sender: watch::Sender<Progress>,
+  counts: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+  done: u64,
+  total: u64,
+}
+
+impl ProgressReporter {
+  pub fn new() -> (Self, watch::Receiver<Progress>) {
+    let (sender, receiver) = watch::channel(Progress { stage: Stage::Downloading, fraction: None, bytes: 0 });
+    (ProgressReporter { shared: Some(Arc::new(Shared { sender, counts: Mutex::default() })) }, receiver)
+  }
+
+  /// Moves on to `stage` with nothing of it done.
+  pub fn start(&self, stage: Stage) {
+    self.update(|counts, progress| {
+      *counts = Counts::default();
+      progress.stage = stage;
+      progress.fraction = None;
+    });
+  }
+
+  /// Adds `units` to the work of the current stage.
+  pub fn expect(&self, units: u64) {
+    self.update(|counts, progress| {
+      counts.total += units;
+      progress.fraction = counts.fraction();
+    });
+  }
+
+  /// Marks `units` of the current stage done, which took `bytes` of download.
+  pub fn advance(&self, units: u64, bytes: u64) {
+    self.update(|counts, progress| {
+      counts.done += units;
+      progress.fraction = counts.fraction();
+      progress.bytes += bytes;
+    });
+  }
+
+  /// Sets the share of the current stage done directly.
+  pub fn set_fraction(&self, fraction: f64) {
+    self.update(|_, progress| progress.fraction = Some(fraction.clamp(0.0, 1.0)));
+  }
+
+  fn update(&self, f: impl FnOnce(&mut Counts, &mut Progress)) {
+    if let Some(shared) = &self.shared {
+      let mut counts = shared.counts.lock().unwrap();
+      shared.sender.send_modify(|progress| f(&mut counts, progress));
+    }
+  }
+}
+
+impl Counts {
+  fn fraction(&self) -> Option<f64> {
+    (self.total > 0).then(|| (self.done as f64 / self.total as f64).min(1.0))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_work_per_stage_and_bytes_per_job() {
+    let (reporter, progress) = ProgressReporter::new();
+    reporter.expect(4);
+    reporter.advance(1, 1000);
+    assert_eq!(*progress.borrow(), Progress { stage: Stage::Downloading, fraction: Some(0.25), bytes: 1000 });
+
+    // A second track adds its segments to the same stage.
+    reporter.expect(4);
+    reporter.advance(3, 3000);
+    assert_eq!(progress.borrow().fraction, Some(0.5));
+
+    reporter.start(Stage::Merging);
+    assert_eq!(*progress.borrow(), Progress { stage: Stage::Merging, fraction: None, bytes: 4000 });
+    reporter.set_fraction(1.5);
+    assert_eq!(progress.borrow().fraction, Some(1.0));
+  }
+}
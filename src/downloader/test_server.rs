@@ -0,0 +1,135 @@
+//! Minimal HTTP/1.1 stand-in for CDN endpoints, used by tests.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Barrier;
+
+#[derive(Clone)]
+pub enum Reply {
+  /// Answers with the body, or with `206` and the requested slice of it.
+  Ok(Vec<u8>),
+  /// Waits until every party of the barrier has arrived, then answers like
+  /// `Ok`.
+  Gated(Arc<Barrier>, Vec<u8>),
+  Status(u16),
+  /// Closes the connection without sending a response.
+  Drop,
+  /// Sends the headers and the start of the body, then keeps the connection
+  /// open without sending the rest.
+  Stall(Vec<u8>),
+}
+
+#[derive(Default)]
+struct Routes {
+  replies: HashMap<String, Vec<Reply>>,
+  hits: HashMap<String, usize>,
+  /// Header lines of the most recent request per path, lowercased.
+  headers: HashMap<String, Vec<String>>,
+}
+
+pub struct TestServer {
+  addr: std::net::SocketAddr,
+  routes: Arc<Mutex<Routes>>,
+}
+
+impl TestServer {
+  pub async fn start() -> Self {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let routes = Arc::new(Mutex::new(Routes::default()));
+
+    let routes_clone = routes.clone();
+    tokio::spawn(async move {
+      while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(handle_connection(stream, routes_clone.clone()));
+      }
+    });
+
+    TestServer { addr, routes }
+  }
+
+  pub fn url(&self, path: &str) -> String {
+    format!("http://{}{}", self.addr, path)
+  }
+
+  /// Serves `replies` in order for `path`; the last one is repeated forever.
+  pub fn route(&self, path: &str, replies: Vec<Reply>) {
+    self.routes.lock().unwrap().replies.insert(path.to_string(), replies);
+  }
+
+  /// Scratch file path that is unique to this server instance.
+  pub fn temp_path(&self, name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("vid-downloader-test-{}-{name}", self.addr.port()))
+  }
+
+  pub fn hits(&self, path: &str) -> usize {
+    self.routes.lock().unwrap().hits.get(path).copied().unwrap_or(0)
+  }
+
+  /// Value of `name` in the last request made for `path`.
+  pub fn last_header(&self, path: &str, name: &str) -> Option<String> {
+    let prefix = format!("{}:", name.to_lowercase());
+    let routes = self.routes.lock().unwrap();
+    let line = routes.headers.get(path)?.iter().find(|line| line.to_lowercase().starts_with(&prefix))?;
+    Some(line[prefix.len()..].trim().to_string())
+  }
+}
+
+async fn handle_connection(mut stream: TcpStream, routes: Arc<Mutex<Routes>>) {
+  let mut request = Vec::new();
+  let mut buffer = [0u8; 1024];
+  while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+    match stream.read(&mut buffer).await {
+      Ok(0) | Err(_) => return,
+      Ok(n) => request.extend_from_slice(&buffer[..n]),
+    }
+  }
+
+  let request = String::from_utf8_lossy(&request);
+  let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+  let reply = {
+    let mut routes = routes.lock().unwrap();
+    *routes.hits.entry(path.clone()).or_default() += 1;
+    routes.headers.insert(path.clone(), request.lines().skip(1).take_while(|line| !line.is_empty()).map(str::to_string).collect());
+    match routes.replies.get_mut(&path) {
+      Some(replies) if replies.len() > 1 => replies.remove(0),
+      Some(replies) => replies.first().cloned().unwrap_or(Reply::Status(404)),
+      None => Reply::Status(404),
+    }
+  };
+
+  let reply = match reply {
+    Reply::Gated(barrier, body) => {
+      barrier.wait().await;
+      Reply::Ok(body)
+    }
+    reply => reply,
+  };
+
+  let range = request.lines().find_map(|line| line.to_lowercase().strip_prefix("range: bytes=").map(str::to_string));
+  let (status, body) = match (reply, range) {
+    (Reply::Ok(body), Some(range)) => {
+      let (first, last) = range.split_once('-').unwrap();
+      let (first, last) = (first.parse::<usize>().unwrap(), last.parse::<usize>().unwrap());
+      (206, body[first..=last.min(body.len() - 1)].to_vec())
+    }
+    (Reply::Ok(body), None) => (200, body),
+    (Reply::Status(status), _) => (status, vec![]),
+    (Reply::Stall(body), _) => {
+      let head = format!("HTTP/1.1 200 Test\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len() + 1);
+      let _ = stream.write_all(head.as_bytes()).await;
+      let _ = stream.write_all(&body).await;
+      tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+      return;
+    }
+    (Reply::Drop, _) | (Reply::Gated(..), _) => return,
+  };
+
+  let head = format!("HTTP/1.1 {status} Test\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+  let _ = stream.write_all(head.as_bytes()).await;
+  let _ = stream.write_all(&body).await;
+  let _ = stream.shutdown().await;
+}
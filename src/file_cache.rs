@@ -0,0 +1,121 @@
+//! Telegram `file_id`s of videos the bot has already uploaded.
+//!
+//! Entries are keyed by the platform's media ID and the chosen quality, so the
+//! same tweet or TikTok posted in any chat is answered by resending the stored
+//! files. Entries expire after `FILE_CACHE_TTL` and the oldest are evicted past
+//! `FILE_CACHE_MAX_ENTRIES`.
+
+use rusqlite::{params, OptionalExtension};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::db::{now, Database};
+
+pub struct FileCache {
+  database: Database,
+  ttl: Duration,
+  max_entries: usize,
+}
+
+impl FileCache {
+  pub fn new(database: Database, ttl: Duration, max_entries: usize) -> rusqlite::Result<Self> {
+    database.lock().execute_batch(
+      "CREATE TABLE IF NOT EXISTS file_cache (
+        media_id TEXT NOT NULL,
+        quality TEXT NOT NULL,
+        file_ids TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (media_id, quality)
+      );
+      CREATE INDEX IF NOT EXISTS file_cache_created_at ON file_cache (created_at);",
+    )?;
+    Ok(FileCache { database, ttl, max_entries })
+  }
+
+  /// The `file_id`s of the video's parts, in order, if they are cached and
+  /// have not expired.
+  pub fn get(&self, media_id: &str, quality: &str) -> Option<Vec<String>> {
+    self.get_at(media_id, quality, now())
+  }
+
+  /// Stores the `file_id`s of an uploaded video, replacing any earlier entry.
+  pub fn insert(&self, media_id: &str, quality: &str, file_ids: &[String]) {
+    self.insert_at(media_id, quality, file_ids, now());
+  }
+
+  /// Forgets an entry whose `file_id`s Telegram no longer accepts.
+  pub fn remove(&self, media_id: &str, quality: &str) {
+    let connection = self.database.lock();
+    if let Err(e) = connection.execute("DELETE FROM file_cache WHERE media_id = ?1 AND quality = ?2", params![media_id, quality]) {
+      warn!("Failed to remove cached file: {e}");
+    }
+  }
+
+  fn get_at(&self, media_id: &str, quality: &str, now: u64) -> Option<Vec<String>> {
+    let connection = self.database.lock();
+    let file_ids = connection
+      .query_row(
+        "SELECT file_ids FROM file_cache WHERE media_id = ?1 AND quality = ?2 AND created_at > ?3",
+        params![media_id, quality, now.saturating_sub(self.ttl.as_secs())],
+        |row| row.get::<_, String>(0),
+      )
+      .optional()
+      .unwrap_or_else(|e| {
+        warn!("Failed to read file cache: {e}");
+        None
+      })?;
+    Some(file_ids.lines().map(str::to_string).collect())
+  }
+
+  fn insert_at(&self, media_id: &str, quality: &str, file_ids: &[String], now: u64) {
+    let connection = self.database.lock();
+    let result = connection
+      .execute(
+        "INSERT OR REPLACE INTO file_cache (media_id, quality, file_ids, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![media_id, quality, file_ids.join("\n"), now],
+      )
+      .and_then(|_| connection.execute("DELETE FROM file_cache WHERE created_at <= ?1", [now.saturating_sub(self.ttl.as_secs())]))
+      .and_then(|_| {
+        connection.execute(
+          "DELETE FROM file_cache WHERE rowid NOT IN (SELECT rowid FROM file_cache ORDER BY created_at DESC, rowid DESC LIMIT ?1)",
+          [self.max_entries as u64],
+        )
+      });
+    if let Err(e) = result {
+      warn!("Failed to write file cache: {e}");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cache(max_entries: usize) -> FileCache {
+    FileCache::new(Database::open_in_memory().unwrap(), Duration::from_secs(100), max_entries).unwrap()
+  }
+
+  #[test]
+  fn returns_file_ids_until_they_expire() {
+    let cache = cache(10);
+    cache.insert_at("twitter:1", "1280x720", &["part-1".to_string(), "part-2".to_string()], 1000);
+
+    assert_eq!(cache.get_at("twitter:1", "1280x720", 1099), Some(vec!["part-1".to_string(), "part-2".to_string()]));
+    assert_eq!(cache.get_at("twitter:1", "640x360", 1099), None);
+    assert_eq!(cache.get_at("twitter:1", "1280x720", 1100), None);
+  }
+
+  #[test]
+  fn evicts_oldest_entries_beyond_the_limit() {
+    let cache = cache(2);
+    for (i, media_id) in ["tiktok:1", "tiktok:2", "tiktok:3"].into_iter().enumerate() {
+      cache.insert_at(media_id, "original", &[format!("file-{i}")], 1000 + i as u64);
+    }
+
+    assert_eq!(cache.get_at("tiktok:1", "original", 1010), None);
+    assert_eq!(cache.get_at("tiktok:3", "original", 1010), Some(vec!["file-2".to_string()]));
+
+    cache.remove("tiktok:3", "original");
+    assert_eq!(cache.get_at("tiktok:3", "original", 1010), None);
+  }
+}
@@ -0,0 +1,266 @@
+//! Queue for download jobs, and for resolving links, which opens browser tabs
+//! as well.
+//!
+//! At most `workers` jobs run at once, and at most `per_user` and `per_chat`
+//! of them for any one user or chat. Waiting jobs start in submission order,
+//! except that admin jobs go ahead of everyone else's; a job whose user or
+//! chat is at its limit is skipped until one of theirs finishes. Every job
+//! reports its place in the queue through a `watch` channel, `0` once it runs;
+//! a job withdrawn before it runs closes the channel instead.
+
+use std::{
+  collections::HashMap,
+  future::Future,
+  pin::Pin,
+  sync::{Arc, Mutex},
+};
+use teloxide::types::{ChatId, UserId};
+use tokio::sync::watch;
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+  pub workers: usize,
+  pub per_user: usize,
+  pub per_chat: usize,
+}
+
+/// Identifies a submitted job, so that it can be withdrawn while it waits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobId(u64);
+
+/// Who a job is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+  pub user_id: Option<UserId>,
+  pub chat_id: ChatId,
+}
+
+#[derive(Clone)]
+pub struct JobQueue {
+  inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+  limits: Limits,
+  /// Admin jobs first, otherwise in submission order.
+  waiting: Vec<Waiting>,
+  running: usize,
+  running_by_user: HashMap<UserId, usize>,
+  running_by_chat: HashMap<ChatId, usize>,
+  next_id: u64,
+}
+
+struct Waiting {
+  id: JobId,
+  owner: Owner,
+  admin: bool,
+  job: Job,
+  position: watch::Sender<usize>,
+}
+
+impl JobQueue {
+  pub fn new(limits: Limits) -> Self {
+    let limits = Limits { workers: limits.workers.max(1), per_user: limits.per_user.max(1), per_chat: limits.per_chat.max(1) };
+    let inner = Inner { limits, waiting: vec![], running: 0, running_by_user: HashMap::new(), running_by_chat: HashMap::new(), next_id: 0 };
+    JobQueue { inner: Arc::new(Mutex::new(inner)) }
+  }
+
+  /// Queues `job` and returns its ID and its place in the queue, starting at
+  /// 1, which drops to 0 when the job starts.
+  pub fn submit(&self, owner: Owner, admin: bool, job: impl Future<Output = ()> + Send + 'static) -> (JobId, watch::Receiver<usize>) {
+    let (position, receiver) = watch::channel(usize::MAX);
+    let id = {
+      let mut inner = self.inner.lock().unwrap();
+      let id = JobId(inner.next_id);
+      inner.next_id += 1;
+      let index = if admin { inner.waiting.iter().position(|waiting| !waiting.admin).unwrap_or(inner.waiting.len()) } else { inner.waiting.len() };
+      inner.waiting.insert(index, Waiting { id, owner, admin, job: Box::pin(job), position });
+      id
+    };
+    self.dispatch();
+    (id, receiver)
+  }
+
+  /// Drops the job `id` if it has not started yet and moves up the jobs behind
+  /// it. Returns `false` if it has already started.
+  pub fn withdraw(&self, id: JobId) -> bool {
+    let waiting = {
+      let mut inner = self.inner.lock().unwrap();
+      let Some(index) = inner.waiting.iter().position(|waiting| waiting.id == id) else {
+        return false;
+      };
+      inner.waiting.remove(index)
+    };
+    drop(waiting);
+    self.dispatch();
+    true
+  }
+
+  /// Starts every waiting job that fits the limits and renumbers the rest.
+  fn dispatch(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    let mut index = 0;
+    while index < inner.waiting.len() && inner.running < inner.limits.workers {
+      if !inner.has_room_for(&inner.waiting[index].owner) {
+        index += 1;
+        continue;
+      }
+
+      let waiting = inner.waiting.remove(index);
+      inner.started(&waiting.owner);
+      waiting.position.send_replace(0);
+      let finished = Finished { queue: self.clone(), owner: waiting.owner };
+      tokio::spawn(async move {
+        waiting.job.await;
+        drop(finished);
+      });
+    }
+
+    for (index, waiting) in inner.waiting.iter().enumerate() {
+      waiting.position.send_if_modified(|position| std::mem::replace(position, index + 1) != index + 1);
+    }
+  }
+}
+
+impl Inner {
+  fn has_room_for(&self, owner: &Owner) -> bool {
+    let user_jobs = owner.user_id.and_then(|user_id| self.running_by_user.get(&user_id)).copied().unwrap_or(0);
+    let chat_jobs = self.running_by_chat.get(&owner.chat_id).copied().unwrap_or(0);
+    user_jobs < self.limits.per_user && chat_jobs < self.limits.per_chat
+  }
+
+  fn started(&mut self, owner: &Owner) {
+    self.running += 1;
+    if let Some(user_id) = owner.user_id {
+      *self.running_by_user.entry(user_id).or_default() += 1;
+    }
+    *self.running_by_chat.entry(owner.chat_id).or_default() += 1;
+  }
+
+  fn finished(&mut self, owner: &Owner) {
+    self.running -= 1;
+    if let Some(user_id) = owner.user_id {
+      decrement(&mut self.running_by_user, user_id);
+    }
+    decrement(&mut self.running_by_chat, owner.chat_id);
+  }
+}
+
+fn decrement<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: K) {
+  if let Some(count) = counts.get_mut(&key) {
+    *count -= 1;
+    if *count == 0 {
+      counts.remove(&key);
+    }
+  }
+}
+
+/// Frees the job's slot when dropped, which also happens when the job panics.
+struct Finished {
+  queue: JobQueue,
+  owner: Owner,
+}
+
+impl Drop for Finished {
+  fn drop(&mut self) {
+    self.queue.inner.lock().unwrap().finished(&self.owner);
+    self.queue.dispatch();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::sync::{mpsc, oneshot};
+
+  fn owner(user: u64, chat: i64) -> Owner {
+    Owner { user_id: Some(UserId(user)), chat_id: ChatId(chat) }
+  }
+
+  /// A job that reports `name` when it starts and then waits to be released.
+  fn job(name: &'static str, started: &mpsc::UnboundedSender<&'static str>) -> (impl Future<Output = ()>, oneshot::Sender<()>) {
+    let (release, released) = oneshot::channel();
+    let started = started.clone();
+    (
+      async move {
+        started.send(name).unwrap();
+        let _ = released.await;
+      },
+      release,
+    )
+  }
+
+  #[tokio::test]
+  async fn runs_admin_jobs_first_and_reports_positions() {
+    let queue = JobQueue::new(Limits { workers: 1, per_user: 5, per_chat: 5 });
+    let (started, mut starts) = mpsc::unbounded_channel();
+
+    let (first, release_first) = job("first", &started);
+    let (_, first_position) = queue.submit(owner(1, 1), false, first);
+    let (user, release_user) = job("user", &started);
+    let (_, user_position) = queue.submit(owner(2, 2), false, user);
+    let (admin, release_admin) = job("admin", &started);
+    let (_, admin_position) = queue.submit(owner(3, 3), true, admin);
+
+    assert_eq!(starts.recv().await, Some("first"));
+    assert_eq!((*first_position.borrow(), *admin_position.borrow(), *user_position.borrow()), (0, 1, 2));
+
+    release_first.send(()).unwrap();
+    assert_eq!(starts.recv().await, Some("admin"));
+    assert_eq!(*user_position.borrow(), 1);
+
+    release_admin.send(()).unwrap();
+    assert_eq!(starts.recv().await, Some("user"));
+    assert_eq!(*user_position.borrow(), 0);
+    release_user.send(()).unwrap();
+  }
+
+  #[tokio::test]
+  async fn holds_back_users_and_chats_at_their_limit() {
+    let queue = JobQueue::new(Limits { workers: 3, per_user: 1, per_chat: 2 });
+    let (started, mut starts) = mpsc::unbounded_channel();
+
+    let (first, release_first) = job("first", &started);
+    queue.submit(owner(1, 1), false, first);
+    let (same_user, _release_same_user) = job("same user", &started);
+    let (_, same_user_position) = queue.submit(owner(1, 2), false, same_user);
+    let (same_chat, _release_same_chat) = job("same chat", &started);
+    queue.submit(owner(2, 1), false, same_chat);
+    let (full_chat, _release_full_chat) = job("full chat", &started);
+    let (_, full_chat_position) = queue.submit(owner(3, 1), false, full_chat);
+
+    assert_eq!(starts.recv().await, Some("first"));
+    assert_eq!(starts.recv().await, Some("same chat"));
+    assert_eq!((*same_user_position.borrow(), *full_chat_position.borrow()), (1, 2));
+
+    drop(release_first);
+    assert_eq!(starts.recv().await, Some("same user"));
+    assert_eq!(starts.recv().await, Some("full chat"));
+  }
+
+  #[tokio::test]
+  async fn withdraws_only_waiting_jobs() {
+    let queue = JobQueue::new(Limits { workers: 1, per_user: 5, per_chat: 5 });
+    let (started, mut starts) = mpsc::unbounded_channel();
+
+    let (first, release_first) = job("first", &started);
+    let (first_id, _) = queue.submit(owner(1, 1), false, first);
+    let (withdrawn, _release_withdrawn) = job("withdrawn", &started);
+    let (withdrawn_id, mut withdrawn_position) = queue.submit(owner(2, 2), false, withdrawn);
+    let (last, release_last) = job("last", &started);
+    let (_, last_position) = queue.submit(owner(3, 3), false, last);
+
+    assert_eq!(starts.recv().await, Some("first"));
+    assert_eq!(*withdrawn_position.borrow_and_update(), 1);
+    assert!(!queue.withdraw(first_id));
+    assert!(queue.withdraw(withdrawn_id));
+    assert!(withdrawn_position.changed().await.is_err());
+    assert_eq!(*last_position.borrow(), 1);
+
+    release_first.send(()).unwrap();
+    assert_eq!(starts.recv().await, Some("last"));
+    release_last.send(()).unwrap();
+  }
+}
@@ -1,30 +1,171 @@
+mod config;
+mod db;
 mod downloader;
+mod file_cache;
+mod job_queue;
+mod pending_store;
+mod settings_store;
 
+use headless_chrome::Browser;
 use std::sync::Arc;
-use std::{collections::HashMap, time::Duration};
-use tokio::sync::RwLock;
+use std::{
+  collections::{HashMap, HashSet},
+  future::Future,
+  path::{Path, PathBuf},
+  time::{Duration, Instant}
+};
+use tokio::{
+  sync::{oneshot, watch, RwLock},
+  task::JoinHandle
+};
 
+use db::Database;
+use file_cache::FileCache;
+use job_queue::{JobId, JobQueue, Limits, Owner};
+use pending_store::PendingStore;
+use settings_store::SettingsStore;
 use downloader::{
   downloader::PlatformDownloader,
-  platforms::{tiktok::TiktokDownloader, twitter::TwitterDownloader},
-  playlist::variant_playlist::VariantPlaylist,
+  downloader_error::DownloaderError,
+  ffmpeg,
+  job_dir::{self, JobDir},
+  oversize::{self, OversizeStrategy},
+  platforms::{dash::DashDownloader, tiktok::TiktokDownloader, twitter::TwitterDownloader},
+  playlist::{master_playlist::format_size, subtitles, variant_playlist::VariantPlaylist},
+  progress::{Progress, ProgressReporter, Stage},
   Downloader
 };
 use teloxide::{
   dispatching::dialogue::GetChatId,
   prelude::*,
+  RequestError,
   types::{
-    InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaVideo, MediaKind::*, MessageEntityKind::*, MessageId,
-    MessageKind::*
-  },
-  RequestError
+    InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaVideo, MediaKind::*, MessageEntity, MessageEntityKind::*,
+    MessageEntityRef, MessageId, MessageKind::*, UserId
+  }
 };
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::{self, fmt::format::FmtSpan};
 
+/// File cache quality of videos picked by auto quality mode.
+const AUTO_QUALITY: &str = "auto";
+/// File cache quality of platforms that offer a single file.
+const ORIGINAL_QUALITY: &str = "original";
+/// Callback data of the button that cancels a running job.
+const CANCEL: &str = "cancel";
+/// Time between progress edits of a status message, which keeps a busy group
+/// under Telegram's limit of 20 messages a minute.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(4);
+
 struct State {
-  downloader: Downloader,
-  variants: HashMap<(ChatId, MessageId), VariantPlaylist>
+  /// Resolved playlists by chat and the message with their keyboard.
+  pending: HashMap<(ChatId, MessageId), PendingDownload>,
+  /// Keyboards still waiting for a tap, which outlive `pending` across restarts.
+  pending_store: PendingStore,
+  /// Selections taken out of `pending` that are queued or being downloaded;
+  /// further taps on their keyboards are ignored.
+  active: HashSet<(ChatId, MessageId)>,
+  jobs: JobQueue,
+  /// Queued and running jobs by chat and status message, with the user who may
+  /// cancel them.
+  running: HashMap<(ChatId, MessageId), RunningJob>,
+  /// Quality mode chosen with `/quality`, where `true` picks the largest
+  /// quality that fits the upload limit instead of asking, and the strategy
+  /// chosen with `/oversize` for videos over the upload limit.
+  settings: SettingsStore,
+  file_cache: Option<FileCache>
+}
+
+impl State {
+  fn auto_quality(&self, user_id: Option<UserId>) -> bool {
+    user_id.and_then(|user_id| self.settings.auto_quality(user_id)).unwrap_or(config::get().auto_quality)
+  }
+
+  fn oversize_strategy(&self, chat_id: ChatId) -> OversizeStrategy {
+    self.settings.oversize_strategy(chat_id).unwrap_or(config::get().oversize_strategy)
+  }
+}
+
+/// A variant playlist waiting for the user to pick a resolution, together with
+/// the options chosen on its keyboard so far.
+struct PendingDownload {
+  variant_playlist: VariantPlaylist,
+  /// The link the playlist was resolved from.
+  url: String,
+  /// Key of the media in the file cache.
+  media_id: Option<String>,
+  /// Index into `variant_playlist.audio_tracks`.
+  audio: Option<usize>,
+  subtitles: Option<(usize, SubtitleMode)>,
+  /// Whether auto quality mode picked the resolution, in which case the upload
+  /// is also cached as the automatic choice.
+  auto_selected: bool
+}
+
+impl PendingDownload {
+  fn new(variant_playlist: VariantPlaylist, url: &str, media_id: Option<String>) -> Self {
+    let audio = variant_playlist.audio_tracks.iter().position(|track| track.media.default);
+    PendingDownload { variant_playlist, url: url.to_string(), media_id, audio, subtitles: None, auto_selected: false }
+  }
+
+  /// Whether `selection` refers to options this playlist has.
+  fn accepts(&self, selection: &Selection) -> bool {
+    match *selection {
+      Selection::Resolution(index) => index < self.variant_playlist.master_playlists.len(),
+      Selection::Audio(index) => index < self.variant_playlist.audio_tracks.len(),
+      Selection::Subtitles(index, _) => index < self.variant_playlist.subtitles.len()
+    }
+  }
+
+  /// Options in the form kept by `PendingStore`.
+  fn stored_subtitles(&self) -> Option<(usize, bool)> {
+    self.subtitles.map(|(index, mode)| (index, mode == SubtitleMode::Embed))
+  }
+
+  /// File cache quality of a selection, or `None` when it is not a single
+  /// video, i.e. subtitles are sent as a separate file.
+  fn cache_quality(&self, resolution_index: usize) -> Option<String> {
+    let mut quality = self.variant_playlist.master_playlists[resolution_index].cache_quality();
+    if let Some(track) = self.audio.map(|index| &self.variant_playlist.audio_tracks[index]) {
+      quality.push_str(&format!(" audio={}", track.media.label()));
+    }
+    match self.subtitles {
+      Some((index, SubtitleMode::Embed)) => quality.push_str(&format!(" subtitles={}", self.variant_playlist.subtitles[index].media.label())),
+      Some((_, SubtitleMode::File)) => return None,
+      None => {}
+    }
+    Some(quality)
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SubtitleMode {
+  /// Sent as a separate `.srt` document after the video.
+  File,
+  /// Muxed into the video as a soft subtitle track.
+  Embed
+}
+
+/// A tap on a selection keyboard.
+enum Selection {
+  Resolution(usize),
+  Audio(usize),
+  Subtitles(usize, SubtitleMode)
+}
+
+/// Parses `"{msg_id} {i}"`, `"{msg_id} a {i}"` and `"{msg_id} s {i} f|e"`,
+/// where `msg_id` keys the selection in `State::pending`.
+fn parse_callback_data(data: &str) -> Option<(MessageId, Selection)> {
+  let mut parts = data.split(' ');
+  let msg_id = MessageId(parts.next()?.parse().ok()?);
+  let selection = match (parts.next()?, parts.next(), parts.next(), parts.next()) {
+    ("a", Some(index), None, None) => Selection::Audio(index.parse().ok()?),
+    ("s", Some(index), Some("f"), None) => Selection::Subtitles(index.parse().ok()?, SubtitleMode::File),
+    ("s", Some(index), Some("e"), None) => Selection::Subtitles(index.parse().ok()?, SubtitleMode::Embed),
+    (index, None, None, None) => Selection::Resolution(index.parse().ok()?),
+    _ => return None
+  };
+  Some((msg_id, selection))
 }
 
 #[tokio::main]
@@ -42,18 +183,62 @@ async fn main() {
     .init();
 
   info!("Starting telegram bot...");
+  job_dir::clean_stale();
+
+  match ffmpeg::discover() {
+    Ok(path) => info!("Using ffmpeg at {}", path.display()),
+    Err(e) => warn!("{e}. Only fMP4 streams can be merged without ffmpeg")
+  }
 
   let client = reqwest::Client::builder().timeout(Duration::from_secs(60 * 60)).build().unwrap();
-  let bot = Bot::from_env_with_client(client);
+  let mut bot = Bot::from_env_with_client(client);
+  if let Some(url) = &config::get().bot_api_url {
+    info!("Using Bot API server at {url}");
+    bot = bot.set_api_url(url.clone());
+  }
+
+  let config = config::get();
+  let database = Database::open(&config.database_path).unwrap_or_else(|e| {
+    warn!("Failed to open {}: {e}. Cached uploads, selections and settings will not survive restarts", config.database_path.display());
+    Database::open_in_memory().unwrap()
+  });
 
-  let state = State { downloader: Downloader::new(), variants: HashMap::new() };
+  let file_cache = match FileCache::new(database.clone(), config.file_cache_ttl, config.file_cache_max_entries) {
+    Ok(file_cache) => Some(file_cache),
+    Err(e) => {
+      warn!("Failed to set up the file cache: {e}. Uploads will not be cached");
+      None
+    }
+  };
+
+  let settings = SettingsStore::new(database.clone()).unwrap_or_else(|e| {
+    warn!("Failed to set up the settings store: {e}. Settings will not survive restarts");
+    SettingsStore::new(Database::open_in_memory().unwrap()).unwrap()
+  });
+
+  let pending_store = PendingStore::new(database, config.pending_ttl).unwrap_or_else(|e| {
+    warn!("Failed to set up the selection store: {e}. Selections will not survive restarts");
+    PendingStore::new(Database::open_in_memory().unwrap(), config.pending_ttl).unwrap()
+  });
+
+  let browser = Downloader::new().browser;
+  let state = Arc::new(RwLock::new(State {
+    pending: HashMap::new(),
+    pending_store,
+    active: HashSet::new(),
+    running: HashMap::new(),
+    jobs: JobQueue::new(Limits { workers: config.workers, per_user: config.max_jobs_per_user, per_chat: config.max_jobs_per_chat }),
+    settings,
+    file_cache
+  }));
+  tokio::spawn(sweep_expired_selections(bot.clone(), state.clone()));
 
   let handler = dptree::entry()
     .branch(Update::filter_message().endpoint(message_handler))
     .branch(Update::filter_callback_query().endpoint(callback_query_handler));
 
   Dispatcher::builder(bot, handler)
-    .dependencies(dptree::deps![Arc::new(RwLock::new(state))])
+    .dependencies(dptree::deps![state, browser])
     .distribution_function(|_| None::<()>)
     .enable_ctrlc_handler()
     .build()
@@ -61,32 +246,95 @@ async fn main() {
     .await;
 }
 
-async fn message_handler(bot: Bot, msg: Message, state: Arc<RwLock<State>>) -> ResponseResult<()> {
-  match &msg.kind {
-    Common(message_common) => match &message_common.media_kind {
-      Text(media_text) => {
-        let is_command = media_text.entities.iter().any(|e| e.kind == BotCommand);
-        let is_link = media_text.entities.iter().any(|e| e.kind == Url);
+async fn message_handler(bot: Bot, msg: Message, state: Arc<RwLock<State>>, browser: Browser) -> ResponseResult<()> {
+  if let Common(message_common) = &msg.kind {
+    if let Text(media_text) = &message_common.media_kind {
+      let text = media_text.text.as_str();
+      let links = links(text, &media_text.entities);
+      let user_id = msg.from.as_ref().map(|user| user.id);
 
-        match media_text.text.as_str() {
-          url if is_link => handle_download_request(bot, msg.chat.id, msg.id, url, state).await?,
-          "/platforms" if is_command => handle_platforms_command(bot, msg.chat.id).await?,
-          _ => handle_help_command(bot, msg.chat.id).await?
-        }
-        info!("Handled user message");
+      match command(text, &media_text.entities) {
+        _ if !links.is_empty() => handle_links(bot, msg.chat.id, user_id, links, state, browser).await?,
+        Some("platforms") => handle_platforms_command(bot, msg.chat.id).await?,
+        Some("quality") => handle_quality_command(bot, msg.chat.id, user_id, text, state).await?,
+        Some("oversize") => handle_oversize_command(bot, msg.chat.id, text, state).await?,
+        _ => handle_help_command(bot, msg.chat.id).await?
       }
-      _ => {}
-    },
-    _ => {}
+      info!("Handled user message");
+    }
+  }
+  Ok(())
+}
+
+/// Name of the command a message starts with, without the `/` and the
+/// `@botname` that commands carry in groups.
+fn command<'a>(text: &'a str, entities: &'a [MessageEntity]) -> Option<&'a str> {
+  let entity = MessageEntityRef::parse(text, entities).into_iter().find(|entity| *entity.kind() == BotCommand && entity.start() == 0)?;
+  let command = entity.text().strip_prefix('/')?;
+  Some(command.split_once('@').map_or(command, |(name, _)| name))
+}
+
+/// Links in a message, in order and without repeats: the text of `Url`
+/// entities and the targets of `TextLink` ones.
+fn links(text: &str, entities: &[MessageEntity]) -> Vec<String> {
+  let mut links: Vec<String> = vec![];
+  for entity in MessageEntityRef::parse(text, entities) {
+    let link = match entity.kind() {
+      Url => entity.text().to_string(),
+      TextLink { url } => url.to_string(),
+      _ => continue
+    };
+    if !links.contains(&link) {
+      links.push(link);
+    }
+  }
+  links
+}
+
+fn is_supported(url: &str) -> bool {
+  TwitterDownloader::validate_url(url).is_ok() || DashDownloader::validate_url(url).is_ok() || TiktokDownloader::validate_url(url).is_ok()
+}
+
+/// Starts a download request for each supported link, up to
+/// `MAX_LINKS_PER_MESSAGE`, each with a status message of its own.
+async fn handle_links(
+  bot: Bot,
+  chat_id: ChatId,
+  user_id: Option<UserId>,
+  links: Vec<String>,
+  state: Arc<RwLock<State>>,
+  browser: Browser
+) -> ResponseResult<()> {
+  let mut links = links.into_iter().filter(|link| is_supported(link)).collect::<Vec<_>>();
+  if links.is_empty() {
+    bot.send_message(chat_id, "This link is not supported. See /platforms for what is").await?;
+    return Ok(());
+  }
+
+  let max_links = config::get().max_links_per_message;
+  if links.len() > max_links {
+    bot.send_message(chat_id, format!("Only the first {max_links} links of a message are downloaded")).await?;
+    links.truncate(max_links);
+  }
+
+  for link in links {
+    let (bot, state, browser) = (bot.clone(), state.clone(), browser.clone());
+    tokio::spawn(async move {
+      if let Err(e) = handle_download_request(bot, chat_id, user_id, &link, state, browser).await {
+        warn!("Failed to handle {link}: {e}");
+      }
+    });
   }
   Ok(())
 }
 
 async fn handle_help_command(bot: Bot, chat_id: ChatId) -> ResponseResult<()> {
-  const HELP: &str = "To download a video, send the video URL to me. I will download the video and send it back to you.\n\n\
+  const HELP: &str = "To download a video, send me a message with the video URL, or several of them. I will download the videos and send them back to you.\n\n\
     Commands:\n\
     /help - Show this message\n\
     /platforms - Show supported platforms\n\
+    /quality - Show or change how the video quality is chosen\n\
+    /oversize - Show or change what happens to videos over the upload limit\n\
     ";
   bot.send_message(chat_id, HELP).await?;
   Ok(())
@@ -94,79 +342,127 @@ async fn handle_help_command(bot: Bot, chat_id: ChatId) -> ResponseResult<()> {
 
 async fn handle_platforms_command(bot: Bot, chat_id: ChatId) -> ResponseResult<()> {
   const PLATFORMS: &str = "\
-  Twitter / X [Videos]\
-  TikTok [Videos]\
+  Twitter / X [Videos]\n\
+  TikTok [Videos]\n\
+  DASH manifest links (.mpd) [Videos]\n\
   ";
   bot.send_message(chat_id, PLATFORMS).await?;
   Ok(())
 }
 
+/// `/quality auto` downloads the largest quality that fits the upload limit
+/// right away, `/quality manual` always shows the keyboard.
+async fn handle_quality_command(bot: Bot, chat_id: ChatId, user_id: Option<UserId>, text: &str, state: Arc<RwLock<State>>) -> ResponseResult<()> {
+  let auto = {
+    let read_guard = state.read().await;
+    if let Some(user_id) = user_id {
+      match text.split_whitespace().nth(1) {
+        Some("auto") => read_guard.settings.set_auto_quality(user_id, true),
+        Some("manual") => read_guard.settings.set_auto_quality(user_id, false),
+        _ => {}
+      }
+    }
+    read_guard.auto_quality(user_id)
+  };
+
+  let mode = if auto {
+    format!("automatic (the largest quality under {} MB)", config::get().upload_limit / 1024 / 1024)
+  } else {
+    "manual (pick from a list)".to_string()
+  };
+  bot.send_message(chat_id, format!("Quality selection: {mode}\n\nChange it with /quality auto or /quality manual")).await?;
+  Ok(())
+}
+
+/// `/oversize reencode` shrinks videos over the upload limit, `/oversize split`
+/// sends them in parts. The choice applies to the whole chat.
+async fn handle_oversize_command(bot: Bot, chat_id: ChatId, text: &str, state: Arc<RwLock<State>>) -> ResponseResult<()> {
+  let strategy = {
+    let read_guard = state.read().await;
+    if let Some(strategy) = text.split_whitespace().nth(1).and_then(|argument| argument.parse().ok()) {
+      read_guard.settings.set_oversize_strategy(chat_id, strategy);
+    }
+    read_guard.oversize_strategy(chat_id)
+  };
+
+  let limit = config::get().upload_limit / 1024 / 1024;
+  let description = match strategy {
+    OversizeStrategy::Reencode => format!("re-encoded to fit in {limit} MB"),
+    OversizeStrategy::Split => format!("split into parts of up to {limit} MB")
+  };
+  bot.send_message(chat_id, format!("Videos over the upload limit are {description}\n\nChange it with /oversize reencode or /oversize split")).await?;
+  Ok(())
+}
+
 async fn handle_download_request(
   bot: Bot,
   chat_id: ChatId,
-  msg_id: MessageId,
+  user_id: Option<UserId>,
   url: &str,
-  state: Arc<RwLock<State>>
+  state: Arc<RwLock<State>>,
+  browser: Browser
 ) -> ResponseResult<()> {
   let initial_msg = bot.send_message(chat_id, "Parsing link...").await?;
   let initial_msg_id = initial_msg.id;
+  // A message may carry several links, so the selection is keyed by its own
+  // status message rather than by the message with the link.
+  let msg_id = initial_msg_id;
+  let auto_quality = state.read().await.auto_quality(user_id);
 
   match url {
-    _ if TwitterDownloader::validate_url(url).is_ok() => {
-      let result = {
-        let read_guard = state.read().await;
-        TwitterDownloader::get_variant_playlist(&read_guard.downloader.browser, url).await
-      };
-
-      match result {
-        Ok(variant_playlist) if !variant_playlist.master_playlists.is_empty() => {
-          let mut keyboard: Vec<Vec<InlineKeyboardButton>> = vec![];
-          for (i, playlist) in variant_playlist.master_playlists.iter().enumerate() {
-            let key = format!("{msg_id} {i}");
-            keyboard.push(vec![InlineKeyboardButton::callback(&playlist.resolution, key)]);
-          }
-
-          bot
-            .edit_message_text(chat_id, initial_msg_id, "Select a resolution to download")
-            .reply_markup(InlineKeyboardMarkup::new(keyboard))
-            .await?;
-          let mut write_guard = state.write().await;
-          write_guard.variants.insert((chat_id, msg_id), variant_playlist);
-        }
-        Err(e) => {
-          bot.edit_message_text(chat_id, initial_msg_id, format!("Failed to download video: {e}")).await?;
-        }
-        _ => {
-          bot.edit_message_text(chat_id, initial_msg_id, format!("Failed to download video")).await?;
+    _ if TwitterDownloader::validate_url(url).is_ok() || DashDownloader::validate_url(url).is_ok() => {
+      let media_id = TwitterDownloader::media_id(url).or(DashDownloader::media_id(url));
+      if auto_quality && send_cached(&bot, chat_id, initial_msg_id, media_id.as_deref(), AUTO_QUALITY, &state).await {
+        return Ok(());
+      }
+      let url = url.to_string();
+      let (job_bot, job_state) = (bot.clone(), state.clone());
+      // Resolving opens a browser tab, so it waits for its turn like downloads.
+      enqueue(&bot, &state, Owner { user_id, chat_id }, initial_msg_id, async move {
+        let (bot, state) = (job_bot, job_state);
+        let _ = bot.edit_message_text(chat_id, initial_msg_id, "Parsing link...").reply_markup(cancel_keyboard()).await;
+        let result = resolve_variants(&url, &browser).await;
+        if let Err(e) = offer_variants(bot, chat_id, msg_id, initial_msg_id, result, user_id, state).await {
+          warn!("Failed to offer variants of {url}: {e}");
         }
-      };
+      })
+      .await;
     }
     _ if TiktokDownloader::validate_url(url).is_ok() => {
-      bot.edit_message_text(chat_id, initial_msg_id, "Downloading video...").await?;
-      let result = {
-        let read_guard = state.read().await;
-        TiktokDownloader::download(&read_guard.downloader.browser, url).await
-      };
+      let media_id = TiktokDownloader::media_id(url);
+      if send_cached(&bot, chat_id, initial_msg_id, media_id.as_deref(), ORIGINAL_QUALITY, &state).await {
+        return Ok(());
+      }
+      let owner = Owner { user_id, chat_id };
+      let url = url.to_string();
+      let (job_bot, job_state) = (bot.clone(), state.clone());
+      enqueue(&bot, &state, owner, initial_msg_id, async move {
+        let (bot, state) = (job_bot, job_state);
+        let _ = bot.edit_message_text(chat_id, initial_msg_id, "Downloading video...").reply_markup(cancel_keyboard()).await;
+        let progress = ProgressMessage::show(&bot, chat_id, initial_msg_id);
+        let result = match JobDir::create() {
+          Ok(job_dir) => TiktokDownloader::download(&browser, &url, &job_dir, &progress.reporter).await.map(|path| (job_dir, path)),
+          Err(e) => Err(e)
+        };
 
-      match result {
-        Ok(path) => {
-          let _ = tokio::spawn(async move {
-            let _ = bot.edit_message_text(chat_id, initial_msg_id, "Uploading video...").await;
-            let input_media = InputMedia::Video(InputMediaVideo::new(InputFile::file(&path)));
-            match bot.edit_message_media(chat_id, initial_msg_id, input_media).await {
-              Ok(_) => {}
+        match result {
+          Ok((job_dir, path)) => {
+            let strategy = state.read().await.oversize_strategy(chat_id);
+            match upload_video(&bot, chat_id, initial_msg_id, &path, strategy, &job_dir, progress).await {
+              Ok(upload) => cache_upload(&state, media_id.as_deref(), &[ORIGINAL_QUALITY], &upload).await,
               Err(e) => {
-                let _ = bot.edit_message_text(chat_id, initial_msg_id, format!("Failed to upload video: {e}")).await;
+                let _ = bot.edit_message_text(chat_id, initial_msg_id, e).await;
               }
             }
-            let _ = tokio::fs::remove_file(&path).await;
-          })
-          .await;
-        }
-        Err(e) => {
-          bot.edit_message_text(chat_id, initial_msg_id, format!("Failed to download video: {e}")).await?;
+            drop(job_dir);
+          }
+          Err(e) => {
+            drop(progress);
+            let _ = bot.edit_message_text(chat_id, initial_msg_id, format!("Failed to download video: {e}")).await;
+          }
         }
-      }
+      })
+      .await;
     }
     _ => {}
   }
@@ -174,49 +470,731 @@ async fn handle_download_request(
   Ok(())
 }
 
-async fn callback_query_handler(bot: Bot, query: CallbackQuery, state: Arc<RwLock<State>>) -> ResponseResult<()> {
-  let initial_msg_id = query.message.as_ref().unwrap().id();
-  let chat_id = query.chat_id().unwrap();
+/// Fetches the variant playlist behind a Twitter or DASH link.
+async fn resolve_variants(url: &str, browser: &Browser) -> Result<PendingDownload, DownloaderError> {
+  let (result, media_id) = if TwitterDownloader::validate_url(url).is_ok() {
+    (TwitterDownloader::get_variant_playlist(browser, url).await, TwitterDownloader::media_id(url))
+  } else {
+    (DashDownloader::get_variant_playlist(browser, url).await, DashDownloader::media_id(url))
+  };
+  result.map(|variant_playlist| PendingDownload::new(variant_playlist, url, media_id))
+}
 
-  if let Some(callback_data) = query.data {
-    bot.answer_callback_query(&query.id).await?;
-    tokio::spawn(async move {
-      let _ = bot.edit_message_text(chat_id, initial_msg_id, "Downloading video...").await;
+/// Shows the selection keyboard for `result` and keeps the playlist until the
+/// user picks a resolution. In auto quality mode the largest quality that fits
+/// the upload limit is downloaded straight away; the keyboard is only shown
+/// when none fits.
+async fn offer_variants(
+  bot: Bot,
+  chat_id: ChatId,
+  msg_id: MessageId,
+  initial_msg_id: MessageId,
+  result: Result<PendingDownload, DownloaderError>,
+  user_id: Option<UserId>,
+  state: Arc<RwLock<State>>
+) -> ResponseResult<()> {
+  let auto_quality = state.read().await.auto_quality(user_id);
+  match result {
+    Ok(mut pending) if !pending.variant_playlist.master_playlists.is_empty() => {
+      let upload_limit = config::get().upload_limit;
+      if let Some(resolution_index) = pending.variant_playlist.best_fitting(upload_limit).filter(|_| auto_quality) {
+        pending.auto_selected = true;
+        state.write().await.pending.insert((chat_id, msg_id), pending);
+        start_download(bot, Owner { user_id, chat_id }, msg_id, initial_msg_id, resolution_index, state).await;
+        return Ok(());
+      }
+
+      let text = if auto_quality {
+        format!("No quality fits the {} MB upload limit. Select a resolution to download", upload_limit / 1024 / 1024)
+      } else {
+        "Select a resolution to download".to_string()
+      };
+      bot.edit_message_text(chat_id, initial_msg_id, text).reply_markup(selection_keyboard(msg_id, &pending)).await?;
+      let mut write_guard = state.write().await;
+      write_guard.pending_store.insert(chat_id, msg_id, initial_msg_id, &pending.url);
+      write_guard.pending.insert((chat_id, msg_id), pending);
+    }
+    Err(e) => {
+      bot.edit_message_text(chat_id, initial_msg_id, format!("Failed to download video: {e}")).await?;
+    }
+    _ => {
+      bot.edit_message_text(chat_id, initial_msg_id, "Failed to download video").await?;
+    }
+  };
 
-      let (msg_id, resolution_index) = callback_data.split_once(' ').unwrap();
-      let msg_id = MessageId(msg_id.parse::<i32>().unwrap());
-      let resolution_index = resolution_index.parse::<usize>().unwrap();
+  Ok(())
+}
 
-      let path = {
-        let mut write_guard = state.write().await;
-        let variants = &mut write_guard.variants;
-        let variant_playlist = variants.get_mut(&(chat_id, msg_id)).unwrap();
-        let master_playlist = &mut variant_playlist.master_playlists[resolution_index];
+/// Resolution buttons, followed by the audio tracks when there is a choice and
+/// two buttons per subtitle track for sending it as a file or embedding it.
+/// The chosen audio track and subtitle option are ticked.
+fn selection_keyboard(msg_id: MessageId, pending: &PendingDownload) -> InlineKeyboardMarkup {
+  let mut keyboard: Vec<Vec<InlineKeyboardButton>> = vec![];
+  for (i, playlist) in pending.variant_playlist.master_playlists.iter().enumerate() {
+    let key = format!("{msg_id} {i}");
+    keyboard.push(vec![InlineKeyboardButton::callback(playlist.label(), key)]);
+  }
 
-        master_playlist.download().await.map_err(|e| RequestError::from(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))
-      };
+  let audio_tracks = &pending.variant_playlist.audio_tracks;
+  if audio_tracks.len() > 1 {
+    let buttons = audio_tracks.iter().enumerate().map(|(i, track)| {
+      let tick = if pending.audio == Some(i) { "✓ " } else { "" };
+      InlineKeyboardButton::callback(format!("{tick}🔊 {}", track.media.label()), format!("{msg_id} a {i}"))
+    });
+    keyboard.extend(buttons.collect::<Vec<_>>().chunks(2).map(<[_]>::to_vec));
+  }
+
+  for (i, track) in pending.variant_playlist.subtitles.iter().enumerate() {
+    let button = |mode: SubtitleMode, code: &str, description: &str| {
+      let tick = if pending.subtitles == Some((i, mode)) { "✓ " } else { "" };
+      InlineKeyboardButton::callback(format!("{tick}{} · {description}", track.media.label()), format!("{msg_id} s {i} {code}"))
+    };
+    keyboard.push(vec![button(SubtitleMode::File, "f", "file"), button(SubtitleMode::Embed, "e", "embedded")]);
+  }
+
+  InlineKeyboardMarkup::new(keyboard)
+}
+
+async fn callback_query_handler(bot: Bot, query: CallbackQuery, state: Arc<RwLock<State>>, browser: Browser) -> ResponseResult<()> {
+  bot.answer_callback_query(&query.id).await?;
+  let (Some(message), Some(chat_id), Some(callback_data)) = (query.message.as_ref(), query.chat_id(), query.data.as_deref()) else {
+    return Ok(());
+  };
+  let initial_msg_id = message.id();
+
+  if callback_data == CANCEL {
+    cancel_job(chat_id, initial_msg_id, query.from.id, &state).await;
+    return Ok(());
+  }
+
+  let Some((msg_id, selection)) = parse_callback_data(callback_data) else {
+    warn!("Unknown callback data {callback_data:?}");
+    expire_keyboard(&bot, chat_id, initial_msg_id).await;
+    return Ok(());
+  };
+  let (known, active) = {
+    let read_guard = state.read().await;
+    (read_guard.pending.contains_key(&(chat_id, msg_id)), read_guard.active.contains(&(chat_id, msg_id)))
+  };
+  if active {
+    return Ok(());
+  }
+  if !known && !restore_pending(Owner { user_id: Some(query.from.id), chat_id }, msg_id, &state, &browser).await {
+    expire_keyboard(&bot, chat_id, initial_msg_id).await;
+    return Ok(());
+  }
+
+  let mut write_guard = state.write().await;
+  let State { pending, pending_store, .. } = &mut *write_guard;
+  let Some(entry) = pending.get_mut(&(chat_id, msg_id)).filter(|entry| entry.accepts(&selection)) else {
+    drop(write_guard);
+    expire_keyboard(&bot, chat_id, initial_msg_id).await;
+    return Ok(());
+  };
+
+  match selection {
+    Selection::Audio(track_index) => {
+      entry.audio = Some(track_index);
+      pending_store.set_options(chat_id, msg_id, entry.audio, entry.stored_subtitles());
+      bot.edit_message_reply_markup(chat_id, initial_msg_id).reply_markup(selection_keyboard(msg_id, entry)).await?;
+    }
+    Selection::Subtitles(track_index, mode) => {
+      // Picking the ticked option again turns subtitles off.
+      entry.subtitles = if entry.subtitles == Some((track_index, mode)) { None } else { Some((track_index, mode)) };
+      pending_store.set_options(chat_id, msg_id, entry.audio, entry.stored_subtitles());
+      bot.edit_message_reply_markup(chat_id, initial_msg_id).reply_markup(selection_keyboard(msg_id, entry)).await?;
+    }
+    Selection::Resolution(resolution_index) => {
+      pending_store.remove(chat_id, msg_id);
+      drop(write_guard);
+      start_download(bot, Owner { user_id: Some(query.from.id), chat_id }, msg_id, initial_msg_id, resolution_index, state).await;
+    }
+  }
+
+  Ok(())
+}
+
+/// Resolves the link of a stored selection again, e.g. after a restart, and
+/// restores the options picked on its keyboard. The resolution is queued as a
+/// job of `owner`, who tapped the keyboard.
+async fn restore_pending(owner: Owner, msg_id: MessageId, state: &RwLock<State>, browser: &Browser) -> bool {
+  let chat_id = owner.chat_id;
+  let (stored, jobs) = {
+    let read_guard = state.read().await;
+    (read_guard.pending_store.get(chat_id, msg_id), read_guard.jobs.clone())
+  };
+  let Some(stored) = stored else {
+    return false;
+  };
+
+  // The keyboard stays in place while the job waits, so there are no queue
+  // notices.
+  let (sender, receiver) = oneshot::channel();
+  let (url, browser) = (stored.url.clone(), browser.clone());
+  jobs.submit(owner, is_admin(owner.user_id), async move {
+    let _ = sender.send(resolve_variants(&url, &browser).await);
+  });
+  let Ok(result) = receiver.await else {
+    return false;
+  };
+
+  match result {
+    Ok(mut pending) => {
+      if stored.audio.is_some_and(|index| index < pending.variant_playlist.audio_tracks.len()) {
+        pending.audio = stored.audio;
+      }
+      pending.subtitles = stored
+        .subtitles
+        .filter(|&(index, _)| index < pending.variant_playlist.subtitles.len())
+        .map(|(index, embed)| (index, if embed { SubtitleMode::Embed } else { SubtitleMode::File }));
+      state.write().await.pending.insert((chat_id, msg_id), pending);
+      true
+    }
+    Err(e) => {
+      warn!("Failed to restore selection for {}: {e}", stored.url);
+      false
+    }
+  }
+}
 
-      match path {
-        Ok(path) => {
-          let _ = bot.edit_message_text(chat_id, initial_msg_id, "Uploading video...").await;
-          let input_media = InputMedia::Video(InputMediaVideo::new(InputFile::file(&path)));
-          match bot.edit_message_media(chat_id, initial_msg_id, input_media).await {
-            Ok(_) => {}
-            Err(e) => {
-              let _ = bot.edit_message_text(chat_id, initial_msg_id, format!("Failed to upload video: {e}")).await;
+/// Replaces the keyboards of expired selections and forgets their playlists.
+async fn sweep_expired_selections(bot: Bot, state: Arc<RwLock<State>>) {
+  let mut interval = tokio::time::interval(Duration::from_secs(60));
+  loop {
+    interval.tick().await;
+    let expired = state.read().await.pending_store.remove_expired();
+    for (chat_id, msg_id, keyboard_msg_id) in expired {
+      state.write().await.pending.remove(&(chat_id, msg_id));
+      expire_keyboard(&bot, chat_id, keyboard_msg_id).await;
+    }
+  }
+}
+
+async fn expire_keyboard(bot: &Bot, chat_id: ChatId, keyboard_msg_id: MessageId) {
+  let _ = bot.edit_message_text(chat_id, keyboard_msg_id, "Expired, send the link again").await;
+}
+
+/// Answers the pending selection for `msg_id` from the file cache, or queues
+/// its download and upload, reporting progress by editing `initial_msg_id`.
+async fn start_download(bot: Bot, owner: Owner, msg_id: MessageId, initial_msg_id: MessageId, resolution_index: usize, state: Arc<RwLock<State>>) {
+  let chat_id = owner.chat_id;
+  // The selection is taken out of the shared state so that no lock is held
+  // while it downloads.
+  let mut pending = {
+    let mut write_guard = state.write().await;
+    let Some(pending) = write_guard.pending.remove(&(chat_id, msg_id)) else {
+      return;
+    };
+    write_guard.active.insert((chat_id, msg_id));
+    pending
+  };
+
+  let quality = pending.cache_quality(resolution_index);
+  if let Some(quality) = &quality {
+    if send_cached(&bot, chat_id, initial_msg_id, pending.media_id.as_deref(), quality, &state).await {
+      state.write().await.active.remove(&(chat_id, msg_id));
+      return;
+    }
+  }
+
+  let active = ActiveSelection { state: state.clone(), key: (chat_id, msg_id) };
+  let (job_bot, job_state) = (bot.clone(), state.clone());
+  enqueue(&bot, &state, owner, initial_msg_id, async move {
+    let (bot, state, _active) = (job_bot, job_state, active);
+    let _ = bot.edit_message_text(chat_id, initial_msg_id, "Downloading video...").reply_markup(cancel_keyboard()).await;
+    let progress = ProgressMessage::show(&bot, chat_id, initial_msg_id);
+
+    let result = match JobDir::create() {
+      Ok(job_dir) => download_selection(&mut pending, resolution_index, &job_dir, &progress.reporter).await.map(|paths| (job_dir, paths)),
+      Err(e) => Err(e)
+    };
+
+    match result {
+      Ok((job_dir, (path, subtitles_path))) => {
+        let strategy = state.read().await.oversize_strategy(chat_id);
+        match upload_video(&bot, chat_id, initial_msg_id, &path, strategy, &job_dir, progress).await {
+          Ok(upload) => {
+            if let Some(quality) = &quality {
+              let qualities = if pending.auto_selected { vec![quality.as_str(), AUTO_QUALITY] } else { vec![quality.as_str()] };
+              cache_upload(&state, pending.media_id.as_deref(), &qualities, &upload).await;
+            }
+            if let Some(subtitles_path) = subtitles_path {
+              if let Err(e) = bot.send_document(chat_id, input_file(&subtitles_path)).await {
+                let _ = bot.send_message(chat_id, format!("Failed to upload subtitles: {e}")).await;
+              }
             }
           }
-          let _ = tokio::fs::remove_file(&path).await;
-        }
-        Err(e) => {
-          let _ = bot.edit_message_text(chat_id, initial_msg_id, format!("Failed to download video: {e}")).await;
+          Err(e) => {
+            let _ = bot.edit_message_text(chat_id, initial_msg_id, e).await;
+          }
         }
+        drop(job_dir);
+      }
+      Err(e) => {
+        drop(progress);
+        let _ = bot.edit_message_text(chat_id, initial_msg_id, format!("Failed to download video: {e}")).await;
       }
+    }
+  })
+  .await;
+}
 
-      let mut write_guard = state.write().await;
-      write_guard.variants.remove(&(chat_id, msg_id));
+/// A queued or running job that its status message can cancel.
+struct RunningJob {
+  /// The user who may cancel it besides admins.
+  owner: Option<UserId>,
+  id: JobId,
+  cancel: oneshot::Sender<()>
+}
+
+/// Removes a selection from `State::active` when dropped, which happens however
+/// its job ends, including when it is withdrawn from the queue.
+struct ActiveSelection {
+  state: Arc<RwLock<State>>,
+  key: (ChatId, MessageId)
+}
+
+impl Drop for ActiveSelection {
+  fn drop(&mut self) {
+    let (state, key) = (self.state.clone(), self.key);
+    tokio::spawn(async move {
+      state.write().await.active.remove(&key);
     });
   }
+}
 
-  Ok(())
+fn cancel_keyboard() -> InlineKeyboardMarkup {
+  InlineKeyboardMarkup::new([[InlineKeyboardButton::callback("Cancel", CANCEL)]])
+}
+
+/// Cancels the job behind `status_msg_id` when the user who started it, or an
+/// admin, taps its Cancel button.
+async fn cancel_job(chat_id: ChatId, status_msg_id: MessageId, user_id: UserId, state: &RwLock<State>) {
+  let key = (chat_id, status_msg_id);
+  let mut write_guard = state.write().await;
+  let allowed = |owner: &Option<UserId>| *owner == Some(user_id) || config::get().admin_ids.contains(&user_id.0);
+  if !write_guard.running.get(&key).is_some_and(|running| allowed(&running.owner)) {
+    return;
+  }
+  if let Some(running) = write_guard.running.remove(&key) {
+    // A job still in the queue is dropped there, a running one where it stands.
+    if !write_guard.jobs.withdraw(running.id) {
+      let _ = running.cancel.send(());
+    }
+  }
+}
+
+fn is_admin(user_id: Option<UserId>) -> bool {
+  user_id.is_some_and(|user_id| config::get().admin_ids.contains(&user_id.0))
+}
+
+/// Queues `job` and keeps `status_msg_id` showing its place in the queue until
+/// it starts. Admins' jobs go first.
+///
+/// Until the job finishes, the Cancel button on `status_msg_id` withdraws it
+/// from the queue or, once it runs, drops it where it stands, which closes its
+/// browser tab, aborts its requests, kills its ffmpeg and removes its job
+/// directory.
+async fn enqueue(bot: &Bot, state: &Arc<RwLock<State>>, owner: Owner, status_msg_id: MessageId, job: impl Future<Output = ()> + Send + 'static) {
+  let admin = is_admin(owner.user_id);
+  let key = (owner.chat_id, status_msg_id);
+  let (cancel, cancelled) = oneshot::channel();
+  // The job waits for the last queue notice to be sent so that it cannot
+  // overwrite the job's own status.
+  let (notices_done, wait_for_notices) = oneshot::channel::<()>();
+  let (job_bot, job_state) = (bot.clone(), state.clone());
+  let job = async move {
+    let _ = wait_for_notices.await;
+    let finished = tokio::select! {
+      _ = job => true,
+      Ok(()) = cancelled => false
+    };
+
+    // The status message may have been handed on to another job meanwhile,
+    // like the download started by resolving a link in auto quality mode, whose
+    // cancel signal is still open.
+    let mut write_guard = job_state.write().await;
+    if write_guard.running.get(&key).is_some_and(|running| running.cancel.is_closed()) {
+      write_guard.running.remove(&key);
+    }
+    drop(write_guard);
+    if !finished {
+      let _ = job_bot.edit_message_text(owner.chat_id, status_msg_id, "Download cancelled").await;
+    }
+  };
+
+  // The job is registered before it can start, so that it cannot finish first.
+  let mut position = {
+    let mut write_guard = state.write().await;
+    let (id, position) = write_guard.jobs.submit(owner, admin, job);
+    write_guard.running.insert(key, RunningJob { owner: owner.user_id, id, cancel });
+    position
+  };
+
+  let bot = bot.clone();
+  tokio::spawn(async move {
+    loop {
+      let place = *position.borrow_and_update();
+      if place == 0 {
+        break;
+      }
+      let _ = bot.edit_message_text(owner.chat_id, status_msg_id, format!("You are #{place} in queue")).reply_markup(cancel_keyboard()).await;
+      if position.changed().await.is_err() {
+        // The queue closes the channel without starting the job when it is
+        // withdrawn.
+        if *position.borrow() != 0 {
+          let _ = bot.edit_message_text(owner.chat_id, status_msg_id, "Download cancelled").await;
+        }
+        break;
+      }
+    }
+    drop(notices_done);
+  });
+}
+
+/// Status message that shows the progress reported to `reporter` until it is
+/// dropped.
+struct ProgressMessage {
+  reporter: ProgressReporter,
+  display: JoinHandle<()>
+}
+
+impl ProgressMessage {
+  fn show(bot: &Bot, chat_id: ChatId, status_msg_id: MessageId) -> Self {
+    let (reporter, updates) = ProgressReporter::new();
+    let display = tokio::spawn(show_progress(bot.clone(), chat_id, status_msg_id, updates));
+    ProgressMessage { reporter, display }
+  }
+}
+
+impl Drop for ProgressMessage {
+  fn drop(&mut self) {
+    self.display.abort();
+  }
+}
+
+/// Edits `status_msg_id` with the latest progress every `PROGRESS_INTERVAL`,
+/// skipping edits that would not change it and waiting out flood control.
+async fn show_progress(bot: Bot, chat_id: ChatId, status_msg_id: MessageId, mut updates: watch::Receiver<Progress>) {
+  let mut shown = String::new();
+  let (mut last_time, mut last_bytes) = (Instant::now(), 0);
+  loop {
+    tokio::time::sleep(PROGRESS_INTERVAL).await;
+    let progress = *updates.borrow_and_update();
+    let speed = progress.bytes.saturating_sub(last_bytes) as f64 / last_time.elapsed().as_secs_f64();
+    (last_time, last_bytes) = (Instant::now(), progress.bytes);
+
+    let text = progress_text(&progress, speed);
+    if text == shown {
+      continue;
+    }
+    match bot.edit_message_text(chat_id, status_msg_id, &text).reply_markup(cancel_keyboard()).await {
+      Err(RequestError::RetryAfter(retry_after)) => tokio::time::sleep(retry_after.duration()).await,
+      _ => shown = text
+    }
+  }
+}
+
+/// `Downloading video... 45% · 12 MB · 2.1 MB/s`, where the speed is in bytes
+/// per second.
+fn progress_text(progress: &Progress, speed: f64) -> String {
+  let stage = match progress.stage {
+    Stage::Downloading => "Downloading video...",
+    Stage::Merging => "Merging video and audio...",
+    Stage::Reencoding => "Video is over the upload limit, re-encoding...",
+    Stage::Splitting => "Video is over the upload limit, splitting into parts..."
+  };
+  let mut details = vec![];
+  if let Some(fraction) = progress.fraction {
+    details.push(format!("{:.0}%", fraction * 100.0));
+  }
+  if progress.stage == Stage::Downloading && progress.bytes > 0 {
+    details.push(format_size(progress.bytes));
+    if speed > 0.0 {
+      details.push(format!("{:.1} MB/s", speed / 1024.0 / 1024.0));
+    }
+  }
+  if details.is_empty() {
+    stage.to_string()
+  } else {
+    format!("{stage} {}", details.join(" · "))
+  }
+}
+
+/// A video sent by `upload_video`.
+struct Upload {
+  /// What `send_parts` returned.
+  file_ids: Option<Vec<String>>,
+  /// The strategy that shrank or split the video, when it was over the limit.
+  fitted: Option<OversizeStrategy>
+}
+
+/// Replaces `initial_msg_id` with the video at `path`. Videos over the upload
+/// limit are first shrunk or split with `strategy`, shown by `progress`, which
+/// stops once the upload starts. Fails with the text to show the user.
+async fn upload_video(
+  bot: &Bot,
+  chat_id: ChatId,
+  initial_msg_id: MessageId,
+  path: &Path,
+  strategy: OversizeStrategy,
+  job_dir: &JobDir,
+  progress: ProgressMessage
+) -> Result<Upload, String> {
+  let parts = oversize::fit(path, config::get().upload_limit, strategy, job_dir, &progress.reporter).await;
+  drop(progress);
+  let parts = parts.map_err(|e| format!("Failed to upload video: {e}"))?;
+  let fitted = (parts != [path]).then_some(strategy);
+
+  let _ = bot.edit_message_text(chat_id, initial_msg_id, "Uploading video...").reply_markup(cancel_keyboard()).await;
+  let file_ids = send_parts(bot, chat_id, initial_msg_id, parts.iter().map(|part| input_file(part)).collect()).await?;
+  Ok(Upload { file_ids, fitted })
+}
+
+/// Replaces `initial_msg_id` with the first of `parts` and sends the rest as
+/// new messages, numbered when there is more than one. Returns the `file_id`s
+/// of all parts, or `None` when some are unknown or a part after the first
+/// failed, which is reported in the chat. Fails with the text to show the user
+/// when the first part fails.
+async fn send_parts(bot: &Bot, chat_id: ChatId, initial_msg_id: MessageId, parts: Vec<InputFile>) -> Result<Option<Vec<String>>, String> {
+  let count = parts.len();
+  let mut file_ids = Some(vec![]);
+  for (i, part) in parts.into_iter().enumerate() {
+    let caption = format!("Part {}/{}", i + 1, count);
+    let result = if i == 0 {
+      let mut video = InputMediaVideo::new(part);
+      if count > 1 {
+        video = video.caption(caption);
+      }
+      bot.edit_message_media(chat_id, initial_msg_id, InputMedia::Video(video)).await
+    } else {
+      bot.send_video(chat_id, part).caption(caption).await
+    };
+
+    match result {
+      Ok(message) => {
+        let file_id = message.video().map(|video| video.file.id.clone()).or(message.document().map(|document| document.file.id.clone()));
+        file_ids = file_ids.zip(file_id).map(|(mut file_ids, file_id)| {
+          file_ids.push(file_id);
+          file_ids
+        });
+      }
+      // Once the first part has replaced the status message it can no longer
+      // show errors, so later failures get a message of their own.
+      Err(e) if i == 0 => return Err(format!("Failed to upload video: {e}")),
+      Err(e) => {
+        let _ = bot.send_message(chat_id, format!("Failed to upload part {}/{count}: {e}", i + 1)).await;
+        return Ok(None);
+      }
+    }
+  }
+  Ok(file_ids)
+}
+
+/// Key of an upload in `quality` in the file cache. Videos shrunk or split to
+/// fit the upload limit are kept apart per strategy, so chats that chose the
+/// other one are not answered with them.
+fn cache_key(quality: &str, fitted: Option<OversizeStrategy>) -> String {
+  match fitted {
+    Some(strategy) => format!("{quality} oversize={strategy}"),
+    None => quality.to_string()
+  }
+}
+
+/// Answers with the cached upload of `media_id` in `quality`, if there is one
+/// that was sent as is or fitted with the chat's strategy. Entries Telegram
+/// rejects are dropped so the caller downloads afresh.
+async fn send_cached(bot: &Bot, chat_id: ChatId, initial_msg_id: MessageId, media_id: Option<&str>, quality: &str, state: &RwLock<State>) -> bool {
+  let Some(media_id) = media_id else {
+    return false;
+  };
+  let cached = {
+    let read_guard = state.read().await;
+    let strategy = read_guard.oversize_strategy(chat_id);
+    read_guard.file_cache.as_ref().and_then(|file_cache| {
+      [cache_key(quality, None), cache_key(quality, Some(strategy))]
+        .into_iter()
+        .find_map(|key| file_cache.get(media_id, &key).map(|file_ids| (key, file_ids)))
+    })
+  };
+  let Some((key, file_ids)) = cached else {
+    return false;
+  };
+
+  info!("Answering {media_id} ({key}) from the file cache");
+  match send_parts(bot, chat_id, initial_msg_id, file_ids.into_iter().map(InputFile::file_id).collect()).await {
+    Ok(_) => true,
+    Err(e) => {
+      warn!("Cached file of {media_id} ({key}) was rejected: {e}");
+      if let Some(file_cache) = &state.read().await.file_cache {
+        file_cache.remove(media_id, &key);
+      }
+      false
+    }
+  }
+}
+
+async fn cache_upload(state: &RwLock<State>, media_id: Option<&str>, qualities: &[&str], upload: &Upload) {
+  let (Some(media_id), Some(file_ids)) = (media_id, &upload.file_ids) else {
+    return;
+  };
+  if let Some(file_cache) = &state.read().await.file_cache {
+    for quality in qualities {
+      file_cache.insert(media_id, &cache_key(quality, upload.fitted), file_ids);
+    }
+  }
+}
+
+/// Downloads the chosen resolution, audio and subtitle track into `job_dir`.
+/// Returns the video and, when subtitles are to be sent separately, the `.srt`
+/// file.
+async fn download_selection(
+  pending: &mut PendingDownload,
+  resolution_index: usize,
+  job_dir: &JobDir,
+  progress: &ProgressReporter
+) -> Result<(PathBuf, Option<PathBuf>), DownloaderError> {
+  let master_playlist = &mut pending.variant_playlist.master_playlists[resolution_index];
+  let audio = pending.audio.map(|index| &pending.variant_playlist.audio_tracks[index]);
+  let path = master_playlist.download(job_dir, audio, progress).await?;
+
+  let Some((track_index, mode)) = pending.subtitles else {
+    return Ok((path, None));
+  };
+  let track = &pending.variant_playlist.subtitles[track_index];
+  let subtitles_path = track.download(job_dir).await?;
+
+  match mode {
+    SubtitleMode::File => Ok((path, Some(subtitles_path))),
+    SubtitleMode::Embed => {
+      let output = job_dir.file(&format!("subtitled_{}", path.file_name().unwrap().to_string_lossy()));
+      subtitles::embed(&path, &subtitles_path, track.media.language.as_deref(), &output).await?;
+      Ok((output, None))
+    }
+  }
+}
+
+/// A file for the Bot API. A local server reads it from disk itself, which
+/// avoids streaming large files through a multipart upload.
+fn input_file(path: &Path) -> InputFile {
+  if config::get().bot_api_local {
+    if let Ok(url) = std::path::absolute(path).map_err(|_| ()).and_then(reqwest::Url::from_file_path) {
+      return InputFile::url(url);
+    }
+  }
+  InputFile::file(path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use downloader::test_server::{Reply, TestServer};
+  use reqwest::header::HeaderMap;
+  use tokio::sync::Barrier;
+
+  #[test]
+  fn finds_links_anywhere_in_the_text() {
+    // Offsets count UTF-16 code units, two of them for the emoji.
+    let text = "👀 look https://x.com/a/status/1 and this, or https://x.com/a/status/1 again";
+    let entities = [
+      MessageEntity::new(Url, 8, 24),
+      MessageEntity::new(TextLink { url: "https://www.tiktok.com/@a/video/2".parse().unwrap() }, 37, 4),
+      MessageEntity::new(Url, 46, 24)
+    ];
+
+    assert_eq!(links(text, &entities), ["https://x.com/a/status/1", "https://www.tiktok.com/@a/video/2"]);
+    assert!(links("/help", &[MessageEntity::new(BotCommand, 0, 5)]).is_empty());
+  }
+
+  #[test]
+  fn matches_whole_commands() {
+    assert_eq!(command("/quality auto", &[MessageEntity::new(BotCommand, 0, 8)]), Some("quality"));
+    assert_eq!(command("/quality@some_bot auto", &[MessageEntity::new(BotCommand, 0, 17)]), Some("quality"));
+    assert_eq!(command("/qualityx", &[MessageEntity::new(BotCommand, 0, 9)]), Some("qualityx"));
+    assert_eq!(command("/oversized@some_bot", &[MessageEntity::new(BotCommand, 0, 19)]), Some("oversized"));
+    assert_eq!(command("see /platforms", &[MessageEntity::new(BotCommand, 4, 10)]), None);
+    assert_eq!(command("/quality", &[]), None);
+  }
+
+  #[test]
+  fn describes_progress() {
+    let downloading = Progress { stage: Stage::Downloading, fraction: Some(0.456), bytes: 12 * 1024 * 1024 };
+    assert_eq!(progress_text(&downloading, 2.1 * 1024.0 * 1024.0), "Downloading video... 46% · 12 MB · 2.1 MB/s");
+    assert_eq!(progress_text(&Progress { fraction: None, ..downloading }, 0.0), "Downloading video... 12 MB");
+
+    let reencoding = Progress { stage: Stage::Reencoding, fraction: Some(0.5), ..downloading };
+    assert_eq!(progress_text(&reencoding, 0.0), "Video is over the upload limit, re-encoding... 50%");
+  }
+
+  #[test]
+  fn keeps_fitted_uploads_apart_per_strategy() {
+    assert_eq!(cache_key("720p", None), "720p");
+    assert_eq!(cache_key("720p", Some(OversizeStrategy::Split)), "720p oversize=split");
+    assert_ne!(cache_key("720p", Some(OversizeStrategy::Split)), cache_key("720p", Some(OversizeStrategy::Reencode)));
+  }
+
+  fn test_state() -> State {
+    State {
+      pending: HashMap::new(),
+      pending_store: PendingStore::new(Database::open_in_memory().unwrap(), Duration::from_secs(60)).unwrap(),
+      active: HashSet::new(),
+      jobs: JobQueue::new(Limits { workers: 2, per_user: 2, per_chat: 2 }),
+      running: HashMap::new(),
+      settings: SettingsStore::new(Database::open_in_memory().unwrap()).unwrap(),
+      file_cache: None
+    }
+  }
+
+  #[tokio::test]
+  async fn selections_download_concurrently() {
+    let server = TestServer::start().await;
+    // Neither segment is served until both have been requested and the test
+    // has read the state, so the downloads only get past the barrier if they
+    // run at the same time and hold no lock on it while they wait.
+    let barrier = Arc::new(Barrier::new(3));
+    for name in ["a", "b"] {
+      let master = format!("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=500000,RESOLUTION=640x360\n{name}.m3u8\n");
+      let media = format!("#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4,\n{name}.ts\n#EXT-X-ENDLIST\n");
+      server.route(&format!("/{name}/master.m3u8"), vec![Reply::Ok(master.into())]);
+      server.route(&format!("/{name}/{name}.m3u8"), vec![Reply::Ok(media.into())]);
+      server.route(&format!("/{name}/{name}.ts"), vec![Reply::Gated(barrier.clone(), name.into())]);
+    }
+
+    let state = Arc::new(RwLock::new(test_state()));
+    let chat_id = ChatId(1);
+    let msg_ids = [MessageId(1), MessageId(2)];
+    for (name, msg_id) in ["a", "b"].into_iter().zip(msg_ids) {
+      let url = server.url(&format!("/{name}/master.m3u8"));
+      let variant_playlist = VariantPlaylist::from_url(&url, HeaderMap::new()).await.unwrap();
+      state.write().await.pending.insert((chat_id, msg_id), PendingDownload::new(variant_playlist, &url, None));
+    }
+
+    // Bot API calls go to the test server, which fails them; the jobs carry on
+    // regardless, as they do when a status message cannot be edited.
+    let bot = Bot::new("0:test").set_api_url(server.url("/").parse().unwrap());
+    let owner = Owner { user_id: Some(UserId(1)), chat_id };
+    for msg_id in msg_ids {
+      tokio::spawn(start_download(bot.clone(), owner, msg_id, msg_id, 0, state.clone()));
+    }
+
+    let requested = async {
+      while (server.hits("/a/a.ts"), server.hits("/b/b.ts")) != (1, 1) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    assert!(tokio::time::timeout(Duration::from_secs(10), requested).await.is_ok());
+    let read_guard = tokio::time::timeout(Duration::from_secs(1), state.read()).await.expect("a download holds the state lock");
+    assert!(read_guard.pending.is_empty());
+    assert_eq!(read_guard.active.len(), 2);
+    drop(read_guard);
+    barrier.wait().await;
+
+    // Merging needs ffmpeg, which may be missing; only the overlap matters here.
+    let finished = async {
+      while !state.read().await.active.is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    assert!(tokio::time::timeout(Duration::from_secs(10), finished).await.is_ok());
+  }
 }
@@ -0,0 +1,160 @@
+//! Selection keyboards that are waiting for a tap.
+//!
+//! The resolved playlists only live in memory, so what is stored is the link
+//! and the options picked so far; after a restart the link is resolved again
+//! when the keyboard is used. Entries expire after `PENDING_TTL`, at which
+//! point their keyboards are replaced with a note to send the link again.
+
+use rusqlite::{params, OptionalExtension};
+use std::time::Duration;
+use teloxide::types::{ChatId, MessageId};
+use tracing::warn;
+
+use crate::db::{now, Database};
+
+pub struct PendingStore {
+  database: Database,
+  ttl: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredSelection {
+  pub url: String,
+  /// Message carrying the keyboard.
+  pub keyboard_msg_id: MessageId,
+  /// Index of the chosen audio track.
+  pub audio: Option<usize>,
+  /// Index of the chosen subtitle track and whether it is embedded.
+  pub subtitles: Option<(usize, bool)>,
+}
+
+impl PendingStore {
+  pub fn new(database: Database, ttl: Duration) -> rusqlite::Result<Self> {
+    database.lock().execute_batch(
+      "CREATE TABLE IF NOT EXISTS pending_selections (
+        chat_id INTEGER NOT NULL,
+        msg_id INTEGER NOT NULL,
+        keyboard_msg_id INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        audio INTEGER,
+        subtitle_track INTEGER,
+        embed_subtitles INTEGER NOT NULL DEFAULT 0,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, msg_id)
+      );",
+    )?;
+    Ok(PendingStore { database, ttl })
+  }
+
+  /// Records a keyboard for the selection keyed by `msg_id`.
+  pub fn insert(&self, chat_id: ChatId, msg_id: MessageId, keyboard_msg_id: MessageId, url: &str) {
+    self.insert_at(chat_id, msg_id, keyboard_msg_id, url, now());
+  }
+
+  pub fn set_options(&self, chat_id: ChatId, msg_id: MessageId, audio: Option<usize>, subtitles: Option<(usize, bool)>) {
+    let connection = self.database.lock();
+    let result = connection.execute(
+      "UPDATE pending_selections SET audio = ?3, subtitle_track = ?4, embed_subtitles = ?5 WHERE chat_id = ?1 AND msg_id = ?2",
+      params![chat_id.0, msg_id.0, audio, subtitles.map(|(track, _)| track), subtitles.is_some_and(|(_, embed)| embed)],
+    );
+    if let Err(e) = result {
+      warn!("Failed to update pending selection: {e}");
+    }
+  }
+
+  /// The selection for `msg_id`, unless it has expired.
+  pub fn get(&self, chat_id: ChatId, msg_id: MessageId) -> Option<StoredSelection> {
+    self.get_at(chat_id, msg_id, now())
+  }
+
+  pub fn remove(&self, chat_id: ChatId, msg_id: MessageId) {
+    let connection = self.database.lock();
+    if let Err(e) = connection.execute("DELETE FROM pending_selections WHERE chat_id = ?1 AND msg_id = ?2", params![chat_id.0, msg_id.0]) {
+      warn!("Failed to remove pending selection: {e}");
+    }
+  }
+
+  /// Deletes expired selections and returns them as chat, selection key and
+  /// keyboard message.
+  pub fn remove_expired(&self) -> Vec<(ChatId, MessageId, MessageId)> {
+    self.remove_expired_at(now())
+  }
+
+  fn insert_at(&self, chat_id: ChatId, msg_id: MessageId, keyboard_msg_id: MessageId, url: &str, now: u64) {
+    let connection = self.database.lock();
+    let result = connection.execute(
+      "INSERT OR REPLACE INTO pending_selections (chat_id, msg_id, keyboard_msg_id, url, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+      params![chat_id.0, msg_id.0, keyboard_msg_id.0, url, now],
+    );
+    if let Err(e) = result {
+      warn!("Failed to store pending selection: {e}");
+    }
+  }
+
+  fn get_at(&self, chat_id: ChatId, msg_id: MessageId, now: u64) -> Option<StoredSelection> {
+    let connection = self.database.lock();
+    connection
+      .query_row(
+        "SELECT url, keyboard_msg_id, audio, subtitle_track, embed_subtitles FROM pending_selections
+         WHERE chat_id = ?1 AND msg_id = ?2 AND created_at > ?3",
+        params![chat_id.0, msg_id.0, now.saturating_sub(self.ttl.as_secs())],
+        |row| {
+          Ok(StoredSelection {
+            url: row.get(0)?,
+            keyboard_msg_id: MessageId(row.get(1)?),
+            audio: row.get(2)?,
+            subtitles: row.get::<_, Option<usize>>(3)?.map(|track| Ok::<_, rusqlite::Error>((track, row.get(4)?))).transpose()?,
+          })
+        },
+      )
+      .optional()
+      .unwrap_or_else(|e| {
+        warn!("Failed to read pending selection: {e}");
+        None
+      })
+  }
+
+  fn remove_expired_at(&self, now: u64) -> Vec<(ChatId, MessageId, MessageId)> {
+    let connection = self.database.lock();
+    let cutoff = now.saturating_sub(self.ttl.as_secs());
+    let expired = connection
+      .prepare("SELECT chat_id, msg_id, keyboard_msg_id FROM pending_selections WHERE created_at <= ?1")
+      .and_then(|mut statement| {
+        statement.query_map([cutoff], |row| Ok((ChatId(row.get(0)?), MessageId(row.get(1)?), MessageId(row.get(2)?))))?.collect::<rusqlite::Result<Vec<_>>>()
+      })
+      .and_then(|expired| connection.execute("DELETE FROM pending_selections WHERE created_at <= ?1", [cutoff]).map(|_| expired));
+
+    expired.unwrap_or_else(|e| {
+      warn!("Failed to remove expired selections: {e}");
+      vec![]
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_options_until_the_selection_expires() {
+    let store = PendingStore::new(Database::open_in_memory().unwrap(), Duration::from_secs(100)).unwrap();
+    store.insert_at(ChatId(1), MessageId(10), MessageId(11), "https://x.com/a/status/1", 1000);
+    store.set_options(ChatId(1), MessageId(10), Some(2), Some((0, true)));
+
+    let selection = StoredSelection { url: "https://x.com/a/status/1".to_string(), keyboard_msg_id: MessageId(11), audio: Some(2), subtitles: Some((0, true)) };
+    assert_eq!(store.get_at(ChatId(1), MessageId(10), 1099), Some(selection));
+    assert_eq!(store.get_at(ChatId(2), MessageId(10), 1099), None);
+    assert_eq!(store.get_at(ChatId(1), MessageId(10), 1100), None);
+  }
+
+  #[test]
+  fn removes_expired_selections_once() {
+    let store = PendingStore::new(Database::open_in_memory().unwrap(), Duration::from_secs(100)).unwrap();
+    store.insert_at(ChatId(1), MessageId(10), MessageId(11), "https://example.com/a.mpd", 1000);
+    store.insert_at(ChatId(1), MessageId(20), MessageId(21), "https://example.com/b.mpd", 1050);
+
+    assert_eq!(store.remove_expired_at(1120), [(ChatId(1), MessageId(10), MessageId(11))]);
+    assert_eq!(store.remove_expired_at(1120), []);
+    assert!(store.get_at(ChatId(1), MessageId(20), 1120).is_some());
+  }
+}
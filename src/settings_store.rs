@@ -0,0 +1,113 @@
+//! Settings changed with bot commands, so that they survive restarts.
+//!
+//! Only explicit choices are stored; anyone who never changed a setting gets
+//! the default from the configuration, including after it changes.
+
+use rusqlite::{params, OptionalExtension};
+use teloxide::types::{ChatId, UserId};
+use tracing::warn;
+
+use crate::{db::Database, downloader::oversize::OversizeStrategy};
+
+pub struct SettingsStore {
+  database: Database,
+}
+
+impl SettingsStore {
+  pub fn new(database: Database) -> rusqlite::Result<Self> {
+    database.lock().execute_batch(
+      "CREATE TABLE IF NOT EXISTS user_settings (
+        user_id INTEGER PRIMARY KEY,
+        auto_quality INTEGER
+      );
+      CREATE TABLE IF NOT EXISTS chat_settings (
+        chat_id INTEGER PRIMARY KEY,
+        oversize_strategy TEXT
+      );",
+    )?;
+    Ok(SettingsStore { database })
+  }
+
+  /// Quality mode chosen with `/quality`, if the user picked one.
+  pub fn auto_quality(&self, user_id: UserId) -> Option<bool> {
+    let connection = self.database.lock();
+    connection
+      .query_row("SELECT auto_quality FROM user_settings WHERE user_id = ?1", [user_id.0], |row| row.get(0))
+      .optional()
+      .unwrap_or_else(|e| {
+        warn!("Failed to read quality mode: {e}");
+        None
+      })
+      .flatten()
+  }
+
+  pub fn set_auto_quality(&self, user_id: UserId, auto_quality: bool) {
+    let connection = self.database.lock();
+    let result = connection.execute(
+      "INSERT INTO user_settings (user_id, auto_quality) VALUES (?1, ?2) ON CONFLICT (user_id) DO UPDATE SET auto_quality = excluded.auto_quality",
+      params![user_id.0, auto_quality],
+    );
+    if let Err(e) = result {
+      warn!("Failed to store quality mode: {e}");
+    }
+  }
+
+  /// Strategy chosen with `/oversize`, if the chat picked one.
+  pub fn oversize_strategy(&self, chat_id: ChatId) -> Option<OversizeStrategy> {
+    let connection = self.database.lock();
+    connection
+      .query_row("SELECT oversize_strategy FROM chat_settings WHERE chat_id = ?1", [chat_id.0], |row| row.get::<_, Option<String>>(0))
+      .optional()
+      .unwrap_or_else(|e| {
+        warn!("Failed to read oversize strategy: {e}");
+        None
+      })
+      .flatten()
+      .and_then(|strategy| strategy.parse().ok())
+  }
+
+  pub fn set_oversize_strategy(&self, chat_id: ChatId, strategy: OversizeStrategy) {
+    let connection = self.database.lock();
+    let result = connection.execute(
+      "INSERT INTO chat_settings (chat_id, oversize_strategy) VALUES (?1, ?2)
+       ON CONFLICT (chat_id) DO UPDATE SET oversize_strategy = excluded.oversize_strategy",
+      params![chat_id.0, strategy.to_string()],
+    );
+    if let Err(e) = result {
+      warn!("Failed to store oversize strategy: {e}");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_quality_mode_per_user() {
+    let database = Database::open_in_memory().unwrap();
+    let store = SettingsStore::new(database.clone()).unwrap();
+    store.set_auto_quality(UserId(1), true);
+    store.set_auto_quality(UserId(1), false);
+    store.set_auto_quality(UserId(2), true);
+
+    let reopened = SettingsStore::new(database).unwrap();
+    assert_eq!(reopened.auto_quality(UserId(1)), Some(false));
+    assert_eq!(reopened.auto_quality(UserId(2)), Some(true));
+    assert_eq!(reopened.auto_quality(UserId(3)), None);
+  }
+
+  #[test]
+  fn keeps_oversize_strategy_per_chat() {
+    let database = Database::open_in_memory().unwrap();
+    let store = SettingsStore::new(database.clone()).unwrap();
+    store.set_oversize_strategy(ChatId(-100), OversizeStrategy::Reencode);
+    store.set_oversize_strategy(ChatId(5), OversizeStrategy::Reencode);
+    store.set_oversize_strategy(ChatId(5), OversizeStrategy::Split);
+
+    let reopened = SettingsStore::new(database).unwrap();
+    assert_eq!(reopened.oversize_strategy(ChatId(-100)), Some(OversizeStrategy::Reencode));
+    assert_eq!(reopened.oversize_strategy(ChatId(5)), Some(OversizeStrategy::Split));
+    assert_eq!(reopened.oversize_strategy(ChatId(6)), None);
+  }
+}